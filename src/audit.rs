@@ -0,0 +1,522 @@
+//! Results of auditing the authorized keys of all targets, and their output formats.
+
+use crate::{date::Date, revocation::Revocation};
+use serde::Serialize;
+use std::fmt;
+
+/// The output format of an audit.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, clap::ValueEnum)]
+pub enum Format {
+    /// Findings as they are made, followed by a summary table
+    Text,
+    /// A JSON object with the results of all targets
+    Json,
+    /// A JUnit XML report with a test case per target
+    Junit,
+}
+
+/// A class of outcomes of auditing a single target, which may fall into several of them.
+#[derive(Serialize, Clone, Copy, Debug, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditStatus {
+    /// The authorized keys match the configuration file.
+    Ok,
+    /// The authorized keys differ from the configuration file, or violate the policy.
+    Drifted,
+    /// The file contains keys excluded in the configuration file, e.g., compromised keys.
+    ExcludedKey,
+    /// The file contains revoked keys, which must not be authorized on any host.
+    RevokedKey,
+    /// The host could not be connected to, or the file could not be read.
+    Unreachable,
+    /// The file contains lines that are not valid authorized keys.
+    ParseError,
+}
+
+impl AuditStatus {
+    /// Returns the bit of the exit code for this status.
+    ///
+    /// The exit code of an audit combines the bits of all statuses of all targets, so that it tells apart
+    /// drift (2), connectivity errors (4), parse errors (8), excluded keys (16) and revoked keys (32),
+    /// while 1 is left for other errors.
+    pub fn exit_code_bit(self) -> u8 {
+        match self {
+            AuditStatus::Ok => 0,
+            AuditStatus::Drifted => 2,
+            AuditStatus::Unreachable => 4,
+            AuditStatus::ParseError => 8,
+            AuditStatus::ExcludedKey => 16,
+            AuditStatus::RevokedKey => 32,
+        }
+    }
+}
+
+impl fmt::Display for AuditStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // padded by the caller, e.g., in the summary table
+        f.pad(match self {
+            AuditStatus::Ok => "ok",
+            AuditStatus::Drifted => "drifted",
+            AuditStatus::ExcludedKey => "excluded-key",
+            AuditStatus::RevokedKey => "revoked-key",
+            AuditStatus::Unreachable => "unreachable",
+            AuditStatus::ParseError => "parse-error",
+        })
+    }
+}
+
+/// The result of auditing a single target.
+#[derive(Serialize, Debug)]
+pub struct AuditResult {
+    #[serde(rename = "host")]
+    pub hostname: String,
+    pub user: String,
+    pub path: String,
+    /// All classes the target falls into, in the order they were found, or just `Ok`
+    pub statuses: Vec<AuditStatus>,
+    /// Keys found in the file but not defined in the configuration file
+    pub unknown_keys: Vec<KeyFinding>,
+    /// Keys defined in the configuration file but not found in the file
+    pub missing_keys: Vec<KeyFinding>,
+    /// Keys found in the file but excluded in the configuration file
+    pub excluded_keys: Vec<KeyFinding>,
+    /// Keys found in the file but revoked, even if they are defined in the configuration file
+    pub revoked_keys: Vec<KeyFinding>,
+    /// Keys of identities found in the file after they expired
+    pub expired_keys: Vec<KeyFinding>,
+    /// All other findings, e.g., invalid lines, policy violations or connection errors
+    pub errors: Vec<String>,
+}
+
+impl AuditResult {
+    pub fn new(hostname: &str, user: &str, path: &str) -> Self {
+        AuditResult {
+            hostname: hostname.to_owned(),
+            user: user.to_owned(),
+            path: path.to_owned(),
+            statuses: vec![AuditStatus::Ok],
+            unknown_keys: Vec::new(),
+            missing_keys: Vec::new(),
+            excluded_keys: Vec::new(),
+            revoked_keys: Vec::new(),
+            expired_keys: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Adds `status` to the classes of the target, which is then no longer `Ok`.
+    pub fn add_status(&mut self, status: AuditStatus) {
+        self.statuses.retain(|&status| status != AuditStatus::Ok);
+
+        if !self.statuses.contains(&status) {
+            self.statuses.push(status);
+        }
+    }
+
+    pub fn has_status(&self, status: AuditStatus) -> bool {
+        self.statuses.contains(&status)
+    }
+
+    /// Returns all statuses separated by commas, e.g., `revoked-key,drifted`.
+    pub fn describe_statuses(&self) -> String {
+        self.statuses
+            .iter()
+            .map(|status| status.to_string())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// A key found in a file or missing from it.
+#[derive(Serialize, Clone, Debug, Eq, PartialEq)]
+pub struct KeyFinding {
+    pub fingerprint: String,
+    pub comment: Option<String>,
+    /// The name of the identity the key belongs to, without the leading `@`
+    pub identity: Option<String>,
+    /// The date the key expires, if it is only temporarily valid
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<Date>,
+    /// Why and when the key was revoked, if it is revoked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revocation: Option<Revocation>,
+}
+
+impl fmt::Display for KeyFinding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.fingerprint)?;
+
+        if let Some(comment) = &self.comment {
+            write!(f, " ({})", comment)?;
+        }
+        if let Some(identity) = &self.identity {
+            write!(f, " @{}", identity)?;
+        }
+        if let Some(expires) = &self.expires {
+            write!(f, " (expires {})", expires)?;
+        }
+        if let Some(revocation) = &self.revocation {
+            write!(f, " ({})", revocation)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the exit code for the results of an audit, see [`AuditStatus::exit_code_bit`].
+pub fn exit_code(results: &[AuditResult]) -> u8 {
+    results
+        .iter()
+        .flat_map(|result| &result.statuses)
+        .fold(0, |code, status| code | status.exit_code_bit())
+}
+
+/// Returns a table of the statuses of all targets, followed by the number of targets per status.
+/// A target with several statuses is counted for each of them.
+pub fn summary(results: &[AuditResult]) -> String {
+    let header = AuditResult::new("HOST", "USER", "PATH");
+
+    let rows = std::iter::once(&header).chain(results);
+    let statuses: Vec<_> = rows
+        .clone()
+        .enumerate()
+        .map(|(i, row)| {
+            if i == 0 {
+                "STATUS".to_owned()
+            } else {
+                row.describe_statuses()
+            }
+        })
+        .collect();
+    // wide enough for any single status
+    let status_width = statuses.iter().map(String::len).fold(12, usize::max);
+    let hostname_width = rows
+        .clone()
+        .map(|row| row.hostname.len())
+        .max()
+        .unwrap_or(0);
+    let user_width = rows.clone().map(|row| row.user.len()).max().unwrap_or(0);
+
+    let mut summary = String::new();
+    for (status, row) in statuses.iter().zip(rows) {
+        summary.push_str(&format!(
+            "{:<status_width$}  {:<hostname_width$}  {:<user_width$}  {}\n",
+            status, row.hostname, row.user, row.path
+        ));
+    }
+
+    let count = |status| {
+        results
+            .iter()
+            .filter(|result| result.has_status(status))
+            .count()
+    };
+    summary.push_str(&format!(
+        "{} ok, {} drifted, {} with revoked keys, {} with excluded keys, {} unreachable, {} parse errors\n",
+        count(AuditStatus::Ok),
+        count(AuditStatus::Drifted),
+        count(AuditStatus::RevokedKey),
+        count(AuditStatus::ExcludedKey),
+        count(AuditStatus::Unreachable),
+        count(AuditStatus::ParseError),
+    ));
+
+    summary
+}
+
+/// Returns the results of all targets as a JSON object.
+pub fn to_json(results: &[AuditResult]) -> String {
+    #[derive(Serialize)]
+    struct Audit<'a> {
+        targets: &'a [AuditResult],
+    }
+
+    serde_json::to_string_pretty(&Audit { targets: results })
+        .expect("audit results can be serialized")
+}
+
+/// Returns the results of all targets as a JUnit XML report,
+/// with a test suite per host and a test case per target.
+///
+/// Drifted targets, revoked and excluded keys and parse errors are reported as failures,
+/// unreachable targets as errors.
+pub fn to_junit(results: &[AuditResult]) -> String {
+    // unreachable targets cannot have any other status
+    let is_error = |result: &AuditResult| result.has_status(AuditStatus::Unreachable);
+    let is_failure =
+        |result: &AuditResult| !is_error(result) && !result.has_status(AuditStatus::Ok);
+    let count = |results: &[&AuditResult], f: &dyn Fn(&AuditResult) -> bool| {
+        results.iter().filter(|result| f(result)).count()
+    };
+
+    let mut hosts: Vec<(&str, Vec<&AuditResult>)> = Vec::new();
+    for result in results {
+        match hosts.last_mut() {
+            Some((hostname, results)) if *hostname == result.hostname => results.push(result),
+            _ => hosts.push((&result.hostname, vec![result])),
+        }
+    }
+
+    let all: Vec<_> = results.iter().collect();
+    let mut xml = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <testsuites name=\"authorized-keys audit\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+        all.len(),
+        count(&all, &is_failure),
+        count(&all, &is_error),
+    );
+
+    for (hostname, results) in hosts {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\">\n",
+            escape_xml(hostname),
+            results.len(),
+            count(&results, &is_failure),
+            count(&results, &is_error),
+        ));
+
+        for result in results {
+            xml.push_str(&format!(
+                "    <testcase classname=\"{}\" name=\"{}\"",
+                escape_xml(hostname),
+                escape_xml(&format!("{}:{}", result.user, result.path)),
+            ));
+
+            if result.has_status(AuditStatus::Ok) {
+                xml.push_str("/>\n");
+                continue;
+            }
+
+            let mut details = Vec::new();
+            details.extend(
+                result
+                    .revoked_keys
+                    .iter()
+                    .map(|key| format!("revoked key {}", key)),
+            );
+            details.extend(
+                result
+                    .excluded_keys
+                    .iter()
+                    .map(|key| format!("excluded key {}", key)),
+            );
+            details.extend(
+                result
+                    .expired_keys
+                    .iter()
+                    .map(|key| format!("expired key {}", key)),
+            );
+            details.extend(
+                result
+                    .unknown_keys
+                    .iter()
+                    .map(|key| format!("unknown key {}", key)),
+            );
+            details.extend(
+                result
+                    .missing_keys
+                    .iter()
+                    .map(|key| format!("missing key {}", key)),
+            );
+            details.extend(result.errors.iter().cloned());
+
+            let element = if is_error(result) { "error" } else { "failure" };
+            xml.push_str(&format!(
+                ">\n      <{element} message=\"{}\">{}</{element}>\n    </testcase>\n",
+                result.describe_statuses(),
+                escape_xml(&details.join("\n")),
+            ));
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// Escapes `s` for XML text and attribute values.
+fn escape_xml(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // control characters other than whitespace are not allowed in XML
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => escaped.push('\u{fffd}'),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(hostname: &str, status: AuditStatus) -> AuditResult {
+        let mut result = AuditResult::new(hostname, "root", "/root/.ssh/authorized_keys");
+        result.add_status(status);
+        result
+    }
+
+    fn findings() -> Vec<AuditResult> {
+        let mut drifted = result("web.example.com", AuditStatus::Drifted);
+        drifted.unknown_keys.push(KeyFinding {
+            fingerprint: "SHA256:abc".to_owned(),
+            comment: Some("mallory <m@example.com>".to_owned()),
+            identity: None,
+            expires: None,
+            revocation: None,
+        });
+        drifted.missing_keys.push(KeyFinding {
+            fingerprint: "SHA256:def".to_owned(),
+            comment: None,
+            identity: Some("alice".to_owned()),
+            expires: None,
+            revocation: None,
+        });
+
+        let mut unreachable = result("db.example.com", AuditStatus::Unreachable);
+        unreachable.errors.push("connection refused".to_owned());
+
+        vec![
+            result("web.example.com", AuditStatus::Ok),
+            drifted,
+            unreachable,
+        ]
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(&[]), 0);
+        assert_eq!(exit_code(&[result("a", AuditStatus::Ok)]), 0);
+        assert_eq!(
+            exit_code(&[
+                result("a", AuditStatus::Drifted),
+                result("b", AuditStatus::Ok),
+                result("c", AuditStatus::Drifted),
+            ]),
+            2
+        );
+        assert_eq!(
+            exit_code(&[
+                result("a", AuditStatus::Unreachable),
+                result("b", AuditStatus::ParseError),
+                result("c", AuditStatus::Drifted),
+            ]),
+            14
+        );
+        assert_eq!(
+            exit_code(&[
+                result("a", AuditStatus::ExcludedKey),
+                result("b", AuditStatus::Drifted),
+            ]),
+            18
+        );
+        assert_eq!(
+            exit_code(&[
+                result("a", AuditStatus::RevokedKey),
+                result("b", AuditStatus::ExcludedKey),
+            ]),
+            48
+        );
+
+        let mut result = result("a", AuditStatus::RevokedKey);
+        result.add_status(AuditStatus::ParseError);
+        result.add_status(AuditStatus::Drifted);
+        result.add_status(AuditStatus::ParseError);
+        assert_eq!(
+            result.statuses,
+            [
+                AuditStatus::RevokedKey,
+                AuditStatus::ParseError,
+                AuditStatus::Drifted
+            ]
+        );
+        assert_eq!(exit_code(&[result]), 42);
+    }
+
+    #[test]
+    fn summary_table() {
+        let mut revoked = result("db", AuditStatus::RevokedKey);
+        revoked.add_status(AuditStatus::Drifted);
+        assert_eq!(
+            summary(&[result("web.example.com", AuditStatus::Ok), revoked]),
+            "\
+STATUS               HOST             USER  PATH
+ok                   web.example.com  root  /root/.ssh/authorized_keys
+revoked-key,drifted  db               root  /root/.ssh/authorized_keys
+1 ok, 1 drifted, 1 with revoked keys, 0 with excluded keys, 0 unreachable, 0 parse errors
+"
+        );
+        assert_eq!(
+            summary(&[
+                result("web.example.com", AuditStatus::Ok),
+                result("db", AuditStatus::Unreachable),
+            ]),
+            "\
+STATUS        HOST             USER  PATH
+ok            web.example.com  root  /root/.ssh/authorized_keys
+unreachable   db               root  /root/.ssh/authorized_keys
+1 ok, 0 drifted, 0 with revoked keys, 0 with excluded keys, 1 unreachable, 0 parse errors
+"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let json: serde_json::Value = serde_json::from_str(&to_json(&findings())).unwrap();
+
+        assert_eq!(
+            json["targets"][1],
+            serde_json::json!({
+                "host": "web.example.com",
+                "user": "root",
+                "path": "/root/.ssh/authorized_keys",
+                "statuses": ["drifted"],
+                "unknown_keys": [
+                    {"fingerprint": "SHA256:abc", "comment": "mallory <m@example.com>", "identity": null}
+                ],
+                "missing_keys": [
+                    {"fingerprint": "SHA256:def", "comment": null, "identity": "alice"}
+                ],
+                "excluded_keys": [],
+                "revoked_keys": [],
+                "expired_keys": [],
+                "errors": [],
+            })
+        );
+        assert_eq!(json["targets"][0]["statuses"], serde_json::json!(["ok"]));
+        assert_eq!(json["targets"][2]["statuses"][0], "unreachable");
+        assert_eq!(json["targets"][2]["errors"][0], "connection refused");
+    }
+
+    #[test]
+    fn junit() {
+        assert_eq!(
+            to_junit(&findings()),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="authorized-keys audit" tests="3" failures="1" errors="1">
+  <testsuite name="web.example.com" tests="2" failures="1" errors="0">
+    <testcase classname="web.example.com" name="root:/root/.ssh/authorized_keys"/>
+    <testcase classname="web.example.com" name="root:/root/.ssh/authorized_keys">
+      <failure message="drifted">unknown key SHA256:abc (mallory &lt;m@example.com&gt;)
+missing key SHA256:def @alice</failure>
+    </testcase>
+  </testsuite>
+  <testsuite name="db.example.com" tests="1" failures="0" errors="1">
+    <testcase classname="db.example.com" name="root:/root/.ssh/authorized_keys">
+      <error message="unreachable">connection refused</error>
+    </testcase>
+  </testsuite>
+</testsuites>
+"#
+        );
+    }
+}
@@ -1,16 +1,23 @@
 use crate::{
     authorized_keys::AuthorizedKeys,
-    identity::{Identities, Identity},
+    date::Date,
+    fingerprint::Fingerprint,
+    identity::{self, Identities, Identity},
     public_key::PublicKey,
 };
-use serde::{Deserialize, Serialize};
-use std::{collections::HashSet, str::FromStr};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use std::{collections::HashSet, fmt, str::FromStr};
 
-#[derive(Deserialize, Clone, Debug, Eq, PartialEq, Hash)]
-#[serde(untagged)]
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub enum AuthorizedItem {
     Identity(Identity),
+    Fingerprint(Fingerprint),
     PublicKey(PublicKey),
+    /// An item whose keys must not be authorized, written with a leading `!`
+    Exclusion(Box<AuthorizedItem>),
 }
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug)]
@@ -19,37 +26,163 @@ pub struct AuthorizedItems(HashSet<AuthorizedItem>);
 
 pub struct CollectAuthorizedKeys<'a> {
     pub authorized_keys: AuthorizedKeys,
-    pub undefined_identities: Vec<&'a Identity>,
+    pub exclusions: Exclusions,
+    pub identity_errors: Vec<identity::Error>,
+    pub unresolved_fingerprints: Vec<&'a Fingerprint>,
+}
+
+/// The keys excluded by exclusion items.
+#[derive(Clone, Default, Debug)]
+pub struct Exclusions {
+    keys: AuthorizedKeys,
+    /// Fingerprints of excluded keys, which need not be known
+    fingerprints: Vec<Fingerprint>,
+}
+
+impl Exclusions {
+    /// Returns `true` if `key` is excluded.
+    pub fn contains(&self, key: &PublicKey) -> bool {
+        self.keys.contains(key)
+            || self
+                .fingerprints
+                .iter()
+                .any(|fingerprint| key.fingerprint(fingerprint.alg()) == *fingerprint)
+    }
+}
+
+impl AuthorizedItem {
+    /// Returns `true` if this item authorizes `key`, i.e., if `key` is one of the keys it expands to.
+    /// Exclusions do not authorize any keys.
+    pub fn authorizes(&self, key: &PublicKey, identities: &Identities, today: Date) -> bool {
+        match self {
+            AuthorizedItem::Identity(identity) => identities
+                .keys_for_identity(identity, today)
+                .is_ok_and(|keys| keys.contains(key)),
+            AuthorizedItem::Fingerprint(fingerprint) => {
+                key.fingerprint(fingerprint.alg()) == *fingerprint
+            }
+            AuthorizedItem::PublicKey(public_key) => public_key == key,
+            AuthorizedItem::Exclusion(_) => false,
+        }
+    }
 }
 
 impl AuthorizedItems {
+    /// Returns the items authorizing `key`, ignoring exclusions, ordered by their string representation.
+    pub fn authorizing(
+        &self,
+        key: &PublicKey,
+        identities: &Identities,
+        today: Date,
+    ) -> Vec<&AuthorizedItem> {
+        let mut items: Vec<_> = self
+            .0
+            .iter()
+            .filter(|item| item.authorizes(key, identities, today))
+            .collect();
+        items.sort_by_cached_key(|item| item.to_string());
+
+        items
+    }
+
     /// Add an item to the authorized items.
     pub fn insert(&mut self, item: AuthorizedItem) {
         self.0.insert(item);
     }
 
-    pub fn collect_authorized_keys(&self, identities: &Identities) -> CollectAuthorizedKeys {
+    /// Replaces the reference to a key by `fingerprint` with the full `key`.
+    pub fn expand_fingerprint(&mut self, fingerprint: &Fingerprint, key: PublicKey) {
+        self.0
+            .remove(&AuthorizedItem::Fingerprint(fingerprint.clone()));
+        self.0.insert(AuthorizedItem::PublicKey(key));
+    }
+
+    /// Add all items of `other` to the authorized items.
+    pub fn extend(&mut self, other: &AuthorizedItems) {
+        self.0.extend(other.0.iter().cloned());
+    }
+
+    /// An iterator visiting all public keys listed directly in the authorized items.
+    pub fn public_keys(&self) -> impl Iterator<Item = &PublicKey> {
+        self.0.iter().filter_map(|item| match item {
+            AuthorizedItem::PublicKey(key) => Some(key),
+            _ => None,
+        })
+    }
+
+    /// An iterator visiting all exclusion items.
+    pub fn exclusions(&self) -> impl Iterator<Item = &AuthorizedItem> {
+        self.0
+            .iter()
+            .filter(|item| matches!(item, AuthorizedItem::Exclusion(_)))
+    }
+
+    /// An iterator visiting all fingerprints listed in the authorized items.
+    pub fn fingerprints(&self) -> impl Iterator<Item = &Fingerprint> {
+        self.0.iter().filter_map(|item| match item {
+            AuthorizedItem::Fingerprint(fingerprint) => Some(fingerprint),
+            _ => None,
+        })
+    }
+
+    /// Collect the authorized keys of all items valid `today`,
+    /// resolving fingerprints against `known_keys`.
+    ///
+    /// The keys of exclusion items are removed after all other items are expanded.
+    pub fn collect_authorized_keys<'a>(
+        &'a self,
+        identities: &Identities,
+        known_keys: &AuthorizedKeys,
+        today: Date,
+    ) -> CollectAuthorizedKeys<'a> {
         let mut authorized_keys = AuthorizedKeys::default();
-        let mut undefined_identities = Vec::new();
+        let mut exclusions = Exclusions::default();
+        let mut identity_errors = Vec::new();
+        let mut unresolved_fingerprints = Vec::new();
 
-        for item in &self.0 {
-            match item {
-                AuthorizedItem::PublicKey(key) => authorized_keys.insert(key.clone()),
-                AuthorizedItem::Identity(identity) => {
-                    if let Some(keys) = identities.keys_for_identity(identity) {
-                        for key in keys {
-                            authorized_keys.insert(key);
+        let mut expand = |item: &'a AuthorizedItem, keys: &mut AuthorizedKeys| match item {
+            AuthorizedItem::PublicKey(key) => keys.insert(key.clone()),
+            AuthorizedItem::Fingerprint(fingerprint) => {
+                if let Some(key) = known_keys.find_by_fingerprint(fingerprint) {
+                    keys.insert(key.clone());
+                } else {
+                    unresolved_fingerprints.push(fingerprint)
+                }
+            }
+            AuthorizedItem::Identity(identity) => {
+                match identities.keys_for_identity(identity, today) {
+                    Ok(identity_keys) => {
+                        for key in identity_keys {
+                            keys.insert(key);
                         }
-                    } else {
-                        undefined_identities.push(identity)
                     }
+                    Err(e) => identity_errors.push(e),
                 }
             }
+            // exclusions of exclusions are rejected when parsing
+            AuthorizedItem::Exclusion(_) => {}
+        };
+
+        for item in &self.0 {
+            match item {
+                AuthorizedItem::Exclusion(item) => match &**item {
+                    // excluded keys need not be known, e.g., if they are compromised
+                    AuthorizedItem::Fingerprint(fingerprint) => {
+                        exclusions.fingerprints.push(fingerprint.clone())
+                    }
+                    item => expand(item, &mut exclusions.keys),
+                },
+                item => expand(item, &mut authorized_keys),
+            }
         }
 
+        authorized_keys.retain(|key| !exclusions.contains(key));
+
         CollectAuthorizedKeys {
             authorized_keys,
-            undefined_identities,
+            exclusions,
+            identity_errors,
+            unresolved_fingerprints,
         }
     }
 }
@@ -62,8 +195,15 @@ impl FromStr for AuthorizedItem {
     type Err = ParseAuthorizedItemError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        if let Ok(identity) = s.parse::<Identity>() {
+        if let Some(s) = s.strip_prefix('!') {
+            match s.parse()? {
+                AuthorizedItem::Exclusion(_) => Err(ParseAuthorizedItemError),
+                item => Ok(AuthorizedItem::Exclusion(Box::new(item))),
+            }
+        } else if let Ok(identity) = s.parse::<Identity>() {
             Ok(AuthorizedItem::Identity(identity))
+        } else if let Ok(fingerprint) = s.parse::<Fingerprint>() {
+            Ok(AuthorizedItem::Fingerprint(fingerprint))
         } else if let Ok(key) = s.parse::<PublicKey>() {
             Ok(AuthorizedItem::PublicKey(key))
         } else {
@@ -84,14 +224,56 @@ impl From<AuthorizedKeys> for AuthorizedItems {
     }
 }
 
+impl fmt::Display for AuthorizedItem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PublicKey(key) => write!(f, "{}", key),
+            Self::Identity(identity) => write!(f, "{}", identity),
+            Self::Fingerprint(fingerprint) => write!(f, "{}", fingerprint),
+            Self::Exclusion(item) => write!(f, "!{}", item),
+        }
+    }
+}
+
 impl Serialize for AuthorizedItem {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        match self {
-            Self::PublicKey(key) => serializer.serialize_str(&key.to_string()),
-            Self::Identity(identity) => serializer.serialize_str(&identity.to_string()),
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for AuthorizedItem {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(AuthorizedItemVisitor)
+    }
+}
+
+struct AuthorizedItemVisitor;
+
+impl<'de> Visitor<'de> for AuthorizedItemVisitor {
+    type Value = AuthorizedItem;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an @identity, a fingerprint or a public key, optionally preceded by !")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        if v.starts_with(['@', '!']) || v.starts_with("SHA256:") || v.starts_with("MD5:") {
+            v.parse()
+                .map_err(|_| de::Error::invalid_value(de::Unexpected::Str(v), &self))
+        } else {
+            // anything else is meant to be a public key, so tell what is wrong with it
+            v.parse()
+                .map(AuthorizedItem::PublicKey)
+                .map_err(de::Error::custom)
         }
     }
 }
@@ -99,6 +281,7 @@ impl Serialize for AuthorizedItem {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_keys;
 
     #[test]
     fn collect_authorized_keys() {
@@ -107,19 +290,168 @@ mod tests {
         items.insert(AuthorizedItem::Identity("@bar".parse().unwrap()));
         items.insert(AuthorizedItem::Identity("@baz".parse().unwrap()));
 
+        let known_keys = AuthorizedKeys::default();
+
         assert_eq!(
             items
-                .collect_authorized_keys(&test_identities())
+                .collect_authorized_keys(&test_identities(), &known_keys, today())
                 .authorized_keys,
-            collect_keys(&["ssh-rsa foo", "ssh-rsa bar", "ssh-rsa baz"])
+            collect_keys([test_keys::FOO, test_keys::BAR, test_keys::BAZ])
+        );
+
+        let identity_errors = items
+            .collect_authorized_keys(&test_identities(), &known_keys, today())
+            .identity_errors;
+        assert!(matches!(
+            identity_errors.as_slice(),
+            [identity::Error::Undefined(identity)] if *identity == "@baz".parse::<Identity>().unwrap()
+        ));
+    }
+
+    #[test]
+    fn collect_authorized_keys_by_fingerprint() {
+        let mut items = AuthorizedItems::default();
+        // fingerprint of `test_keys::BAR`
+        items.insert(AuthorizedItem::Fingerprint(
+            "SHA256:yTtpXF+H4WUpJ+FKpId+Q8zQ5ZwnHI0LM6yRTSJixh4"
+                .parse()
+                .unwrap(),
+        ));
+        // fingerprint of `test_keys::RSA_2048`
+        items.insert(AuthorizedItem::Fingerprint(
+            "SHA256:Zre1K9ViCcK9y7Ms4/ja4KVURDhjaoTdxBSVP1auWjQ"
+                .parse()
+                .unwrap(),
+        ));
+
+        let known_keys = collect_keys([test_keys::FOO, test_keys::BAR]);
+        let collect = items.collect_authorized_keys(&Identities::default(), &known_keys, today());
+
+        assert_eq!(collect.authorized_keys, collect_keys([test_keys::BAR]));
+        assert_eq!(
+            collect.unresolved_fingerprints,
+            vec![&"SHA256:Zre1K9ViCcK9y7Ms4/ja4KVURDhjaoTdxBSVP1auWjQ"
+                .parse::<Fingerprint>()
+                .unwrap()]
         );
+    }
+
+    #[test]
+    fn collect_authorized_keys_with_exclusions() {
+        let items: AuthorizedItems = serde_yaml::from_str(&format!(
+            "- '@foo'
+- '@bar'
+- '!{baz}'
+# fingerprint of `test_keys::RSA_2048`, which is not known
+- '!SHA256:Zre1K9ViCcK9y7Ms4/ja4KVURDhjaoTdxBSVP1auWjQ'
+",
+            baz = test_keys::BAZ,
+        ))
+        .unwrap();
+
+        let collect =
+            items.collect_authorized_keys(&test_identities(), &AuthorizedKeys::default(), today());
 
         assert_eq!(
+            collect.authorized_keys,
+            collect_keys([test_keys::FOO, test_keys::BAR])
+        );
+        assert!(collect.unresolved_fingerprints.is_empty());
+        assert!(collect
+            .exclusions
+            .contains(&test_keys::BAZ.parse().unwrap()));
+        assert!(collect
+            .exclusions
+            .contains(&test_keys::RSA_2048.parse().unwrap()));
+        assert!(!collect
+            .exclusions
+            .contains(&test_keys::FOO.parse().unwrap()));
+    }
+
+    #[test]
+    fn authorizing_items() {
+        let items: AuthorizedItems = serde_yaml::from_str(&format!(
+            "- '@foo'
+- '@bar'
+- {baz}
+- SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo
+- '!@bar'
+",
+            baz = test_keys::BAZ,
+        ))
+        .unwrap();
+        let authorizing = |key: &str| {
             items
-                .collect_authorized_keys(&test_identities())
-                .undefined_identities,
-            vec![&"@baz".parse::<Identity>().unwrap()]
+                .authorizing(&key.parse().unwrap(), &test_identities(), today())
+                .into_iter()
+                .map(|item| item.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            authorizing(test_keys::FOO),
+            ["@foo", "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo"]
         );
+        assert_eq!(authorizing(test_keys::BAZ), ["@foo", test_keys::BAZ]);
+        assert_eq!(authorizing(test_keys::BAR), ["@bar"]);
+        assert!(authorizing(test_keys::ECDSA).is_empty());
+    }
+
+    #[test]
+    fn item_authorizes_keys() {
+        let authorizes = |item: &str, key: &str| {
+            item.parse::<AuthorizedItem>().unwrap().authorizes(
+                &key.parse().unwrap(),
+                &test_identities(),
+                today(),
+            )
+        };
+
+        assert!(authorizes("@foo", test_keys::BAZ));
+        assert!(!authorizes("@foo", test_keys::BAR));
+        assert!(!authorizes("@undefined", test_keys::FOO));
+        // fingerprint of `test_keys::FOO`
+        assert!(authorizes(
+            "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo",
+            test_keys::FOO
+        ));
+        assert!(authorizes(
+            test_keys::BAR,
+            &format!("no-pty {}", test_keys::BAR)
+        ));
+        assert!(!authorizes(&format!("!{}", test_keys::BAR), test_keys::BAR));
+    }
+
+    #[test]
+    fn parse_authorized_item() {
+        assert_eq!(
+            "@foo".parse::<AuthorizedItem>().unwrap(),
+            AuthorizedItem::Identity("@foo".parse().unwrap())
+        );
+        assert_eq!(
+            "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo"
+                .parse::<AuthorizedItem>()
+                .unwrap(),
+            AuthorizedItem::Fingerprint(
+                "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo"
+                    .parse()
+                    .unwrap()
+            )
+        );
+        assert_eq!(
+            test_keys::FOO.parse::<AuthorizedItem>().unwrap(),
+            AuthorizedItem::PublicKey(test_keys::FOO.parse().unwrap())
+        );
+        assert!("foo".parse::<AuthorizedItem>().is_err());
+
+        let exclusion = "!@foo".parse::<AuthorizedItem>().unwrap();
+        assert_eq!(
+            exclusion,
+            AuthorizedItem::Exclusion(Box::new(AuthorizedItem::Identity("@foo".parse().unwrap())))
+        );
+        assert_eq!(exclusion.to_string(), "!@foo");
+        assert!("!!@foo".parse::<AuthorizedItem>().is_err());
+        assert!("!foo".parse::<AuthorizedItem>().is_err());
     }
 
     #[test]
@@ -128,19 +460,23 @@ mod tests {
         items.insert(AuthorizedItem::Identity("@foo".parse().unwrap()));
         items.insert(AuthorizedItem::Identity("@foo".parse().unwrap()));
         items.insert(AuthorizedItem::Identity("@bar".parse().unwrap()));
-        items.insert(AuthorizedItem::PublicKey("ssh-rsa foo".parse().unwrap()));
-        items.insert(AuthorizedItem::PublicKey("ssh-rsa bar".parse().unwrap()));
-        items.insert(AuthorizedItem::PublicKey("ssh-rsa bar".parse().unwrap()));
+        items.insert(AuthorizedItem::PublicKey(test_keys::FOO.parse().unwrap()));
+        items.insert(AuthorizedItem::PublicKey(test_keys::BAR.parse().unwrap()));
+        items.insert(AuthorizedItem::PublicKey(test_keys::BAR.parse().unwrap()));
         assert_eq!(items.0.len(), 4);
     }
 
+    fn today() -> Date {
+        "2024-01-15".parse().unwrap()
+    }
+
     fn test_identities() -> Identities {
         let mut identities = Identities::default();
         identities.set_keys_for_identity(
-            collect_keys(&["ssh-rsa foo", "ssh-rsa baz"]),
+            collect_keys([test_keys::FOO, test_keys::BAZ]),
             &"@foo".parse().unwrap(),
         );
-        identities.set_keys_for_identity(collect_keys(&["ssh-rsa bar"]), &"@bar".parse().unwrap());
+        identities.set_keys_for_identity(collect_keys([test_keys::BAR]), &"@bar".parse().unwrap());
         identities
     }
 
@@ -1,52 +1,12 @@
-use crate::public_key::PublicKey;
+use crate::{fingerprint::Fingerprint, public_key::PublicKey};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
-use std::fmt::Write;
-use std::io::BufRead;
-
-type Result<T> = anyhow::Result<T>;
 
 #[derive(Serialize, Deserialize, Clone, Default, Debug, Eq, PartialEq)]
 #[serde(transparent)]
 pub struct AuthorizedKeys(HashSet<PublicKey>);
 
 impl AuthorizedKeys {
-    /// Read the authorized keys using `reader`.
-    pub fn from_reader<R>(reader: R) -> Result<Self>
-    where
-        R: BufRead,
-    {
-        let mut authorized_keys = AuthorizedKeys::default();
-
-        for line in reader.lines() {
-            match line {
-                Ok(line) => {
-                    if line.is_empty() {
-                        continue;
-                    }
-
-                    let key: PublicKey = line.parse()?;
-                    authorized_keys.insert(key)
-                }
-                Err(e) => return Err(e)?,
-            }
-        }
-
-        Ok(authorized_keys)
-    }
-
-    /// Write the authorized keys using `writer`.
-    pub fn to_writer<W>(&self, writer: &mut W) -> Result<()>
-    where
-        W: Write,
-    {
-        for key in self.sorted_keys() {
-            writeln!(writer, "{}", key)?;
-        }
-
-        Ok(())
-    }
-
     /// Add a key to the authorized keys.
     pub fn insert(&mut self, key: PublicKey) {
         self.0.insert(key);
@@ -67,6 +27,26 @@ impl AuthorizedKeys {
         self.0.contains(key)
     }
 
+    /// Retains only the keys for which `f` returns `true`.
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&PublicKey) -> bool,
+    {
+        self.0.retain(f);
+    }
+
+    /// Returns the key in the authorized keys equal to `key`, which may differ in its options and comment.
+    pub fn get(&self, key: &PublicKey) -> Option<&PublicKey> {
+        self.0.get(key)
+    }
+
+    /// Returns the key with the given fingerprint, if any.
+    pub fn find_by_fingerprint(&self, fingerprint: &Fingerprint) -> Option<&PublicKey> {
+        self.0
+            .iter()
+            .find(|key| key.fingerprint(fingerprint.alg()) == *fingerprint)
+    }
+
     /// Returns the difference,
     /// i.e., the keys that are in `self` but not in `other`.
     pub fn difference(&self, other: &AuthorizedKeys) -> AuthorizedKeys {
@@ -80,14 +60,15 @@ impl AuthorizedKeys {
         self.0.is_superset(&other.0)
     }
 
-    fn sorted_keys(&self) -> Vec<&PublicKey> {
+    /// Returns all keys in sorted order.
+    pub fn sorted_keys(&self) -> Vec<&PublicKey> {
         let mut keys: Vec<_> = self.0.iter().collect();
         keys.sort();
         keys
     }
 
     /// An iterator visiting all keys in arbitrary order.
-    pub fn iter(&self) -> AuthorizedKeysIter {
+    pub fn iter(&self) -> AuthorizedKeysIter<'_> {
         AuthorizedKeysIter(self.0.iter())
     }
 }
@@ -116,82 +97,81 @@ impl IntoIterator for AuthorizedKeys {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::io::Cursor;
+    use crate::test_keys;
 
     #[test]
     fn authorized_keys_contains() {
-        let cursor = Cursor::new("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3=\nssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg=");
-        let authorized_keys = AuthorizedKeys::from_reader(cursor).unwrap();
+        let authorized_keys = collect_keys([test_keys::FOO, test_keys::BAR]);
 
-        assert!(authorized_keys.contains(
-            &("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3="
-                .parse()
-                .unwrap())
-        ));
-        assert!(authorized_keys.contains(
-            &("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg="
-                .parse()
-                .unwrap())
-        ));
+        assert!(authorized_keys.contains(&test_keys::FOO.parse().unwrap()));
+        assert!(authorized_keys.contains(&test_keys::BAR.parse().unwrap()));
 
+        assert!(
+            authorized_keys.contains(&(test_keys::FOO.replace(" foo", " random")).parse().unwrap())
+        );
         assert!(authorized_keys.contains(
-            &("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3= random"
-                .parse()
-                .unwrap())
-        ));
-        assert!(authorized_keys.contains(
-            &("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg= comment"
+            &(test_keys::BAR.replace(" bar", " comment"))
                 .parse()
-                .unwrap())
+                .unwrap()
         ));
 
-        assert!(!authorized_keys.contains(
-            &("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mj="
-                .parse()
-                .unwrap())
-        ));
+        assert!(!authorized_keys.contains(&test_keys::BAZ.parse().unwrap()));
     }
 
     #[test]
-    fn authorized_keys_difference() {}
+    fn authorized_keys_difference() {
+        let foo_bar = collect_keys([test_keys::FOO, test_keys::BAR]);
+        let bar_baz = collect_keys([test_keys::BAR, test_keys::BAZ]);
+
+        assert_eq!(foo_bar.difference(&bar_baz), collect_keys([test_keys::FOO]));
+        assert_eq!(bar_baz.difference(&foo_bar), collect_keys([test_keys::BAZ]));
+        assert!(foo_bar.difference(&foo_bar).is_empty());
+    }
 
     #[test]
-    fn read_authorized_keys() {
-        let cursor = Cursor::new("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3=\nssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg=\n\n");
-        let authorized_keys = AuthorizedKeys::from_reader(cursor).unwrap();
+    fn find_by_fingerprint() {
+        let authorized_keys = collect_keys([test_keys::FOO, test_keys::BAR]);
 
         assert_eq!(
-            authorized_keys.0,
-            HashSet::from_iter(
-                [
-                    "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3="
-                        .parse()
-                        .unwrap(),
-                    "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg="
-                        .parse()
-                        .unwrap()
-                ]
-                .into_iter()
-            )
+            authorized_keys.find_by_fingerprint(
+                &"SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo"
+                    .parse()
+                    .unwrap()
+            ),
+            Some(&test_keys::FOO.parse().unwrap())
+        );
+        assert_eq!(
+            authorized_keys.find_by_fingerprint(
+                &"MD5:a5:58:02:eb:69:5c:1b:ef:9a:aa:b3:94:da:6e:0a:98"
+                    .parse()
+                    .unwrap()
+            ),
+            Some(&test_keys::BAR.parse().unwrap())
+        );
+        assert_eq!(
+            authorized_keys.find_by_fingerprint(
+                &"SHA256:OHRTNYB4zrvmV3KszsXfvWYnvBDA0yCxN1yE0wsa7KY"
+                    .parse()
+                    .unwrap()
+            ),
+            None
         );
     }
 
     #[test]
-    fn write_authorized_keys() {
-        let authorized_keys = AuthorizedKeys(HashSet::from_iter(
-            [
-                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg="
-                    .parse()
-                    .unwrap(),
-                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3="
-                    .parse()
-                    .unwrap(),
+    fn sorted_keys() {
+        let authorized_keys = collect_keys([test_keys::FOO, test_keys::BAR]);
+
+        assert_eq!(
+            authorized_keys.sorted_keys(),
+            vec![
+                &test_keys::BAR.parse::<PublicKey>().unwrap(),
+                &test_keys::FOO.parse::<PublicKey>().unwrap()
             ]
-            .into_iter(),
-        ));
+        );
+    }
 
-        let mut output = String::new();
-        authorized_keys.to_writer(&mut output).unwrap();
-        assert_eq!(output.as_str(), "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3=\nssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mg=\n");
+    fn collect_keys<const N: usize>(keys: [&str; N]) -> AuthorizedKeys {
+        AuthorizedKeys(keys.into_iter().map(|key| key.parse().unwrap()).collect())
     }
 }
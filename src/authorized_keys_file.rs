@@ -0,0 +1,485 @@
+use crate::{authorized_keys::AuthorizedKeys, public_key::PublicKey};
+use std::{fmt, ops::Range};
+
+const BEGIN_MARKER: &str = "# BEGIN authorized-keys managed";
+const END_MARKER: &str = "# END authorized-keys managed";
+
+/// The contents of an `authorized_keys` file.
+///
+/// Unlike [`AuthorizedKeys`], this keeps comments, blank lines, invalid lines
+/// and the order of all lines, so that an unmodified file is written back byte for byte.
+#[derive(Clone, Debug, Default)]
+pub struct AuthorizedKeysFile {
+    lines: Vec<Line>,
+    newline_at_end: bool,
+}
+
+#[derive(Clone, Debug)]
+enum Line {
+    /// A line containing a public key, along with its original text.
+    Key { text: String, key: PublicKey },
+    /// A comment or blank line.
+    Comment(String),
+    /// A line that could not be parsed as a public key.
+    Invalid { text: String, error: String },
+}
+
+/// The part of an `authorized_keys` file that is owned by this tool.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Scope {
+    /// The whole file.
+    File,
+    /// Only the lines between the managed block markers.
+    ManagedBlock,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("managed block starting at line {0} is not terminated")]
+    UnterminatedManagedBlock(usize),
+    #[error("unexpected end of managed block at line {0}")]
+    UnexpectedEndMarker(usize),
+    #[error("unexpected second managed block at line {0}")]
+    MultipleManagedBlocks(usize),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A line of an `authorized_keys` file that could not be parsed.
+pub struct InvalidLine<'a> {
+    /// The line number, starting at 1.
+    pub number: usize,
+    pub error: &'a str,
+}
+
+impl AuthorizedKeysFile {
+    /// Parse the contents of an `authorized_keys` file.
+    pub fn parse(text: &str) -> Self {
+        if text.is_empty() {
+            return AuthorizedKeysFile::default();
+        }
+
+        let (text, newline_at_end) = match text.strip_suffix('\n') {
+            Some(text) => (text, true),
+            None => (text, false),
+        };
+
+        let lines = text.split('\n').map(Line::parse).collect();
+
+        AuthorizedKeysFile {
+            lines,
+            newline_at_end,
+        }
+    }
+
+    /// Returns the keys contained in `scope`.
+    pub fn authorized_keys(&self, scope: Scope) -> Result<AuthorizedKeys> {
+        let mut authorized_keys = AuthorizedKeys::default();
+
+        for line in &self.lines[self.range(scope)?] {
+            if let Line::Key { key, .. } = line {
+                authorized_keys.insert(key.clone());
+            }
+        }
+
+        Ok(authorized_keys)
+    }
+
+    /// Returns all lines in `scope` that could not be parsed.
+    pub fn invalid_lines(&self, scope: Scope) -> Result<Vec<InvalidLine<'_>>> {
+        let range = self.range(scope)?;
+        let offset = range.start;
+
+        let invalid_lines = self.lines[range]
+            .iter()
+            .enumerate()
+            .filter_map(|(i, line)| match line {
+                Line::Invalid { error, .. } => Some(InvalidLine {
+                    number: offset + i + 1,
+                    error,
+                }),
+                _ => None,
+            })
+            .collect();
+
+        Ok(invalid_lines)
+    }
+
+    /// Replace the keys contained in `scope` with `authorized_keys`.
+    ///
+    /// Lines of keys that remain are kept as they are, unless their options differ from those
+    /// of the key in `authorized_keys`, in which case the line is replaced.
+    /// Lines of other keys are removed and keys that are not yet contained in `scope` are appended at its end.
+    /// A managed block is added at the end of the file if there is none yet.
+    pub fn set_authorized_keys(
+        &mut self,
+        scope: Scope,
+        authorized_keys: &AuthorizedKeys,
+    ) -> Result<()> {
+        if scope == Scope::ManagedBlock && self.managed_block()?.is_none() {
+            self.lines.push(Line::Comment(BEGIN_MARKER.to_owned()));
+            self.lines.push(Line::Comment(END_MARKER.to_owned()));
+            self.newline_at_end = true;
+        }
+
+        let existing_keys = self.authorized_keys(scope)?;
+        let range = self.range(scope)?;
+
+        let mut lines: Vec<_> = self.lines.drain(range.start..).collect();
+        let tail = lines.split_off(range.len());
+
+        lines.retain(|line| match line {
+            Line::Key { key, .. } => authorized_keys.contains(key),
+            _ => true,
+        });
+
+        for line in &mut lines {
+            if let Line::Key { key, .. } = line {
+                match authorized_keys.get(key) {
+                    Some(new_key) if new_key.options() != key.options() => {
+                        *line = Line::Key {
+                            text: new_key.to_string(),
+                            key: new_key.clone(),
+                        };
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let new_keys = authorized_keys.difference(&existing_keys);
+        for key in new_keys.sorted_keys() {
+            lines.push(Line::Key {
+                text: key.to_string(),
+                key: key.clone(),
+            });
+
+            if tail.is_empty() {
+                self.newline_at_end = true;
+            }
+        }
+
+        self.lines.extend(lines);
+        self.lines.extend(tail);
+
+        Ok(())
+    }
+
+    /// Returns the range of lines in `scope`.
+    fn range(&self, scope: Scope) -> Result<Range<usize>> {
+        match scope {
+            Scope::File => Ok(0..self.lines.len()),
+            Scope::ManagedBlock => Ok(self.managed_block()?.unwrap_or_default()),
+        }
+    }
+
+    /// Returns the range of lines between the managed block markers, if any.
+    fn managed_block(&self) -> Result<Option<Range<usize>>> {
+        let mut block = None;
+        let mut begin = None;
+
+        for (i, line) in self.lines.iter().enumerate() {
+            match line.text().trim() {
+                BEGIN_MARKER if begin.is_some() || block.is_some() => {
+                    return Err(Error::MultipleManagedBlocks(i + 1))
+                }
+                BEGIN_MARKER => begin = Some(i),
+                END_MARKER => match begin.take() {
+                    Some(begin) => block = Some(begin + 1..i),
+                    None => return Err(Error::UnexpectedEndMarker(i + 1)),
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(begin) = begin {
+            return Err(Error::UnterminatedManagedBlock(begin + 1));
+        }
+
+        Ok(block)
+    }
+}
+
+impl Line {
+    fn parse(text: &str) -> Self {
+        // lines may end with a carriage return, which is kept in `text`
+        let trimmed = text.trim();
+
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            return Line::Comment(text.to_owned());
+        }
+
+        match trimmed.parse() {
+            Ok(key) => Line::Key {
+                text: text.to_owned(),
+                key,
+            },
+            Err(e) => Line::Invalid {
+                text: text.to_owned(),
+                error: e.to_string(),
+            },
+        }
+    }
+
+    fn text(&self) -> &str {
+        match self {
+            Line::Key { text, .. } => text,
+            Line::Comment(text) => text,
+            Line::Invalid { text, .. } => text,
+        }
+    }
+}
+
+impl fmt::Display for AuthorizedKeysFile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            f.write_str(line.text())?;
+        }
+
+        if self.newline_at_end {
+            f.write_str("\n")?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_keys;
+    use std::collections::HashSet;
+
+    #[test]
+    fn read_authorized_keys() {
+        let file =
+            AuthorizedKeysFile::parse(&format!("{}\n{}\n\n", test_keys::FOO, test_keys::BAR));
+
+        assert_eq!(
+            HashSet::<PublicKey>::from_iter(file.authorized_keys(Scope::File).unwrap()),
+            HashSet::from_iter([
+                test_keys::FOO.parse().unwrap(),
+                test_keys::BAR.parse().unwrap()
+            ])
+        );
+    }
+
+    #[test]
+    fn write_authorized_keys() {
+        let mut file = AuthorizedKeysFile::default();
+        file.set_authorized_keys(Scope::File, &keys([test_keys::FOO, test_keys::BAR]))
+            .unwrap();
+
+        assert_eq!(
+            file.to_string(),
+            format!("{}\n{}\n", test_keys::BAR, test_keys::FOO)
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        for text in [
+            "".to_owned(),
+            "\n".to_owned(),
+            test_keys::FOO.to_owned(),
+            format!(
+                "# managed by hand\n\n{}  \n# second key\n{}\r\n\n",
+                test_keys::FOO,
+                test_keys::BAR
+            ),
+            format!("ssh-rsa invalid\n{}\n", test_keys::FOO),
+        ] {
+            assert_eq!(AuthorizedKeysFile::parse(&text).to_string(), text);
+        }
+    }
+
+    #[test]
+    fn comments_are_not_keys() {
+        let file =
+            AuthorizedKeysFile::parse(&format!("# {}\n{}\n", test_keys::FOO, test_keys::BAR));
+
+        assert_eq!(
+            file.authorized_keys(Scope::File).unwrap(),
+            keys([test_keys::BAR])
+        );
+        assert!(file.invalid_lines(Scope::File).unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_lines() {
+        let file = AuthorizedKeysFile::parse(&format!("{}\nssh-rsa invalid\n", test_keys::FOO));
+        let invalid_lines = file.invalid_lines(Scope::File).unwrap();
+
+        assert_eq!(invalid_lines.len(), 1);
+        assert_eq!(invalid_lines[0].number, 2);
+        assert_eq!(invalid_lines[0].error, "key data is not valid base64");
+    }
+
+    #[test]
+    fn set_authorized_keys() {
+        let mut file = AuthorizedKeysFile::parse(&format!(
+            "# header\n{}\n# keep me\n{}\nssh-rsa invalid\n",
+            test_keys::FOO.replace(" foo", " foo with a changed comment"),
+            test_keys::BAR
+        ));
+
+        file.set_authorized_keys(Scope::File, &keys([test_keys::FOO, test_keys::BAZ]))
+            .unwrap();
+
+        assert_eq!(
+            file.to_string(),
+            format!(
+                "# header\n{}\n# keep me\nssh-rsa invalid\n{}\n",
+                test_keys::FOO.replace(" foo", " foo with a changed comment"),
+                test_keys::BAZ
+            )
+        );
+    }
+
+    #[test]
+    fn set_authorized_keys_with_options() {
+        let mut file =
+            AuthorizedKeysFile::parse(&format!("no-pty {}\n{}\n", test_keys::FOO, test_keys::BAR));
+        let new_foo = format!("expiry-time=\"20301231\" {}", test_keys::FOO);
+
+        file.set_authorized_keys(Scope::File, &keys([new_foo.as_str(), test_keys::BAR]))
+            .unwrap();
+
+        assert_eq!(
+            file.to_string(),
+            format!("{}\n{}\n", new_foo, test_keys::BAR)
+        );
+
+        // options are removed as well, e.g., after pushing without `--expiry-time`
+        file.set_authorized_keys(Scope::File, &keys([test_keys::FOO, test_keys::BAR]))
+            .unwrap();
+        assert_eq!(
+            file.to_string(),
+            format!("{}\n{}\n", test_keys::FOO, test_keys::BAR)
+        );
+    }
+
+    #[test]
+    fn set_unchanged_authorized_keys() {
+        let text = format!("# header\n{}\n\n{}", test_keys::FOO, test_keys::BAR);
+        let mut file = AuthorizedKeysFile::parse(&text);
+        file.set_authorized_keys(Scope::File, &file.authorized_keys(Scope::File).unwrap())
+            .unwrap();
+
+        assert_eq!(file.to_string(), text);
+    }
+
+    #[test]
+    fn managed_block_authorized_keys() {
+        let file = AuthorizedKeysFile::parse(&format!(
+            "{}\n{}\n{}\nssh-rsa invalid\n{}\n",
+            test_keys::FOO,
+            BEGIN_MARKER,
+            test_keys::BAR,
+            END_MARKER
+        ));
+
+        assert_eq!(
+            file.authorized_keys(Scope::ManagedBlock).unwrap(),
+            keys([test_keys::BAR])
+        );
+        assert_eq!(
+            file.authorized_keys(Scope::File).unwrap(),
+            keys([test_keys::FOO, test_keys::BAR])
+        );
+        assert_eq!(
+            file.invalid_lines(Scope::ManagedBlock).unwrap()[0].number,
+            4
+        );
+    }
+
+    #[test]
+    fn set_managed_block_authorized_keys() {
+        let mut file = AuthorizedKeysFile::parse(&format!(
+            "# cloud-init\n{}\n{}\n{}\n{}\n# vendor agent\n{}\n",
+            test_keys::FOO,
+            BEGIN_MARKER,
+            test_keys::BAR,
+            END_MARKER,
+            test_keys::BAZ,
+        ));
+
+        file.set_authorized_keys(
+            Scope::ManagedBlock,
+            &keys([test_keys::FOO, test_keys::RSA_3072]),
+        )
+        .unwrap();
+
+        assert_eq!(
+            file.to_string(),
+            format!(
+                "# cloud-init\n{}\n{}\n{}\n{}\n{}\n# vendor agent\n{}\n",
+                test_keys::FOO,
+                BEGIN_MARKER,
+                test_keys::RSA_3072,
+                test_keys::FOO,
+                END_MARKER,
+                test_keys::BAZ,
+            )
+        );
+    }
+
+    #[test]
+    fn add_managed_block() {
+        let mut file = AuthorizedKeysFile::parse(&format!("# cloud-init\n{}", test_keys::FOO));
+
+        assert!(file
+            .authorized_keys(Scope::ManagedBlock)
+            .unwrap()
+            .is_empty());
+
+        file.set_authorized_keys(Scope::ManagedBlock, &keys([test_keys::BAR]))
+            .unwrap();
+
+        assert_eq!(
+            file.to_string(),
+            format!(
+                "# cloud-init\n{}\n{}\n{}\n{}\n",
+                test_keys::FOO,
+                BEGIN_MARKER,
+                test_keys::BAR,
+                END_MARKER
+            )
+        );
+    }
+
+    #[test]
+    fn invalid_managed_blocks() {
+        for (text, line) in [
+            (format!("{}\n{}\n", test_keys::FOO, BEGIN_MARKER), 2),
+            (format!("{}\n{}\n", END_MARKER, BEGIN_MARKER), 1),
+            (
+                format!(
+                    "{}\n{}\n{}\n{}\n",
+                    BEGIN_MARKER, END_MARKER, BEGIN_MARKER, END_MARKER
+                ),
+                3,
+            ),
+        ] {
+            let file = AuthorizedKeysFile::parse(&text);
+
+            match file.authorized_keys(Scope::ManagedBlock) {
+                Err(Error::UnterminatedManagedBlock(n))
+                | Err(Error::UnexpectedEndMarker(n))
+                | Err(Error::MultipleManagedBlocks(n)) => assert_eq!(n, line),
+                Ok(_) => panic!("expected an error for {:?}", text),
+            }
+        }
+    }
+
+    fn keys<const N: usize>(keys: [&str; N]) -> AuthorizedKeys {
+        let mut authorized_keys = AuthorizedKeys::default();
+        for key in keys {
+            authorized_keys.insert(key.parse().unwrap());
+        }
+        authorized_keys
+    }
+}
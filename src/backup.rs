@@ -0,0 +1,188 @@
+use crate::transport::Transport;
+use std::{
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+const BACKUP_INFIX: &str = ".bak.";
+
+/// The number of backups kept of each file, older ones are removed after writing it.
+pub const KEPT_BACKUPS: usize = 10;
+
+/// The timestamp of the most recent backup taken by this process.
+static LAST_TIMESTAMP: AtomicU64 = AtomicU64::new(0);
+
+/// Returns the path of the backup of `path` taken at `timestamp`,
+/// given in nanoseconds since the Unix epoch.
+pub fn backup_path(path: &str, timestamp: u64) -> String {
+    format!("{}{}{}", path, BACKUP_INFIX, timestamp)
+}
+
+/// Returns the path of a new backup of `path`.
+///
+/// The timestamp is increased if needed so that no two backups taken by this process share it,
+/// and the process ID is appended so that concurrent processes do not overwrite each other's backups.
+pub fn new_backup_path(path: &str) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64);
+    let previous = LAST_TIMESTAMP
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| {
+            Some(now.max(last + 1))
+        })
+        .expect("update always succeeds");
+    let timestamp = now.max(previous + 1);
+
+    format!("{}.{}", backup_path(path, timestamp), process::id())
+}
+
+/// Returns the most recent backup of `path` on the host accessed by `transport`.
+pub fn find_latest_backup(transport: &dyn Transport, path: &str) -> anyhow::Result<Option<String>> {
+    let backups = list_files(transport, path)?;
+    Ok(latest_backup(path, &backups).map(str::to_owned))
+}
+
+/// Removes all but the `keep` most recent backups of `path` on the host accessed by `transport`.
+pub fn prune_backups(transport: &dyn Transport, path: &str, keep: usize) -> anyhow::Result<()> {
+    let files = list_files(transport, path)?;
+
+    for backup in outdated_backups(path, &files, keep) {
+        transport.remove_file(backup)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the paths of the files in the directory of `path`.
+fn list_files(transport: &dyn Transport, path: &str) -> anyhow::Result<Vec<String>> {
+    Ok(match path.rsplit_once('/') {
+        Some((dir, _)) => transport
+            .list(if dir.is_empty() { "/" } else { dir })?
+            .into_iter()
+            .map(|name| format!("{}/{}", dir, name))
+            .collect(),
+        None => transport.list(".")?,
+    })
+}
+
+/// Returns the most recent backup of `path` among `backups`.
+pub fn latest_backup<'a>(path: &str, backups: &'a [String]) -> Option<&'a str> {
+    sorted_backups(path, backups).pop()
+}
+
+/// Returns the backups of `path` among `backups`, except for the `keep` most recent ones.
+pub fn outdated_backups<'a>(path: &str, backups: &'a [String], keep: usize) -> Vec<&'a str> {
+    let mut backups = sorted_backups(path, backups);
+    backups.truncate(backups.len().saturating_sub(keep));
+    backups
+}
+
+/// Returns the backups of `path` among `backups`, from the oldest to the most recent one.
+///
+/// Backups are named by their timestamp, optionally followed by the ID of the process that took them.
+fn sorted_backups<'a>(path: &str, backups: &'a [String]) -> Vec<&'a str> {
+    let mut backups: Vec<_> = backups
+        .iter()
+        .filter_map(|backup| {
+            let suffix = backup.strip_prefix(path)?.strip_prefix(BACKUP_INFIX)?;
+            let (timestamp, pid) = match suffix.split_once('.') {
+                Some((timestamp, pid)) => (timestamp, pid.parse::<u32>().ok()?),
+                None => (suffix, 0),
+            };
+            Some(((timestamp.parse::<u64>().ok()?, pid), backup.as_str()))
+        })
+        .collect();
+    backups.sort();
+
+    backups.into_iter().map(|(_, backup)| backup).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn latest_backup_of_path() {
+        let path = "/home/test/.ssh/authorized_keys";
+        let backups = [
+            backup_path(path, 999),
+            backup_path(path, 1000),
+            backup_path(path, 10),
+            format!("{}.bak.invalid", path),
+            backup_path("/home/test/.ssh/authorized_keys2", 2000),
+        ];
+
+        assert_eq!(
+            latest_backup(path, &backups),
+            Some("/home/test/.ssh/authorized_keys.bak.1000")
+        );
+        assert_eq!(latest_backup(path, &[]), None);
+        assert_eq!(
+            outdated_backups(path, &backups, 2),
+            ["/home/test/.ssh/authorized_keys.bak.10"]
+        );
+        assert!(outdated_backups(path, &backups, 3).is_empty());
+    }
+
+    #[test]
+    fn new_backup_paths_are_unique_and_ordered() {
+        let path = "/home/test/.ssh/authorized_keys";
+        let backups: Vec<_> = (0..100).map(|_| new_backup_path(path)).collect();
+
+        let mut unique = backups.clone();
+        unique.dedup();
+        assert_eq!(unique.len(), backups.len());
+        assert_eq!(
+            latest_backup(path, &backups),
+            backups.last().map(String::as_str)
+        );
+        assert_eq!(
+            outdated_backups(path, &backups, 1),
+            backups[..99].iter().map(String::as_str).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn find_latest_backup_of_path() {
+        let transport = crate::transport::MemoryTransport::default();
+        let path = "/home/test/.ssh/authorized_keys";
+
+        assert_eq!(find_latest_backup(&transport, path).unwrap(), None);
+
+        transport.set_file(path, "current\n");
+        transport.set_file(&backup_path(path, 10), "old\n");
+        transport.set_file(&backup_path(path, 20), "older\n");
+        transport.set_file("/home/test/authorized_keys.bak.30", "elsewhere\n");
+
+        assert_eq!(
+            find_latest_backup(&transport, path).unwrap(),
+            Some(backup_path(path, 20))
+        );
+    }
+
+    #[test]
+    fn prune_backups_of_path() {
+        let transport = crate::transport::MemoryTransport::default();
+        let path = "/home/test/.ssh/authorized_keys";
+
+        transport.set_file(path, "current\n");
+        for timestamp in [30, 10, 20] {
+            transport.set_file(&backup_path(path, timestamp), "old\n");
+        }
+        transport.set_file("/home/test/.ssh/authorized_keys2.bak.5", "other\n");
+
+        prune_backups(&transport, path, 2).unwrap();
+        let mut files = transport.list("/home/test/.ssh").unwrap();
+        files.sort();
+        assert_eq!(
+            files,
+            [
+                "authorized_keys",
+                "authorized_keys.bak.20",
+                "authorized_keys.bak.30",
+                "authorized_keys2.bak.5",
+            ]
+        );
+    }
+}
@@ -0,0 +1,881 @@
+use crate::{
+    authorized_items::{AuthorizedItem, AuthorizedItems, CollectAuthorizedKeys, Exclusions},
+    authorized_keys::AuthorizedKeys,
+    authorized_keys_file::Scope,
+    date::Date,
+    fingerprint::Fingerprint,
+    identity::{self, Identities},
+    policy::Policy,
+    public_key::PublicKey,
+    revocation::Revoked,
+};
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{collections::BTreeMap, fmt, path::PathBuf};
+
+#[derive(Deserialize, Serialize)]
+pub struct Config {
+    /// Items and connection settings shared by several hosts
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub groups: BTreeMap<String, Group>,
+    pub hosts: BTreeMap<String, Host>,
+    pub identities: Option<Identities>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<Policy>,
+    /// Keys that must not be authorized on any host
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revoked: Option<Revoked>,
+    /// The directory of the configuration file, which relative paths in it are resolved against
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+/// A host and the items managed on it.
+///
+/// Hosts without connection settings and groups can be written as a plain list of items.
+pub struct Host {
+    /// The groups to inherit items and connection settings from, in order
+    pub groups: Vec<String>,
+    pub settings: ConnectionSettings,
+    pub items: Vec<Item>,
+}
+
+/// Items and connection settings inherited by the hosts in a group.
+#[derive(Deserialize, Serialize)]
+pub struct Group {
+    #[serde(flatten)]
+    pub settings: ConnectionSettings,
+    #[serde(default)]
+    pub items: Vec<Item>,
+}
+
+/// Settings for connecting to a host, overriding those of the SSH client.
+#[derive(Deserialize, Serialize, Clone, Default, Debug, Eq, PartialEq)]
+pub struct ConnectionSettings {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity_file: Option<String>,
+    /// Jump hosts to connect through, as for `ssh -J`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy_jump: Option<String>,
+    /// Timeout for establishing the connection, in seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub connect_timeout: Option<u64>,
+    /// Additional options, as for `ssh -o`
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "deserialize_ssh_options"
+    )]
+    pub ssh_options: BTreeMap<String, String>,
+}
+
+/// An item together with the host it is managed on,
+/// with the items and connection settings inherited from the groups of the host merged in.
+#[derive(Clone)]
+pub struct Target<'a> {
+    pub hostname: &'a str,
+    pub settings: ConnectionSettings,
+    pub item: Item,
+    /// The authorized items inherited from groups, also contained in `item`
+    pub inherited_items: AuthorizedItems,
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+pub struct Item {
+    /// The user to log in as
+    pub user: String,
+    pub path: String,
+    /// The user to access the file as, if different from the login user
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// The command used to access the file as `owner`
+    #[serde(default, rename = "become", skip_serializing_if = "Option::is_none")]
+    pub become_method: Option<BecomeMethod>,
+    /// Only manage the keys between the managed block markers, leaving the rest of the file untouched
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub managed_block: bool,
+    #[serde(rename = "authorized_keys")]
+    pub authorized_items: AuthorizedItems,
+}
+
+/// A command to run commands as another user.
+#[derive(Deserialize, Serialize, Clone, Copy, Default, Debug, Eq, Hash, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BecomeMethod {
+    #[default]
+    Sudo,
+    Doas,
+}
+
+/// Accessing files as another user than the login user.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct Become {
+    pub method: BecomeMethod,
+    pub user: String,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error(transparent)]
+    Identity(#[from] identity::Error),
+    #[error("no known key matches fingerprint {0}")]
+    UnresolvedFingerprint(Fingerprint),
+    #[error("undefined group {0}")]
+    UndefinedGroup(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Config {
+    /// Returns all items, ordered by host.
+    ///
+    /// The items of a host are preceded by those inherited from its groups,
+    /// and items with the same user and path are merged into one.
+    pub fn targets(&self) -> Result<Vec<Target<'_>>> {
+        let mut targets = Vec::new();
+
+        for (hostname, host) in &self.hosts {
+            let mut settings = ConnectionSettings::default();
+            let mut host_targets: Vec<Target> = Vec::new();
+            let mut merge = |item: &Item, inherited: bool| {
+                let inherited_items = if inherited {
+                    item.authorized_items.clone()
+                } else {
+                    AuthorizedItems::default()
+                };
+
+                match host_targets
+                    .iter_mut()
+                    .find(|target| target.item.user == item.user && target.item.path == item.path)
+                {
+                    Some(target) => {
+                        target.item.merge(item);
+                        target.inherited_items.extend(&inherited_items);
+                    }
+                    None => host_targets.push(Target {
+                        hostname,
+                        settings: ConnectionSettings::default(),
+                        item: item.clone(),
+                        inherited_items,
+                    }),
+                }
+            };
+
+            for name in &host.groups {
+                let group = self
+                    .groups
+                    .get(name)
+                    .ok_or_else(|| Error::UndefinedGroup(name.clone()))?;
+
+                settings.merge(&group.settings);
+                for item in &group.items {
+                    merge(item, true);
+                }
+            }
+
+            settings.merge(&host.settings);
+            for item in &host.items {
+                merge(item, false);
+            }
+
+            for mut target in host_targets {
+                target.settings = settings.clone();
+                targets.push(target);
+            }
+        }
+
+        Ok(targets)
+    }
+
+    /// Returns all keys known in the configuration,
+    /// i.e., the keys of all identities and all keys listed directly for a host.
+    pub fn known_keys(&self) -> AuthorizedKeys {
+        let mut known_keys = AuthorizedKeys::default();
+
+        if let Some(identities) = &self.identities {
+            for key in identities.keys() {
+                known_keys.insert(key.clone());
+            }
+        }
+
+        let items = self.hosts.values().flat_map(|host| &host.items);
+        let group_items = self.groups.values().flat_map(|group| &group.items);
+        for item in items.chain(group_items) {
+            for key in item.authorized_items.public_keys() {
+                known_keys.insert(key.clone());
+            }
+        }
+
+        known_keys
+    }
+
+    /// Lists keys referenced by fingerprint in full again if they are no longer known otherwise,
+    /// e.g., after `pull` rewrote the only item listing the full key, taking them from `keys`.
+    /// Items of hosts and groups are expanded alike.
+    pub fn expand_unresolved_fingerprints(&mut self, keys: &AuthorizedKeys) {
+        loop {
+            let known_keys = self.known_keys();
+            let group_items = self.groups.values_mut().flat_map(|group| &mut group.items);
+            let unresolved = self
+                .hosts
+                .values_mut()
+                .flat_map(|host| &mut host.items)
+                .chain(group_items)
+                .find_map(|item| {
+                    let key = item
+                        .authorized_items
+                        .fingerprints()
+                        .find_map(|fingerprint| {
+                            match known_keys.find_by_fingerprint(fingerprint) {
+                                Some(_) => None,
+                                None => keys.find_by_fingerprint(fingerprint),
+                            }
+                        })?;
+                    Some((item, key))
+                });
+
+            // once expanded, the key is known to all other items referencing it by fingerprint
+            match unresolved {
+                Some((item, key)) => {
+                    let fingerprint = item
+                        .authorized_items
+                        .fingerprints()
+                        .find(|fingerprint| key.fingerprint(fingerprint.alg()) == **fingerprint)
+                        .cloned()
+                        .expect("fingerprint of the key is listed");
+                    item.authorized_items
+                        .expand_fingerprint(&fingerprint, key.clone());
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+impl Item {
+    /// Returns the user owning the file, i.e., `owner` or else the login user.
+    pub fn file_owner(&self) -> &str {
+        self.owner.as_deref().unwrap_or(&self.user)
+    }
+
+    /// Returns how to access the file as its owner, if it is not the login user.
+    pub fn become_owner(&self) -> Option<Become> {
+        let owner = self.owner.as_ref().filter(|owner| **owner != self.user)?;
+
+        Some(Become {
+            method: self.become_method.unwrap_or_default(),
+            user: owner.clone(),
+        })
+    }
+
+    /// Returns the part of the authorized keys file managed for this item.
+    pub fn scope(&self) -> Scope {
+        if self.managed_block {
+            Scope::ManagedBlock
+        } else {
+            Scope::File
+        }
+    }
+
+    pub fn collect_authorized_keys(
+        &self,
+        identities: &Identities,
+        known_keys: &AuthorizedKeys,
+        today: Date,
+    ) -> Result<AuthorizedKeys> {
+        Ok(collect(&self.authorized_items, identities, known_keys, today)?.authorized_keys)
+    }
+
+    /// Returns the keys excluded by the exclusion items.
+    pub fn exclusions(
+        &self,
+        identities: &Identities,
+        known_keys: &AuthorizedKeys,
+        today: Date,
+    ) -> Result<Exclusions> {
+        Ok(collect(&self.authorized_items, identities, known_keys, today)?.exclusions)
+    }
+
+    /// Merges `other`, an item with the same user and path, into this item.
+    /// The authorized items are combined, and the settings of `other` take precedence if set.
+    fn merge(&mut self, other: &Item) {
+        if other.owner.is_some() {
+            self.owner.clone_from(&other.owner);
+        }
+        if other.become_method.is_some() {
+            self.become_method = other.become_method;
+        }
+        self.managed_block |= other.managed_block;
+        self.authorized_items.extend(&other.authorized_items);
+    }
+
+    pub fn set_authorized_items(
+        &mut self,
+        authorized_keys: AuthorizedKeys,
+        identities: &Identities,
+        today: Date,
+    ) {
+        let mut authorized_items = AuthorizedItems::default();
+
+        // keys that were referenced by fingerprint keep being referenced that way
+        let fingerprints: Vec<_> = self.authorized_items.fingerprints().cloned().collect();
+        let fingerprint_for_key = |key: &PublicKey| {
+            fingerprints
+                .iter()
+                .find(|fingerprint| key.fingerprint(fingerprint.alg()) == **fingerprint)
+                .cloned()
+        };
+
+        // keys excluded by the item need not be contained in `authorized_keys`,
+        // as the exclusions are kept and remove them from any identity
+        let exclusions = self
+            .authorized_items
+            .collect_authorized_keys(identities, &AuthorizedKeys::default(), today)
+            .exclusions;
+        let is_covered =
+            |key: &PublicKey| authorized_keys.contains(key) || exclusions.contains(key);
+
+        // only add identities if all of their keys are contained in `authorized_keys`,
+        // preferring the largest ones (i.e., teams over their members)
+        let mut contained_identities: Vec<_> = identities
+            .identities()
+            .filter_map(|identity| {
+                let keys = identities.keys_for_identity(&identity, today).ok()?;
+                let contained = keys.iter().any(|key| authorized_keys.contains(key))
+                    && keys.iter().all(is_covered);
+                contained.then_some((identity, keys))
+            })
+            .collect();
+        contained_identities.sort_by(|(a, a_keys), (b, b_keys)| {
+            b_keys
+                .len()
+                .cmp(&a_keys.len())
+                .then_with(|| a.identity().cmp(b.identity()))
+        });
+
+        let mut covered_keys = AuthorizedKeys::default();
+        for (identity, keys) in contained_identities {
+            if !covered_keys.is_superset(&keys) {
+                authorized_items.insert(AuthorizedItem::Identity(identity));
+                for key in keys {
+                    covered_keys.insert(key);
+                }
+            }
+        }
+
+        // all other keys are added on their own
+        for key in authorized_keys.difference(&covered_keys) {
+            if let Some(fingerprint) = fingerprint_for_key(&key) {
+                authorized_items.insert(AuthorizedItem::Fingerprint(fingerprint));
+            } else {
+                authorized_items.insert(AuthorizedItem::PublicKey(key));
+            }
+        }
+
+        // exclusions are kept, as they cannot be told from the keys
+        for item in self.authorized_items.exclusions() {
+            authorized_items.insert(item.clone());
+        }
+
+        self.authorized_items = authorized_items;
+    }
+}
+
+impl<'a> Target<'a> {
+    /// Returns the authorized keys inherited from groups.
+    pub fn inherited_keys(
+        &self,
+        identities: &Identities,
+        known_keys: &AuthorizedKeys,
+        today: Date,
+    ) -> Result<AuthorizedKeys> {
+        Ok(collect(&self.inherited_items, identities, known_keys, today)?.authorized_keys)
+    }
+}
+
+impl Host {
+    /// Sets the authorized keys of the host's own item with `user` and `path`,
+    /// adding such an item if there is none yet and `authorized_keys` is not empty.
+    pub fn set_authorized_keys(
+        &mut self,
+        user: &str,
+        path: &str,
+        authorized_keys: AuthorizedKeys,
+        identities: &Identities,
+        today: Date,
+    ) {
+        match self
+            .items
+            .iter_mut()
+            .find(|item| item.user == user && item.path == path)
+        {
+            Some(item) => item.set_authorized_items(authorized_keys, identities, today),
+            None if authorized_keys.is_empty() => {}
+            None => {
+                let mut item = Item {
+                    user: user.to_owned(),
+                    path: path.to_owned(),
+                    owner: None,
+                    become_method: None,
+                    managed_block: false,
+                    authorized_items: AuthorizedItems::default(),
+                };
+                item.set_authorized_items(authorized_keys, identities, today);
+                self.items.push(item);
+            }
+        }
+    }
+}
+
+impl ConnectionSettings {
+    /// Overrides these settings with those set in `other`.
+    fn merge(&mut self, other: &ConnectionSettings) {
+        if other.port.is_some() {
+            self.port = other.port;
+        }
+        if other.identity_file.is_some() {
+            self.identity_file.clone_from(&other.identity_file);
+        }
+        if other.proxy_jump.is_some() {
+            self.proxy_jump.clone_from(&other.proxy_jump);
+        }
+        if other.connect_timeout.is_some() {
+            self.connect_timeout = other.connect_timeout;
+        }
+        self.ssh_options.extend(other.ssh_options.clone());
+    }
+}
+
+/// Collects the authorized keys of `authorized_items`, failing on undefined identities
+/// and unresolved fingerprints.
+fn collect<'a>(
+    authorized_items: &'a AuthorizedItems,
+    identities: &Identities,
+    known_keys: &AuthorizedKeys,
+    today: Date,
+) -> Result<CollectAuthorizedKeys<'a>> {
+    let mut collect = authorized_items.collect_authorized_keys(identities, known_keys, today);
+
+    if !collect.identity_errors.is_empty() {
+        Err(collect.identity_errors.swap_remove(0).into())
+    } else if let Some(&fingerprint) = collect.unresolved_fingerprints.first() {
+        Err(Error::UnresolvedFingerprint(fingerprint.clone()))
+    } else {
+        Ok(collect)
+    }
+}
+
+/// A host with connection settings or groups, as written in the configuration file.
+#[derive(Deserialize)]
+struct HostWithSettings {
+    #[serde(default)]
+    groups: Vec<String>,
+    #[serde(flatten)]
+    settings: ConnectionSettings,
+    #[serde(default)]
+    items: Vec<Item>,
+}
+
+#[derive(Serialize)]
+struct HostWithSettingsRef<'a> {
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    groups: &'a [String],
+    #[serde(flatten)]
+    settings: &'a ConnectionSettings,
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    items: &'a [Item],
+}
+
+impl Serialize for Host {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if self.groups.is_empty() && self.settings == ConnectionSettings::default() {
+            self.items.serialize(serializer)
+        } else {
+            HostWithSettingsRef {
+                groups: &self.groups,
+                settings: &self.settings,
+                items: &self.items,
+            }
+            .serialize(serializer)
+        }
+    }
+}
+
+struct HostVisitor;
+
+impl<'de> Visitor<'de> for HostVisitor {
+    type Value = Host;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a list of items or a map with groups, connection settings and items")
+    }
+
+    fn visit_seq<A>(self, seq: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        Ok(Host {
+            groups: Vec::new(),
+            settings: ConnectionSettings::default(),
+            items: Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq))?,
+        })
+    }
+
+    fn visit_map<A>(self, map: A) -> std::result::Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let host = HostWithSettings::deserialize(de::value::MapAccessDeserializer::new(map))?;
+
+        Ok(Host {
+            groups: host.groups,
+            settings: host.settings,
+            items: host.items,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Host {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(HostVisitor)
+    }
+}
+
+/// Deserializes SSH options, accepting numbers and booleans as values as well.
+fn deserialize_ssh_options<'de, D>(
+    deserializer: D,
+) -> std::result::Result<BTreeMap<String, String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    BTreeMap::<String, serde_yaml::Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(name, value)| {
+            let value = match value {
+                serde_yaml::Value::String(value) => value,
+                serde_yaml::Value::Number(value) => value.to_string(),
+                serde_yaml::Value::Bool(value) => if value { "yes" } else { "no" }.to_owned(),
+                _ => {
+                    return Err(de::Error::custom(format!(
+                        "invalid value for SSH option {}",
+                        name
+                    )))
+                }
+            };
+            Ok((name, value))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_keys;
+
+    #[test]
+    fn host_without_settings() {
+        let yaml = "- user: test\n  path: /home/test/.ssh/authorized_keys\n  authorized_keys: []\n";
+        let host: Host = serde_yaml::from_str(yaml).unwrap();
+
+        assert_eq!(host.settings, ConnectionSettings::default());
+        assert_eq!(host.items.len(), 1);
+        assert_eq!(serde_yaml::to_string(&host).unwrap(), yaml);
+    }
+
+    #[test]
+    fn host_with_settings() {
+        let host: Host = serde_yaml::from_str(
+            "port: 2222
+identity_file: ~/.ssh/deploy
+proxy_jump: bastion.example.com
+connect_timeout: 10
+ssh_options:
+  ServerAliveInterval: 30
+  StrictHostKeyChecking: 'yes'
+  Compression: true
+items:
+- user: test
+  path: /home/test/.ssh/authorized_keys
+  authorized_keys: []
+",
+        )
+        .unwrap();
+
+        assert_eq!(host.settings.port, Some(2222));
+        assert_eq!(
+            host.settings.identity_file.as_deref(),
+            Some("~/.ssh/deploy")
+        );
+        assert_eq!(
+            host.settings.proxy_jump.as_deref(),
+            Some("bastion.example.com")
+        );
+        assert_eq!(host.settings.connect_timeout, Some(10));
+        assert_eq!(
+            host.settings.ssh_options,
+            BTreeMap::from([
+                ("Compression".to_owned(), "yes".to_owned()),
+                ("ServerAliveInterval".to_owned(), "30".to_owned()),
+                ("StrictHostKeyChecking".to_owned(), "yes".to_owned()),
+            ])
+        );
+        assert_eq!(host.items.len(), 1);
+
+        let yaml = serde_yaml::to_string(&host).unwrap();
+        let reparsed: Host = serde_yaml::from_str(&yaml).unwrap();
+        assert_eq!(reparsed.settings, host.settings);
+    }
+
+    #[test]
+    fn item_with_owner() {
+        let items: Vec<Item> = serde_yaml::from_str(
+            "- user: admin
+  path: /home/alice/.ssh/authorized_keys
+  owner: alice
+  authorized_keys: []
+- user: admin
+  path: /home/bob/.ssh/authorized_keys
+  owner: bob
+  become: doas
+  authorized_keys: []
+- user: admin
+  path: /home/admin/.ssh/authorized_keys
+  owner: admin
+  authorized_keys: []
+",
+        )
+        .unwrap();
+
+        assert_eq!(
+            items[0].become_owner(),
+            Some(Become {
+                method: BecomeMethod::Sudo,
+                user: "alice".to_owned()
+            })
+        );
+        assert_eq!(
+            items[1].become_owner(),
+            Some(Become {
+                method: BecomeMethod::Doas,
+                user: "bob".to_owned()
+            })
+        );
+        assert_eq!(items[2].become_owner(), None);
+    }
+
+    #[test]
+    fn hosts_in_groups() {
+        let config: Config = serde_yaml::from_str(&format!(
+            "groups:
+  web:
+    port: 2222
+    connect_timeout: 10
+    items:
+    - user: root
+      path: /root/.ssh/authorized_keys
+      authorized_keys: [{foo}]
+    - user: deploy
+      path: /home/deploy/.ssh/authorized_keys
+      owner: deploy
+      authorized_keys: [{foo}]
+  prod:
+    port: 22
+    items:
+    - user: root
+      path: /root/.ssh/authorized_keys
+      authorized_keys: [{bar}]
+hosts:
+  web1.example.com:
+    groups: [web, prod]
+    connect_timeout: 5
+    items:
+    - user: root
+      path: /root/.ssh/authorized_keys
+      authorized_keys: [{baz}]
+  web2.example.com:
+    groups: [web]
+  db.example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys: [{baz}]
+identities: {{}}
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            baz = test_keys::BAZ,
+        ))
+        .unwrap();
+
+        let targets = config.targets().unwrap();
+        let identities = Identities::default();
+        let known_keys = config.known_keys();
+        let keys = |keys: AuthorizedKeys| {
+            keys.sorted_keys()
+                .into_iter()
+                .map(|key| key.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            targets
+                .iter()
+                .map(|target| format!("{}:{}", target.hostname, target.item.user))
+                .collect::<Vec<_>>(),
+            [
+                "db.example.com:root",
+                "web1.example.com:root",
+                "web1.example.com:deploy",
+                "web2.example.com:root",
+                "web2.example.com:deploy",
+            ]
+        );
+
+        assert_eq!(targets[0].settings, ConnectionSettings::default());
+        assert_eq!(targets[1].settings.port, Some(22));
+        assert_eq!(targets[1].settings.connect_timeout, Some(5));
+        assert_eq!(targets[3].settings.port, Some(2222));
+        assert_eq!(
+            keys(
+                targets[1]
+                    .item
+                    .collect_authorized_keys(&identities, &known_keys, today())
+                    .unwrap()
+            ),
+            [test_keys::BAR, test_keys::BAZ, test_keys::FOO]
+        );
+        assert_eq!(
+            keys(
+                targets[1]
+                    .inherited_keys(&identities, &known_keys, today())
+                    .unwrap()
+            ),
+            [test_keys::BAR, test_keys::FOO]
+        );
+        assert_eq!(targets[4].item.owner.as_deref(), Some("deploy"));
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert!(yaml.contains("  web2.example.com:\n    groups:\n    - web\n"));
+        assert!(yaml.contains("  db.example.com:\n  - user: root\n"));
+    }
+
+    #[test]
+    fn undefined_group() {
+        let config: Config =
+            serde_yaml::from_str("hosts:\n  example.com:\n    groups: [web]\n").unwrap();
+
+        assert!(matches!(config.targets(), Err(Error::UndefinedGroup(group)) if group == "web"));
+    }
+
+    #[test]
+    fn set_authorized_items_prefers_teams() {
+        let identities: Identities = serde_yaml::from_str(&format!(
+            "foo: [{foo}]
+bar: [{bar}]
+baz: [{baz}]
+sre: ['@foo', '@bar']
+everyone: ['@sre', '@baz', {rsa}]
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            baz = test_keys::BAZ,
+            rsa = test_keys::RSA_2048,
+        ))
+        .unwrap();
+        let mut item: Item = serde_yaml::from_str(
+            "user: root\npath: /root/.ssh/authorized_keys\nauthorized_keys: []\n",
+        )
+        .unwrap();
+
+        let mut authorized_keys = AuthorizedKeys::default();
+        for key in [test_keys::FOO, test_keys::BAR, test_keys::BAZ] {
+            authorized_keys.insert(key.parse().unwrap());
+        }
+        item.set_authorized_items(authorized_keys.clone(), &identities, today());
+
+        let mut items: Vec<_> = serde_yaml::from_value::<Vec<String>>(
+            serde_yaml::to_value(&item.authorized_items).unwrap(),
+        )
+        .unwrap();
+        items.sort();
+        assert_eq!(items, ["@baz", "@sre"]);
+
+        authorized_keys.insert(test_keys::RSA_2048.parse().unwrap());
+        item.set_authorized_items(authorized_keys, &identities, today());
+        assert_eq!(
+            serde_yaml::to_string(&item.authorized_items).unwrap(),
+            "- '@everyone'\n"
+        );
+    }
+
+    #[test]
+    fn set_authorized_items_keeps_exclusions() {
+        let identities: Identities = serde_yaml::from_str(&format!(
+            "alice: [{foo}]\nsre: ['@alice', {bar}]\n",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+        ))
+        .unwrap();
+        let mut item: Item = serde_yaml::from_str(&format!(
+            "user: root\npath: /root/.ssh/authorized_keys\nauthorized_keys: ['@sre', '!{bar}']\n",
+            bar = test_keys::BAR,
+        ))
+        .unwrap();
+
+        // `@sre` is kept, as its excluded key need not be on the host
+        let mut authorized_keys = AuthorizedKeys::default();
+        for key in [test_keys::FOO, test_keys::BAZ] {
+            authorized_keys.insert(key.parse().unwrap());
+        }
+        item.set_authorized_items(authorized_keys, &identities, today());
+
+        let mut items: Vec<String> =
+            serde_yaml::from_value(serde_yaml::to_value(&item.authorized_items).unwrap()).unwrap();
+        items.sort();
+        assert_eq!(
+            items,
+            [
+                format!("!{}", test_keys::BAR),
+                "@sre".to_owned(),
+                test_keys::BAZ.to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_key() {
+        let error = serde_yaml::from_str::<Config>(
+            "hosts:
+  example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys:
+    - ssh-ed25519 AAAA!! typo
+",
+        )
+        .err()
+        .unwrap();
+
+        assert!(error.to_string().contains("key data is not valid base64"));
+    }
+
+    #[test]
+    fn invalid_ssh_option() {
+        assert!(
+            serde_yaml::from_str::<Host>("ssh_options:\n  SendEnv: [LANG]\nitems: []\n").is_err()
+        );
+    }
+
+    fn today() -> Date {
+        "2024-01-15".parse().unwrap()
+    }
+}
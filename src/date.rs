@@ -0,0 +1,220 @@
+//! Calendar dates, for the validity of keys.
+
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// A calendar date in UTC, written as `YYYY-MM-DD`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct Date {
+    /// Days since 1970-01-01
+    days: i64,
+}
+
+/// A number of days, written as e.g. `14d` or `2w`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Days(pub u32);
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid date {0:?}, expected YYYY-MM-DD")]
+pub struct ParseDateError(String);
+
+#[derive(thiserror::Error, Debug)]
+#[error("invalid number of days {0:?}, expected e.g. 14d or 2w")]
+pub struct ParseDaysError(String);
+
+impl Date {
+    /// Returns the current date in UTC.
+    pub fn today() -> Self {
+        let secs = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.as_secs());
+
+        Date {
+            days: (secs / 86400) as i64,
+        }
+    }
+
+    pub fn from_ymd(year: i64, month: u32, day: u32) -> Option<Self> {
+        if !(1..=12).contains(&month) || day == 0 || day > days_in_month(year, month) {
+            return None;
+        }
+
+        // see http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year.rem_euclid(400);
+        let month = i64::from(month);
+        let day_of_year =
+            (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+
+        Some(Date {
+            days: era * 146097 + day_of_era - 719468,
+        })
+    }
+
+    /// Returns the year, month and day of this date.
+    pub fn ymd(self) -> (i64, u32, u32) {
+        // see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+        let days = self.days + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days.rem_euclid(146097);
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+        (year, month, day)
+    }
+
+    /// Returns the date `days` days after this date.
+    pub fn add_days(self, days: Days) -> Self {
+        Date {
+            days: self.days + i64::from(days.0),
+        }
+    }
+
+    /// Returns this date in the format of OpenSSH's `expiry-time` option in UTC, i.e., `YYYYMMDDZ`.
+    pub fn to_expiry_time(self) -> String {
+        let (year, month, day) = self.ymd();
+        format!("{:04}{:02}{:02}Z", year, month, day)
+    }
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl FromStr for Date {
+    type Err = ParseDateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseDateError(s.to_owned());
+
+        let mut parts = s.splitn(3, '-');
+        let mut next = |len: usize| {
+            parts
+                .next()
+                .filter(|part| part.len() == len && part.bytes().all(|b| b.is_ascii_digit()))
+                .ok_or_else(error)
+        };
+        let year = next(4)?.parse().map_err(|_| error())?;
+        let month = next(2)?.parse().map_err(|_| error())?;
+        let day = next(2)?.parse().map_err(|_| error())?;
+
+        Date::from_ymd(year, month, day).ok_or_else(error)
+    }
+}
+
+impl fmt::Display for Date {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (year, month, day) = self.ymd();
+        write!(f, "{:04}-{:02}-{:02}", year, month, day)
+    }
+}
+
+impl Serialize for Date {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Date {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(DateVisitor)
+    }
+}
+
+struct DateVisitor;
+
+impl<'de> Visitor<'de> for DateVisitor {
+    type Value = Date;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a date as YYYY-MM-DD")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        FromStr::from_str(v).map_err(de::Error::custom)
+    }
+}
+
+impl FromStr for Days {
+    type Err = ParseDaysError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || ParseDaysError(s.to_owned());
+
+        let (number, factor) = if let Some(number) = s.strip_suffix('d') {
+            (number, 1)
+        } else if let Some(number) = s.strip_suffix('w') {
+            (number, 7)
+        } else {
+            (s, 1)
+        };
+        let number: u32 = number.parse().map_err(|_| error())?;
+
+        number.checked_mul(factor).map(Days).ok_or_else(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_date() {
+        let date: Date = "2024-02-29".parse().unwrap();
+        assert_eq!(date.ymd(), (2024, 2, 29));
+        assert_eq!(date.to_string(), "2024-02-29");
+        assert_eq!(date.to_expiry_time(), "20240229Z");
+
+        assert_eq!("1970-01-01".parse::<Date>().unwrap().days, 0);
+        assert_eq!("2000-03-01".parse::<Date>().unwrap().days, 11017);
+        assert!("2023-02-29".parse::<Date>().is_err());
+        assert!("2024-13-01".parse::<Date>().is_err());
+        assert!("2024-1-01".parse::<Date>().is_err());
+        assert!("2024-01-01T00:00".parse::<Date>().is_err());
+        assert!("+024-01-01".parse::<Date>().is_err());
+    }
+
+    #[test]
+    fn add_days() {
+        let date: Date = "2024-12-25".parse().unwrap();
+        assert_eq!(date.add_days(Days(7)).to_string(), "2025-01-01");
+        assert!(date < date.add_days(Days(1)));
+    }
+
+    #[test]
+    fn parse_days() {
+        assert_eq!("14d".parse::<Days>().unwrap(), Days(14));
+        assert_eq!("2w".parse::<Days>().unwrap(), Days(14));
+        assert_eq!("3".parse::<Days>().unwrap(), Days(3));
+        assert!("2m".parse::<Days>().is_err());
+        assert!("-1d".parse::<Days>().is_err());
+    }
+}
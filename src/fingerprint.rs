@@ -0,0 +1,186 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use md5::Md5;
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use sha2::{Digest, Sha256};
+use std::{fmt, str::FromStr};
+
+/// The hash algorithm used to compute a key fingerprint.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, clap::ValueEnum)]
+pub enum HashAlg {
+    Sha256,
+    Md5,
+}
+
+/// The fingerprint of a public key, as printed by `ssh-keygen -l`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub struct Fingerprint {
+    alg: HashAlg,
+    digest: Vec<u8>,
+}
+
+impl Fingerprint {
+    /// Computes the fingerprint of the encoded public key `data`.
+    pub fn compute(alg: HashAlg, data: &[u8]) -> Self {
+        let digest = match alg {
+            HashAlg::Sha256 => Sha256::digest(data).to_vec(),
+            HashAlg::Md5 => Md5::digest(data).to_vec(),
+        };
+
+        Fingerprint { alg, digest }
+    }
+
+    /// Returns the hash algorithm of this fingerprint.
+    pub fn alg(&self) -> HashAlg {
+        self.alg
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("failed to parse fingerprint")]
+pub struct ParseFingerprintError;
+
+impl FromStr for Fingerprint {
+    type Err = ParseFingerprintError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(digest) = s.strip_prefix("SHA256:") {
+            let digest = STANDARD_NO_PAD
+                .decode(digest)
+                .map_err(|_| ParseFingerprintError)?;
+
+            if digest.len() != 32 {
+                return Err(ParseFingerprintError);
+            }
+
+            Ok(Fingerprint {
+                alg: HashAlg::Sha256,
+                digest,
+            })
+        } else if let Some(digest) = s.strip_prefix("MD5:") {
+            let digest = digest
+                .split(':')
+                .map(|byte| match byte.len() {
+                    2 => u8::from_str_radix(byte, 16).map_err(|_| ParseFingerprintError),
+                    _ => Err(ParseFingerprintError),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            if digest.len() != 16 {
+                return Err(ParseFingerprintError);
+            }
+
+            Ok(Fingerprint {
+                alg: HashAlg::Md5,
+                digest,
+            })
+        } else {
+            Err(ParseFingerprintError)
+        }
+    }
+}
+
+impl fmt::Display for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.alg {
+            HashAlg::Sha256 => write!(f, "SHA256:{}", STANDARD_NO_PAD.encode(&self.digest)),
+            HashAlg::Md5 => {
+                f.write_str("MD5:")?;
+                for (i, byte) in self.digest.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(":")?;
+                    }
+                    write!(f, "{:02x}", byte)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Serialize for Fingerprint {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Fingerprint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(FingerprintVisitor)
+    }
+}
+
+struct FingerprintVisitor;
+
+impl<'de> Visitor<'de> for FingerprintVisitor {
+    type Value = Fingerprint;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a valid SHA256 or MD5 fingerprint")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        FromStr::from_str(v).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fingerprint() {
+        for fingerprint in [
+            "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo",
+            "MD5:3f:fe:d5:aa:5c:ea:6c:65:85:82:80:88:34:3b:56:90",
+        ] {
+            assert_eq!(
+                fingerprint.parse::<Fingerprint>().unwrap().to_string(),
+                fingerprint
+            );
+        }
+
+        assert_eq!(
+            "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo"
+                .parse::<Fingerprint>()
+                .unwrap()
+                .alg(),
+            HashAlg::Sha256
+        );
+        assert_eq!(
+            "MD5:3f:fe:d5:aa:5c:ea:6c:65:85:82:80:88:34:3b:56:90"
+                .parse::<Fingerprint>()
+                .unwrap()
+                .alg(),
+            HashAlg::Md5
+        );
+    }
+
+    #[test]
+    fn parse_invalid_fingerprint() {
+        assert!("".parse::<Fingerprint>().is_err());
+        assert!("lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo"
+            .parse::<Fingerprint>()
+            .is_err());
+        assert!("SHA256:lbaPt/4kw5".parse::<Fingerprint>().is_err());
+        assert!("SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo="
+            .parse::<Fingerprint>()
+            .is_err());
+        assert!("MD5:3f:fe:d5:aa".parse::<Fingerprint>().is_err());
+        assert!("MD5:3f:fe:d5:aa:5c:ea:6c:65:85:82:80:88:34:3b:56:zz"
+            .parse::<Fingerprint>()
+            .is_err());
+        assert!("ssh-ed25519 AAAA".parse::<Fingerprint>().is_err());
+    }
+}
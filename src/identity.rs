@@ -1,7 +1,7 @@
-use crate::{authorized_keys::AuthorizedKeys, public_key::PublicKey};
+use crate::{authorized_keys::AuthorizedKeys, date::Date, public_key::PublicKey};
 use serde::{
     de::{self, Visitor},
-    Deserialize, Deserializer, Serialize,
+    Deserialize, Deserializer, Serialize, Serializer,
 };
 use std::{collections::HashMap, fmt, str::FromStr};
 
@@ -13,6 +13,14 @@ pub struct Identity(String);
 #[error("failed to parse identity")]
 pub struct ParseIdentityError;
 
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("undefined identity {0}")]
+    Undefined(Identity),
+    #[error("identities include each other: {}", join_cycle(.0))]
+    Cycle(Vec<Identity>),
+}
+
 impl Identity {
     pub fn new(identity: String) -> Self {
         Identity(identity)
@@ -39,31 +47,222 @@ impl fmt::Display for Identity {
     }
 }
 
+/// A member of an identity: one of its keys, or another identity whose keys it includes.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Member {
+    Identity(Identity),
+    PublicKey(PublicKey),
+    TemporaryKey(TemporaryKey),
+}
+
+/// A key of an identity that is only valid from the start of `not_before`
+/// until the start of `expires`, in UTC.
+#[derive(Deserialize, Serialize, Clone, Debug, Eq, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TemporaryKey {
+    pub key: PublicKey,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub not_before: Option<Date>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires: Option<Date>,
+}
+
+impl TemporaryKey {
+    /// Returns `true` if the key is valid on `date`.
+    pub fn is_valid(&self, date: Date) -> bool {
+        self.not_before.is_none_or(|not_before| not_before <= date)
+            && self.expires.is_none_or(|expires| date < expires)
+    }
+}
+
 #[derive(Deserialize, Serialize, Clone, Default)]
 #[serde(transparent)]
-pub struct Identities(HashMap<String, AuthorizedKeys>);
+pub struct Identities(HashMap<String, Vec<Member>>);
 
 impl Identities {
-    /// Returns the identity for a key.
+    /// Returns the identity a key is listed for directly, i.e., not via another identity.
     pub fn identity_for_key(&self, key: &PublicKey) -> Option<Identity> {
         self.0
             .iter()
-            .find(|(_, keys)| keys.contains(key))
+            .find(|(_, members)| {
+                members
+                    .iter()
+                    .any(|member| member.public_key() == Some(key))
+            })
             .map(|(identity, _)| Identity::new(identity.clone()))
     }
 
-    /// Returns the keys for an identity.
-    pub fn keys_for_identity(&self, identity: &Identity) -> Option<AuthorizedKeys> {
-        self.0.get(identity.identity()).cloned()
+    /// Returns the date a key expires on, if it is only listed with an expiry date.
+    /// If it is listed several times, the latest expiry date is returned.
+    pub fn expiry(&self, key: &PublicKey) -> Option<Date> {
+        let mut expiry = None;
+
+        for member in self.0.values().flatten() {
+            match member {
+                Member::PublicKey(public_key) if public_key == key => return None,
+                // keys without an expiry date never expire
+                Member::TemporaryKey(temporary_key) if temporary_key.key == *key => {
+                    expiry = expiry.max(Some(temporary_key.expires?));
+                }
+                _ => {}
+            }
+        }
+
+        expiry
+    }
+
+    /// An iterator visiting all temporary keys with their identities, in arbitrary order.
+    pub fn temporary_keys(&self) -> impl Iterator<Item = (Identity, &TemporaryKey)> {
+        self.0.iter().flat_map(|(identity, members)| {
+            members.iter().filter_map(move |member| match member {
+                Member::TemporaryKey(temporary_key) => {
+                    Some((Identity::new(identity.clone()), temporary_key))
+                }
+                _ => None,
+            })
+        })
+    }
+
+    /// Returns the keys for an identity, including the keys of the identities it includes.
+    /// Temporary keys are only included if they are valid `today`.
+    pub fn keys_for_identity(
+        &self,
+        identity: &Identity,
+        today: Date,
+    ) -> Result<AuthorizedKeys, Error> {
+        let mut keys = AuthorizedKeys::default();
+        self.collect_keys(identity, today, &mut Vec::new(), &mut keys)?;
+
+        Ok(keys)
+    }
+
+    /// Collects the keys of `identity` valid on `date` into `keys`,
+    /// where `path` are the identities that include `identity`.
+    fn collect_keys(
+        &self,
+        identity: &Identity,
+        date: Date,
+        path: &mut Vec<Identity>,
+        keys: &mut AuthorizedKeys,
+    ) -> Result<(), Error> {
+        if let Some(start) = path.iter().position(|included| included == identity) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(identity.clone());
+            return Err(Error::Cycle(cycle));
+        }
+
+        let members = self
+            .0
+            .get(identity.identity())
+            .ok_or_else(|| Error::Undefined(identity.clone()))?;
+
+        path.push(identity.clone());
+        for member in members {
+            match member {
+                Member::PublicKey(key) => keys.insert(key.clone()),
+                Member::TemporaryKey(temporary_key) => {
+                    if temporary_key.is_valid(date) {
+                        keys.insert(temporary_key.key.clone());
+                    }
+                }
+                Member::Identity(identity) => self.collect_keys(identity, date, path, keys)?,
+            }
+        }
+        path.pop();
+
+        Ok(())
+    }
+
+    /// An iterator visiting all identities in arbitrary order.
+    pub fn identities(&self) -> impl Iterator<Item = Identity> + '_ {
+        self.0
+            .keys()
+            .map(|identity| Identity::new(identity.clone()))
+    }
+
+    /// An iterator visiting the keys listed directly for all identities,
+    /// including temporary keys that are not valid today.
+    pub fn keys(&self) -> impl Iterator<Item = &PublicKey> {
+        self.0.values().flatten().filter_map(Member::public_key)
     }
 
     /// Set the public keys for an identity.
     #[cfg(test)]
     pub fn set_keys_for_identity(&mut self, keys: AuthorizedKeys, identity: &Identity) {
-        self.0.insert(identity.identity().to_owned(), keys);
+        let members = keys.into_iter().map(Member::PublicKey).collect();
+        self.0.insert(identity.identity().to_owned(), members);
     }
 }
 
+impl Member {
+    fn public_key(&self) -> Option<&PublicKey> {
+        match self {
+            Member::PublicKey(key) => Some(key),
+            Member::TemporaryKey(temporary_key) => Some(&temporary_key.key),
+            Member::Identity(_) => None,
+        }
+    }
+}
+
+impl Serialize for Member {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match self {
+            Member::Identity(identity) => serializer.serialize_str(&identity.to_string()),
+            Member::PublicKey(key) => key.serialize(serializer),
+            Member::TemporaryKey(temporary_key) => temporary_key.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Member {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_any(MemberVisitor)
+    }
+}
+
+struct MemberVisitor;
+
+impl<'de> Visitor<'de> for MemberVisitor {
+    type Value = Member;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an @identity, a public key or a map with a key and its validity")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        if v.starts_with('@') {
+            v.parse().map(Member::Identity).map_err(de::Error::custom)
+        } else {
+            v.parse().map(Member::PublicKey).map_err(de::Error::custom)
+        }
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: de::MapAccess<'de>,
+    {
+        TemporaryKey::deserialize(de::value::MapAccessDeserializer::new(map))
+            .map(Member::TemporaryKey)
+    }
+}
+
+fn join_cycle(cycle: &[Identity]) -> String {
+    cycle
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" -> ")
+}
+
 impl<'de> Deserialize<'de> for Identity {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -93,6 +292,7 @@ impl<'de> Visitor<'de> for IdentityVisitor {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_keys;
 
     #[test]
     fn parse_identity() {
@@ -117,15 +317,15 @@ mod tests {
         let identities = test_identities();
 
         assert_eq!(
-            identities.identity_for_key(&"ssh-rsa foo".parse().unwrap()),
+            identities.identity_for_key(&test_keys::FOO.parse().unwrap()),
             Some("@foo".parse().unwrap())
         );
         assert_eq!(
-            identities.identity_for_key(&"ssh-rsa bar".parse().unwrap()),
+            identities.identity_for_key(&test_keys::BAR.parse().unwrap()),
             Some("@bar".parse().unwrap())
         );
         assert_eq!(
-            identities.identity_for_key(&"ssh-rsa baz".parse().unwrap()),
+            identities.identity_for_key(&test_keys::BAZ.parse().unwrap()),
             None
         );
     }
@@ -134,26 +334,166 @@ mod tests {
     fn keys_for_identity() {
         let identities = test_identities();
         assert_eq!(
-            identities.keys_for_identity(&"@foo".parse().unwrap()),
-            Some(authorized_keys("ssh-rsa foo"))
+            identities
+                .keys_for_identity(&"@foo".parse().unwrap(), today())
+                .unwrap(),
+            authorized_keys(test_keys::FOO)
+        );
+        assert_eq!(
+            identities
+                .keys_for_identity(&"@bar".parse().unwrap(), today())
+                .unwrap(),
+            authorized_keys(test_keys::BAR)
+        );
+        assert!(matches!(
+            identities.keys_for_identity(&"@baz".parse().unwrap(), today()),
+            Err(Error::Undefined(identity)) if identity.identity() == "baz"
+        ));
+    }
+
+    #[test]
+    fn nested_identities() {
+        let identities: Identities = serde_yaml::from_str(&format!(
+            "foo: [{foo}]
+bar: [{bar}]
+sre: ['@foo', '@bar']
+ops: ['@sre', '@foo', {baz}]
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            baz = test_keys::BAZ,
+        ))
+        .unwrap();
+
+        let mut keys = authorized_keys(test_keys::FOO);
+        keys.insert(test_keys::BAR.parse().unwrap());
+        assert_eq!(
+            identities
+                .keys_for_identity(&"@sre".parse().unwrap(), today())
+                .unwrap(),
+            keys
+        );
+        keys.insert(test_keys::BAZ.parse().unwrap());
+        assert_eq!(
+            identities
+                .keys_for_identity(&"@ops".parse().unwrap(), today())
+                .unwrap(),
+            keys
+        );
+
+        // keys are attributed to the identities they are listed for directly
+        assert_eq!(
+            identities.identity_for_key(&test_keys::BAR.parse().unwrap()),
+            Some("@bar".parse().unwrap())
         );
+        assert_eq!(identities.keys().count(), 3);
+
+        let yaml = serde_yaml::to_string(&identities).unwrap();
+        assert!(yaml.contains("- '@foo'\n- '@bar'\n"));
+    }
+
+    #[test]
+    fn cyclic_identities() {
+        let identities: Identities = serde_yaml::from_str(&format!(
+            "foo: [{foo}, '@bar']
+bar: ['@baz']
+baz: ['@bar']
+",
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+
+        let error = identities
+            .keys_for_identity(&"@foo".parse().unwrap(), today())
+            .unwrap_err();
         assert_eq!(
-            identities.keys_for_identity(&"@bar".parse().unwrap()),
-            Some(authorized_keys("ssh-rsa bar"))
+            error.to_string(),
+            "identities include each other: @bar -> @baz -> @bar"
         );
+    }
+
+    #[test]
+    fn temporary_keys() {
+        let identities: Identities = serde_yaml::from_str(&format!(
+            "foo:
+- {foo}
+- key: {bar}
+  expires: 2000-01-01
+- key: {baz}
+  not_before: 2000-01-01
+  expires: 2999-01-01
+bar:
+- key: {foo}
+  expires: 2000-01-01
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            baz = test_keys::BAZ,
+        ))
+        .unwrap();
+
+        let keys = identities
+            .keys_for_identity(&"@foo".parse().unwrap(), today())
+            .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&test_keys::BAZ.parse().unwrap()));
         assert!(identities
-            .keys_for_identity(&"@baz".parse().unwrap())
-            .is_none());
+            .keys_for_identity(&"@bar".parse().unwrap(), today())
+            .unwrap()
+            .is_empty());
+
+        // validity depends on the date passed in, not on the clock
+        let keys = identities
+            .keys_for_identity(&"@foo".parse().unwrap(), "1999-12-31".parse().unwrap())
+            .unwrap();
+        assert_eq!(keys.len(), 2);
+        assert!(keys.contains(&test_keys::BAR.parse().unwrap()));
+
+        // keys listed without an expiry date elsewhere never expire
+        assert_eq!(identities.expiry(&test_keys::FOO.parse().unwrap()), None);
+        assert_eq!(
+            identities.expiry(&test_keys::BAR.parse().unwrap()),
+            Some("2000-01-01".parse().unwrap())
+        );
+
+        let temporary_key = TemporaryKey {
+            key: test_keys::BAZ.parse().unwrap(),
+            not_before: Some("2024-01-01".parse().unwrap()),
+            expires: Some("2024-02-01".parse().unwrap()),
+        };
+        assert!(!temporary_key.is_valid("2023-12-31".parse().unwrap()));
+        assert!(temporary_key.is_valid("2024-01-01".parse().unwrap()));
+        assert!(temporary_key.is_valid("2024-01-31".parse().unwrap()));
+        assert!(!temporary_key.is_valid("2024-02-01".parse().unwrap()));
+
+        assert!(serde_yaml::from_str::<Identities>(&format!(
+            "foo:\n- key: {}\n  expires: 2024-02-30\n",
+            test_keys::FOO
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_member() {
+        let error = serde_yaml::from_str::<Identities>("alice:\n- ssh-ed25519 AAAA!! alice\n")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("key data is not valid base64"));
+
+        let error = serde_yaml::from_str::<Identities>("alice:\n- key: ssh-foo AAAA alice\n")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("unknown key type ssh-foo"));
+    }
+
+    fn today() -> Date {
+        "2024-01-15".parse().unwrap()
     }
 
     fn test_identities() -> Identities {
         let mut identities = Identities::default();
-        identities
-            .0
-            .insert(String::from("foo"), authorized_keys("ssh-rsa foo"));
-        identities
-            .0
-            .insert(String::from("bar"), authorized_keys("ssh-rsa bar"));
+        identities.set_keys_for_identity(authorized_keys(test_keys::FOO), &"@foo".parse().unwrap());
+        identities.set_keys_for_identity(authorized_keys(test_keys::BAR), &"@bar".parse().unwrap());
         identities
     }
 
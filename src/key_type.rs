@@ -0,0 +1,179 @@
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::{fmt, str::FromStr};
+
+/// A public key algorithm supported in `authorized_keys` files.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub enum KeyType {
+    Rsa,
+    Dsa,
+    EcdsaNistp256,
+    EcdsaNistp384,
+    EcdsaNistp521,
+    Ed25519,
+    SkEcdsaNistp256,
+    SkEd25519,
+    RsaCert,
+    DsaCert,
+    EcdsaNistp256Cert,
+    EcdsaNistp384Cert,
+    EcdsaNistp521Cert,
+    Ed25519Cert,
+    SkEcdsaNistp256Cert,
+    SkEd25519Cert,
+}
+
+impl KeyType {
+    const ALL: [KeyType; 16] = [
+        KeyType::Rsa,
+        KeyType::Dsa,
+        KeyType::EcdsaNistp256,
+        KeyType::EcdsaNistp384,
+        KeyType::EcdsaNistp521,
+        KeyType::Ed25519,
+        KeyType::SkEcdsaNistp256,
+        KeyType::SkEd25519,
+        KeyType::RsaCert,
+        KeyType::DsaCert,
+        KeyType::EcdsaNistp256Cert,
+        KeyType::EcdsaNistp384Cert,
+        KeyType::EcdsaNistp521Cert,
+        KeyType::Ed25519Cert,
+        KeyType::SkEcdsaNistp256Cert,
+        KeyType::SkEd25519Cert,
+    ];
+
+    /// Returns the name of this key type as used by OpenSSH.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyType::Rsa => "ssh-rsa",
+            KeyType::Dsa => "ssh-dss",
+            KeyType::EcdsaNistp256 => "ecdsa-sha2-nistp256",
+            KeyType::EcdsaNistp384 => "ecdsa-sha2-nistp384",
+            KeyType::EcdsaNistp521 => "ecdsa-sha2-nistp521",
+            KeyType::Ed25519 => "ssh-ed25519",
+            KeyType::SkEcdsaNistp256 => "sk-ecdsa-sha2-nistp256@openssh.com",
+            KeyType::SkEd25519 => "sk-ssh-ed25519@openssh.com",
+            KeyType::RsaCert => "ssh-rsa-cert-v01@openssh.com",
+            KeyType::DsaCert => "ssh-dss-cert-v01@openssh.com",
+            KeyType::EcdsaNistp256Cert => "ecdsa-sha2-nistp256-cert-v01@openssh.com",
+            KeyType::EcdsaNistp384Cert => "ecdsa-sha2-nistp384-cert-v01@openssh.com",
+            KeyType::EcdsaNistp521Cert => "ecdsa-sha2-nistp521-cert-v01@openssh.com",
+            KeyType::Ed25519Cert => "ssh-ed25519-cert-v01@openssh.com",
+            KeyType::SkEcdsaNistp256Cert => "sk-ecdsa-sha2-nistp256-cert-v01@openssh.com",
+            KeyType::SkEd25519Cert => "sk-ssh-ed25519-cert-v01@openssh.com",
+        }
+    }
+
+    /// Returns `true` if keys of this type are RSA keys or certificates.
+    pub fn is_rsa(&self) -> bool {
+        matches!(self, KeyType::Rsa | KeyType::RsaCert)
+    }
+
+    /// Returns `true` if keys of this type are backed by a FIDO security key.
+    pub fn is_security_key(&self) -> bool {
+        matches!(
+            self,
+            KeyType::SkEcdsaNistp256
+                | KeyType::SkEd25519
+                | KeyType::SkEcdsaNistp256Cert
+                | KeyType::SkEd25519Cert
+        )
+    }
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("unknown key type {0}")]
+pub struct ParseKeyTypeError(String);
+
+impl FromStr for KeyType {
+    type Err = ParseKeyTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        KeyType::ALL
+            .into_iter()
+            .find(|key_type| key_type.name() == s)
+            .ok_or_else(|| ParseKeyTypeError(s.to_owned()))
+    }
+}
+
+impl fmt::Display for KeyType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Serialize for KeyType {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(KeyTypeVisitor)
+    }
+}
+
+struct KeyTypeVisitor;
+
+impl<'de> Visitor<'de> for KeyTypeVisitor {
+    type Value = KeyType;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a valid key type")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        FromStr::from_str(v).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_key_type() {
+        assert_eq!("ssh-rsa".parse::<KeyType>().unwrap(), KeyType::Rsa);
+        assert_eq!("ssh-ed25519".parse::<KeyType>().unwrap(), KeyType::Ed25519);
+        assert_eq!(
+            "sk-ssh-ed25519@openssh.com".parse::<KeyType>().unwrap(),
+            KeyType::SkEd25519
+        );
+        assert_eq!(
+            "ecdsa-sha2-nistp384-cert-v01@openssh.com"
+                .parse::<KeyType>()
+                .unwrap(),
+            KeyType::EcdsaNistp384Cert
+        );
+        assert!("ssh-rsaa".parse::<KeyType>().is_err());
+        assert!("no-pty".parse::<KeyType>().is_err());
+    }
+
+    #[test]
+    fn security_key_types() {
+        assert!(KeyType::SkEd25519.is_security_key());
+        assert!(KeyType::SkEcdsaNistp256Cert.is_security_key());
+        assert!(!KeyType::Ed25519.is_security_key());
+        assert!(!KeyType::EcdsaNistp256.is_security_key());
+    }
+
+    #[test]
+    fn key_type_round_trip() {
+        for key_type in KeyType::ALL {
+            assert_eq!(key_type.name().parse::<KeyType>().unwrap(), key_type);
+        }
+    }
+}
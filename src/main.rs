@@ -1,21 +1,47 @@
+mod audit;
 mod authorized_items;
 mod authorized_keys;
+mod authorized_keys_file;
+mod backup;
+mod config;
+mod date;
+mod fingerprint;
 mod identity;
+mod key_type;
+#[cfg(feature = "native-ssh")]
+mod native_ssh;
+mod parallel;
+mod policy;
 mod public_key;
+mod report;
+mod revocation;
+mod select;
+mod shell;
 mod ssh;
+#[cfg(test)]
+mod test_keys;
+mod transport;
+mod wire;
 
 use crate::{
+    audit::{AuditResult, AuditStatus, Format, KeyFinding},
+    authorized_items::{AuthorizedItem, Exclusions, ParseAuthorizedItemError},
     authorized_keys::AuthorizedKeys,
+    authorized_keys_file::{AuthorizedKeysFile, Scope},
+    config::{Config, Target},
+    date::{Date, Days},
+    fingerprint::HashAlg,
     identity::{Identities, Identity},
-    ssh::SshConnection,
+    policy::Policy,
+    public_key::PublicKey,
+    report::Report,
+    revocation::Revocations,
+    select::Selector,
+    transport::{Connector, DefaultConnector, RemoteFile, Transport},
 };
-use authorized_items::{AuthorizedItem, AuthorizedItems};
 use clap::{Parser, Subcommand};
-use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
-    fs::File,
-    io::{BufReader, Cursor},
+    collections::HashMap, fs::File, io::BufReader, num::NonZeroUsize, path::Path, process::ExitCode,
 };
 
 type Result<T> = anyhow::Result<T>;
@@ -27,30 +53,54 @@ struct Cli {
     /// Path to the YAML configuration file
     #[arg(long, short)]
     config: String,
+    /// Hash algorithm used to display key fingerprints
+    #[arg(long, value_enum, default_value_t = HashAlg::Sha256)]
+    fingerprint_hash: HashAlg,
+    /// Number of targets to process concurrently
+    #[arg(long, short, default_value_t = NonZeroUsize::MIN)]
+    jobs: NonZeroUsize,
+    #[command(flatten)]
+    selector: Selector,
 }
 
 #[derive(Subcommand)]
 enum Command {
     /// Push the authorized keys defined in the configuration file
-    Push,
+    Push {
+        /// Add OpenSSH's expiry-time option to keys with an expiry date
+        #[arg(long)]
+        expiry_time: bool,
+    },
     /// Pull the authorized keys into the configuration file
     Pull,
     /// Audit the authorized keys stored on remote servers
-    Audit,
-}
-
-#[derive(Deserialize, Serialize)]
-struct Config {
-    hosts: HashMap<String, Vec<Item>>,
-    identities: Option<Identities>,
-}
-
-#[derive(Deserialize, Serialize)]
-struct Item {
-    user: String,
-    path: String,
-    #[serde(rename = "authorized_keys")]
-    authorized_items: AuthorizedItems,
+    Audit {
+        /// Output format of the results
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+    /// Show the changes push would make to the remote servers, without writing anything
+    Plan,
+    /// Restore the most recent backup of the authorized keys on a remote server
+    Rollback {
+        /// Host as defined in the configuration file
+        host: String,
+    },
+    /// List the keys of identities that expire soon or have expired
+    Expiring {
+        /// Only list keys expiring within this many days, e.g., 14d or 2w
+        #[arg(long, default_value = "14d")]
+        within: Days,
+    },
+    /// Find the hosts, users and paths a key or identity can access
+    Where {
+        /// A public key, a fingerprint or an identity, e.g., @alice
+        #[arg(value_parser = parse_query)]
+        key: AuthorizedItem,
+        /// Also read the authorized keys files, to find where the keys are actually authorized
+        #[arg(long)]
+        live: bool,
+    },
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -61,113 +111,1170 @@ enum Error {
     WriteConfig { path: String, source: anyhow::Error },
     #[error("failed to read authorized keys")]
     ReadAuthorizedKeys(#[source] anyhow::Error),
+    #[error("authorized keys file {path} does not exist (via {transport})")]
+    MissingAuthorizedKeys { path: String, transport: String },
     #[error("failed to write authorized keys")]
     WriteAuthorizedKeys(#[source] anyhow::Error),
-    #[error("audit failed for {path} (via {user}@{hostname})")]
-    AuditFailed {
+    #[error("failed to restore authorized keys")]
+    RestoreAuthorizedKeys(#[source] anyhow::Error),
+    #[error("no backup found for {path} (via {transport})")]
+    NoBackup { path: String, transport: String },
+    #[error("no item matches host {0}")]
+    NoMatchingItem(String),
+    #[error("no item matches the selected hosts, users and paths")]
+    NoSelectedItem,
+    #[error("{0} does not support selecting hosts, users or paths")]
+    SelectorUnsupported(&'static str),
+    #[error("policy violated for {path} (via {user}@{hostname})")]
+    PolicyViolated {
         hostname: String,
         user: String,
         path: String,
     },
-    #[error("undefined identity {0}")]
-    UndefinedIdentity(Identity),
+    #[error("failed to read {0} authorized keys files")]
+    IncompleteLiveState(usize),
 }
 
-fn main() -> Result<()> {
+fn main() -> Result<ExitCode> {
     let cli: Cli = Cli::parse();
+    let connector = DefaultConnector::default();
+    let jobs = cli.jobs.get();
+    let selector = &cli.selector;
 
     match cli.command {
-        Command::Push => push_config(cli.config)?,
-        Command::Pull => pull_config(cli.config)?,
-        Command::Audit => audit_config(cli.config)?,
+        Command::Push { expiry_time } => push_config(
+            cli.config,
+            &connector,
+            selector,
+            cli.fingerprint_hash,
+            jobs,
+            expiry_time,
+        )?,
+        Command::Pull => pull_config(cli.config, &connector, selector, cli.fingerprint_hash, jobs)?,
+        Command::Audit { format } => {
+            let code = audit_config(
+                cli.config,
+                &connector,
+                selector,
+                cli.fingerprint_hash,
+                jobs,
+                format,
+            )?;
+            return Ok(ExitCode::from(code));
+        }
+        Command::Plan => plan_config(cli.config, &connector, selector, cli.fingerprint_hash, jobs)?,
+        Command::Rollback { host } => rollback_config(cli.config, &connector, selector, host)?,
+        Command::Expiring { within } => {
+            expiring_config(cli.config, selector, cli.fingerprint_hash, within)?
+        }
+        Command::Where { key, live } => where_config(
+            cli.config,
+            &connector,
+            selector,
+            cli.fingerprint_hash,
+            jobs,
+            &key,
+            live,
+        )?,
     }
 
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-fn push_config(path: String) -> Result<()> {
+fn push_config(
+    path: String,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    expiry_time: bool,
+) -> Result<()> {
     let config = read_config(path)?;
+    push(
+        &config,
+        connector,
+        selector,
+        hash_alg,
+        jobs,
+        expiry_time,
+        Date::today(),
+    )
+}
 
-    let identities = config.identities.unwrap_or_default();
+fn push(
+    config: &Config,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    expiry_time: bool,
+    today: Date,
+) -> Result<()> {
+    let identities = config.identities.clone().unwrap_or_default();
+    let known_keys = config.known_keys();
+    let policy = config.policy.clone().unwrap_or_default();
+    let revocations = Revocations::load(config.revoked.as_ref(), &config.dir)?;
+
+    // check all targets before pushing anything
+    let mut targets = Vec::new();
+    for target in select(config, selector)? {
+        let item = &target.item;
+        let mut authorized_keys = item.collect_authorized_keys(&identities, &known_keys, today)?;
+        if expiry_time {
+            authorized_keys = with_expiry_times(authorized_keys, &identities);
+        }
 
-    for (hostname, items) in config.hosts {
-        for item in items {
-            let connection = SshConnection::new(hostname.clone(), item.user.clone());
-            let authorized_keys = item.collect_authorized_keys(&identities)?;
-            write_authorized_keys(&connection, item.path, authorized_keys)?;
+        let mut report = Report::default();
+        remove_revoked_keys(&mut report, &revocations, &mut authorized_keys, hash_alg);
+        let compliant = check_policy(
+            &mut report,
+            &policy,
+            item.file_owner(),
+            &authorized_keys,
+            hash_alg,
+        );
+        report.print();
+
+        if !compliant {
+            return Err(Error::PolicyViolated {
+                hostname: target.hostname.to_owned(),
+                user: item.user.clone(),
+                path: item.path.clone(),
+            }
+            .into());
         }
+
+        targets.push((target, authorized_keys));
     }
 
+    let (targets, authorized_keys): (Vec<_>, Vec<_>) = targets.into_iter().unzip();
+    let results = map_files(connector, jobs, &targets, |report, i, transport, file| {
+        write_authorized_keys(
+            report,
+            transport,
+            &targets[i].item.path,
+            targets[i].item.scope(),
+            &authorized_keys[i],
+            file.map(|file| file.text),
+        )
+    });
+    report::finish(results)?;
+
     Ok(())
 }
 
-fn pull_config(path: String) -> Result<()> {
+/// Adds OpenSSH's `expiry-time` option to keys that expire,
+/// so that `sshd` rejects them even if they are not removed in time.
+fn with_expiry_times(authorized_keys: AuthorizedKeys, identities: &Identities) -> AuthorizedKeys {
+    let mut keys = AuthorizedKeys::default();
+
+    for key in authorized_keys {
+        match identities.expiry(&key) {
+            Some(expiry) => {
+                keys.insert(key.with_option("expiry-time", Some(&expiry.to_expiry_time())))
+            }
+            None => keys.insert(key),
+        }
+    }
+
+    keys
+}
+
+fn pull_config(
+    path: String,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+) -> Result<()> {
     let mut config = read_config(path.clone())?;
+    pull(
+        &mut config,
+        connector,
+        selector,
+        hash_alg,
+        jobs,
+        Date::today(),
+    )?;
+    write_config(path, &config)?;
 
+    Ok(())
+}
+
+fn pull(
+    config: &mut Config,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    today: Date,
+) -> Result<()> {
     let identities = config.identities.clone().unwrap_or_default();
+    let known_keys = config.known_keys();
+
+    let targets = select(config, selector)?;
+    let inherited_keys = targets
+        .iter()
+        .map(|target| target.inherited_keys(&identities, &known_keys, today))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let exclusions = targets
+        .iter()
+        .map(|target| target.item.exclusions(&identities, &known_keys, today))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let revocations = Revocations::load(config.revoked.as_ref(), &config.dir)?;
+
+    let results = map_files(connector, jobs, &targets, |report, i, transport, file| {
+        let item = &targets[i].item;
+        let file = read_authorized_keys(report, transport, &item.path, item.scope(), file)?;
+        let mut authorized_keys = file.authorized_keys(item.scope())?;
+
+        // revoked, excluded and expired keys are not authorized again, so that they are removed by the next push
+        authorized_keys.retain(|key| {
+            if let Some(revocation) = revocations.find(key) {
+                report.eprintln(format!(
+                    "found revoked key {} ({}), skipping",
+                    describe_key(key, hash_alg),
+                    revocation
+                ));
+                false
+            } else if exclusions[i].contains(key) {
+                report.eprintln(format!(
+                    "found excluded key {}, skipping",
+                    describe_key(key, hash_alg)
+                ));
+                false
+            } else if let Some(expires) = identities.expiry(key).filter(|expires| *expires <= today)
+            {
+                report.eprintln(format!(
+                    "found expired key {} (expired {}), skipping",
+                    describe_key(key, hash_alg),
+                    expires
+                ));
+                false
+            } else {
+                true
+            }
+        });
 
-    for (hostname, items) in config.hosts.iter_mut() {
-        for item in items {
-            let connection = SshConnection::new(hostname.clone(), item.user.clone());
-            let authorized_keys = read_authorized_keys(&connection, item.path.clone())?;
-            item.set_authorized_items(authorized_keys, &identities);
+        // keys inherited from groups stay in the groups, so that they are not expanded into every host
+        for key in inherited_keys[i].difference(&authorized_keys).sorted_keys() {
+            report.eprintln(format!(
+                "found inherited key {} missing, skipping (remove it from the group instead)",
+                describe_key(key, hash_alg)
+            ));
         }
+        Ok(authorized_keys.difference(&inherited_keys[i]))
+    });
+    let authorized_keys = report::finish(results)?;
+    // all keys known before the pull, including those of unselected items
+    let mut keys = known_keys.clone();
+    for key in authorized_keys.iter().flat_map(AuthorizedKeys::iter) {
+        keys.insert(key.clone());
     }
 
-    write_config(path, &config)?;
+    let targets: Vec<_> = targets
+        .into_iter()
+        .map(|target| (target.hostname.to_owned(), target.item))
+        .collect();
+    for ((hostname, item), authorized_keys) in targets.into_iter().zip(authorized_keys) {
+        if let Some(host) = config.hosts.get_mut(&hostname) {
+            host.set_authorized_keys(&item.user, &item.path, authorized_keys, &identities, today);
+        }
+    }
+    // keys are only kept as fingerprints while their full key is listed elsewhere,
+    // e.g., not if the pull removed it from the only item listing it
+    config.expand_unresolved_fingerprints(&keys);
 
     Ok(())
 }
 
-fn audit_config(path: String) -> Result<()> {
+fn audit_config(
+    path: String,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    format: Format,
+) -> Result<u8> {
+    // keep stdout free of progress output for machine-readable formats
+    let config = match format {
+        Format::Text => read_config(path)?,
+        Format::Json | Format::Junit => load_config(path)?,
+    };
+    let results = audit(&config, connector, selector, hash_alg, jobs, Date::today())?
+        .into_iter()
+        .map(|(report, result)| {
+            if format == Format::Text {
+                report.print();
+            } else {
+                report.print_to_stderr();
+            }
+            result
+        })
+        .collect::<Vec<_>>();
+
+    match format {
+        Format::Text => print!("{}", audit::summary(&results)),
+        Format::Json => println!("{}", audit::to_json(&results)),
+        Format::Junit => print!("{}", audit::to_junit(&results)),
+    }
+
+    Ok(audit::exit_code(&results))
+}
+
+/// Audits all targets, continuing past targets that fail.
+/// Returns the report and result of each target, in order.
+fn audit(
+    config: &Config,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    today: Date,
+) -> Result<Vec<(Report, AuditResult)>> {
+    let identities = config.identities.clone().unwrap_or_default();
+    let known_keys = config.known_keys();
+    let policy = config.policy.clone().unwrap_or_default();
+    let revocations = Revocations::load(config.revoked.as_ref(), &config.dir)?;
+
+    // errors in the configuration file are not specific to a target, so they abort the audit
+    let targets = select(config, selector)?;
+    let expected_keys = targets
+        .iter()
+        .map(|target| {
+            let item = &target.item;
+            // revoked keys are not expected, even if an identity still lists them
+            let mut authorized_keys =
+                item.collect_authorized_keys(&identities, &known_keys, today)?;
+            authorized_keys.retain(|key| revocations.find(key).is_none());
+            Ok((
+                authorized_keys,
+                item.exclusions(&identities, &known_keys, today)?,
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let results = map_files(connector, jobs, &targets, |report, i, transport, file| {
+        let (expected_keys, exclusions) = &expected_keys[i];
+        Ok(audit_target(
+            report,
+            &targets[i],
+            transport,
+            file,
+            expected_keys,
+            exclusions,
+            &identities,
+            &policy,
+            &revocations,
+            hash_alg,
+            today,
+        ))
+    });
+
+    let results = targets
+        .iter()
+        .zip(results)
+        .map(|(target, (mut report, result))| {
+            let result = result.unwrap_or_else(|e| {
+                let item = &target.item;
+                let mut result = AuditResult::new(target.hostname, &item.user, &item.path);
+                result.add_status(AuditStatus::Unreachable);
+                result.errors.push(format!("{:#}", e));
+
+                report.eprintln(format!(
+                    "failed to audit {} (via {}@{}): {:#}",
+                    item.path, item.user, target.hostname, e
+                ));
+                result
+            });
+            (report, result)
+        })
+        .collect();
+
+    Ok(results)
+}
+
+/// Audits a target whose file has been read, reporting all findings.
+/// A missing file is treated like an empty one.
+#[allow(clippy::too_many_arguments)]
+fn audit_target(
+    report: &mut Report,
+    target: &Target,
+    transport: &dyn Transport,
+    file: Option<RemoteFile>,
+    expected_keys: &AuthorizedKeys,
+    exclusions: &Exclusions,
+    identities: &Identities,
+    policy: &Policy,
+    revocations: &Revocations,
+    hash_alg: HashAlg,
+    today: Date,
+) -> AuditResult {
+    let item = &target.item;
+    let mut result = AuditResult::new(target.hostname, &item.user, &item.path);
+
+    report.println(format!("Auditing {} (via {})...", item.path, transport));
+
+    let contents = file.map(|file| file.text).unwrap_or_default();
+    let file = AuthorizedKeysFile::parse(&contents);
+
+    let (authorized_keys, invalid_lines) = match (
+        file.authorized_keys(item.scope()),
+        file.invalid_lines(item.scope()),
+    ) {
+        (Ok(authorized_keys), Ok(invalid_lines)) => (authorized_keys, invalid_lines),
+        (Err(e), _) | (_, Err(e)) => {
+            let error = format!("failed to parse {}: {}", item.path, e);
+            report.eprintln(error.clone());
+            result.errors.push(error);
+            result.add_status(AuditStatus::ParseError);
+            return result;
+        }
+    };
+
+    let key_finding = |key: &PublicKey| KeyFinding {
+        fingerprint: key.fingerprint(hash_alg).to_string(),
+        comment: key.comment().map(str::to_owned),
+        identity: identities
+            .identity_for_key(key)
+            .map(|identity| identity.identity().to_owned()),
+        expires: identities.expiry(key),
+        revocation: revocations.find(key),
+    };
+    // revoked keys are found anywhere in the file, even outside of the managed block
+    // and if they are defined in the configuration file
+    let all_keys = file.authorized_keys(Scope::File).unwrap_or_default();
+    result.revoked_keys = all_keys
+        .sorted_keys()
+        .into_iter()
+        .map(key_finding)
+        .filter(|finding| finding.revocation.is_some())
+        .collect();
+
+    // excluded keys are a separate, more severe finding than unknown keys,
+    // and expired keys are told apart from keys that were never authorized
+    for key in authorized_keys.difference(expected_keys).sorted_keys() {
+        let finding = key_finding(key);
+
+        if finding.revocation.is_some() {
+            continue;
+        } else if exclusions.contains(key) {
+            result.excluded_keys.push(finding);
+        } else if finding.expires.is_some_and(|expires| expires <= today) {
+            result.expired_keys.push(finding);
+        } else {
+            result.unknown_keys.push(finding);
+        }
+    }
+    result.missing_keys = expected_keys
+        .difference(&authorized_keys)
+        .sorted_keys()
+        .into_iter()
+        .map(key_finding)
+        .collect();
+
+    result
+        .errors
+        .extend(invalid_lines.iter().map(|invalid_line| {
+            format!(
+                "found invalid line {}: {}",
+                invalid_line.number, invalid_line.error
+            )
+        }));
+    let parsed = result.errors.is_empty();
+
+    result.errors.extend(policy_violations(
+        policy,
+        item.file_owner(),
+        &authorized_keys,
+        hash_alg,
+    ));
+    result.errors.extend(option_differences(
+        &authorized_keys,
+        expected_keys,
+        identities,
+        hash_alg,
+    ));
+    // all errors other than invalid lines are drift
+    let conforming = result.errors.len() == invalid_lines.len();
+
+    for key in &result.revoked_keys {
+        report.eprintln(format!("found revoked key {}", key));
+    }
+    for key in &result.excluded_keys {
+        report.eprintln(format!("found excluded key {}", key));
+    }
+    for error in &result.errors {
+        report.eprintln(error.clone());
+    }
+    for key in &result.expired_keys {
+        report.eprintln(format!("found expired key {}", key));
+    }
+    for key in &result.unknown_keys {
+        report.eprintln(format!("found unknown key {}", key));
+    }
+    for key in &result.missing_keys {
+        report.eprintln(format!("found missing key {}", key));
+    }
+
+    if !result.revoked_keys.is_empty() {
+        result.add_status(AuditStatus::RevokedKey);
+    }
+    if !result.excluded_keys.is_empty() {
+        result.add_status(AuditStatus::ExcludedKey);
+    }
+    if !parsed {
+        result.add_status(AuditStatus::ParseError);
+    }
+    if !conforming
+        || !result.expired_keys.is_empty()
+        || !result.unknown_keys.is_empty()
+        || !result.missing_keys.is_empty()
+    {
+        result.add_status(AuditStatus::Drifted);
+    }
+    if result.has_status(AuditStatus::Ok) {
+        report.println("OK");
+    }
+
+    result
+}
+
+/// Checks `authorized_keys` against `policy`, reporting all violations.
+/// Returns `true` if no key violates the policy.
+fn check_policy(
+    report: &mut Report,
+    policy: &Policy,
+    user: &str,
+    authorized_keys: &AuthorizedKeys,
+    hash_alg: HashAlg,
+) -> bool {
+    let violations = policy_violations(policy, user, authorized_keys, hash_alg);
+
+    for violation in &violations {
+        report.eprintln(violation.clone());
+    }
+
+    violations.is_empty()
+}
+
+/// Removes revoked keys from `authorized_keys`, e.g., keys still listed by an identity,
+/// reporting each of them.
+fn remove_revoked_keys(
+    report: &mut Report,
+    revocations: &Revocations,
+    authorized_keys: &mut AuthorizedKeys,
+    hash_alg: HashAlg,
+) {
+    for key in authorized_keys.sorted_keys() {
+        if let Some(revocation) = revocations.find(key) {
+            report.eprintln(format!(
+                "found revoked key {} ({}), skipping",
+                describe_key(key, hash_alg),
+                revocation
+            ));
+        }
+    }
+
+    authorized_keys.retain(|key| revocations.find(key).is_none());
+}
+
+/// Returns a finding for each violation of `policy` by `authorized_keys`.
+fn policy_violations(
+    policy: &Policy,
+    user: &str,
+    authorized_keys: &AuthorizedKeys,
+    hash_alg: HashAlg,
+) -> Vec<String> {
+    let mut violations = Vec::new();
+
+    for key in authorized_keys.sorted_keys() {
+        for violation in policy.check(user, key) {
+            violations.push(format!(
+                "found key violating policy {}: {}",
+                describe_key(key, hash_alg),
+                violation
+            ));
+        }
+    }
+
+    violations
+}
+
+/// Returns a finding for each key whose options differ from those defined in the configuration file.
+/// An `expiry-time` option with the expiry date of the key is expected, as pushed with `--expiry-time`.
+fn option_differences(
+    authorized_keys: &AuthorizedKeys,
+    expected_keys: &AuthorizedKeys,
+    identities: &Identities,
+    hash_alg: HashAlg,
+) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    for key in authorized_keys.sorted_keys() {
+        let Some(expected_key) = expected_keys.get(key) else {
+            continue;
+        };
+        let expected_key = match identities.expiry(key) {
+            Some(expiry) if key.option("expiry-time").is_some() => expected_key
+                .clone()
+                .with_option("expiry-time", Some(&expiry.to_expiry_time())),
+            _ => expected_key.clone(),
+        };
+
+        if key.options() != expected_key.options() {
+            differences.push(format!(
+                "found key {} with options `{}`, expected `{}`",
+                describe_key(key, hash_alg),
+                describe_options(key),
+                describe_options(&expected_key)
+            ));
+        }
+    }
+
+    differences
+}
+
+/// Returns the options of `key` as written in front of it, e.g., `no-pty,from="10.0.0.0/8"`.
+fn describe_options(key: &PublicKey) -> String {
+    key.options()
+        .iter()
+        .map(|option| option.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Returns a short description of `key` for CLI output, i.e., its fingerprint and comment.
+fn describe_key(key: &PublicKey, hash_alg: HashAlg) -> String {
+    match key.comment() {
+        Some(comment) => format!("{} ({})", key.fingerprint(hash_alg), comment),
+        None => key.fingerprint(hash_alg).to_string(),
+    }
+}
+
+/// Parses the key, fingerprint or identity to look up with `where`.
+fn parse_query(s: &str) -> std::result::Result<AuthorizedItem, ParseAuthorizedItemError> {
+    match s.parse()? {
+        AuthorizedItem::Exclusion(_) => Err(ParseAuthorizedItemError),
+        item => Ok(item),
+    }
+}
+
+/// A key matching the query of `where`, which can access a target.
+#[derive(Clone, Debug)]
+struct Access {
+    hostname: String,
+    user: String,
+    path: String,
+    key: PublicKey,
+    /// The items of the target authorizing the key, empty if it is only found in the file
+    grants: Vec<AuthorizedItem>,
+    /// Whether the key is found in the file, if it has been read
+    deployed: Option<bool>,
+}
+
+fn where_config(
+    path: String,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    query: &AuthorizedItem,
+    live: bool,
+) -> Result<()> {
     let config = read_config(path)?;
 
-    let identities = config.identities.unwrap_or_default();
+    let mut failures = 0;
+    let mut accesses = Vec::new();
+    for (report, result) in locate(
+        &config,
+        connector,
+        selector,
+        jobs,
+        query,
+        live,
+        Date::today(),
+    )? {
+        report.print();
+        match result {
+            Ok(target_accesses) => accesses.extend(target_accesses),
+            Err(_) => failures += 1,
+        }
+    }
 
-    for (hostname, items) in config.hosts {
-        for item in items {
-            let connection = SshConnection::new(hostname.clone(), item.user.clone());
+    if accesses.is_empty() {
+        println!("no selected item authorizes {}", query);
+    } else {
+        print!("{}", accesses_table(&accesses, hash_alg, live));
+    }
 
-            println!("Auditing {} (via {})...", item.path, connection);
+    if failures > 0 {
+        return Err(Error::IncompleteLiveState(failures).into());
+    }
 
-            let authorized_keys = read_authorized_keys(&connection, item.path.clone())?;
-            let known_keys = item.collect_authorized_keys(&identities)?;
-            let unknown_keys = authorized_keys.difference(&known_keys);
-            let missing_keys = known_keys.difference(&authorized_keys);
+    Ok(())
+}
 
-            if !unknown_keys.is_empty() || !missing_keys.is_empty() {
-                for unknown_key in unknown_keys {
-                    eprintln!("found unknown key {}", unknown_key);
-                }
+/// Finds the targets authorizing keys matching `query`, according to the configuration file,
+/// and with `live`, also according to the authorized keys files.
+/// Returns the report and accesses of each target, in order.
+fn locate(
+    config: &Config,
+    connector: &dyn Connector,
+    selector: &Selector,
+    jobs: usize,
+    query: &AuthorizedItem,
+    live: bool,
+    today: Date,
+) -> Result<Vec<(Report, Result<Vec<Access>>)>> {
+    let identities = config.identities.clone().unwrap_or_default();
+    let known_keys = config.known_keys();
+
+    let targets = select(config, selector)?;
+    let access = |target: &Target, key: &PublicKey, grants: Vec<AuthorizedItem>| Access {
+        hostname: target.hostname.to_owned(),
+        user: target.item.user.clone(),
+        path: target.item.path.clone(),
+        key: key.clone(),
+        grants,
+        deployed: None,
+    };
+    let configured = targets
+        .iter()
+        .map(|target| {
+            let item = &target.item;
+            let authorized_keys = item.collect_authorized_keys(&identities, &known_keys, today)?;
+
+            Ok(authorized_keys
+                .sorted_keys()
+                .into_iter()
+                .filter(|key| query.authorizes(key, &identities, today))
+                .map(|key| {
+                    let grants = item.authorized_items.authorizing(key, &identities, today);
+                    access(target, key, grants.into_iter().cloned().collect())
+                })
+                .collect::<Vec<_>>())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if !live {
+        return Ok(configured
+            .into_iter()
+            .map(|accesses| (Report::default(), Ok(accesses)))
+            .collect());
+    }
+
+    let results = map_files(connector, jobs, &targets, |_, i, _, file| {
+        let contents = file.map(|file| file.text).unwrap_or_default();
+        // any key in the file grants access, even outside of the managed block
+        let deployed_keys = AuthorizedKeysFile::parse(&contents).authorized_keys(Scope::File)?;
 
-                for missing_key in missing_keys {
-                    eprintln!("found missing key {}", missing_key);
-                }
+        let mut accesses = configured[i].clone();
+        for access in &mut accesses {
+            access.deployed = Some(deployed_keys.contains(&access.key));
+        }
+        for key in deployed_keys.sorted_keys() {
+            if query.authorizes(key, &identities, today)
+                && !configured[i].iter().any(|a| a.key == *key)
+            {
+                accesses.push(Access {
+                    deployed: Some(true),
+                    ..access(&targets[i], key, Vec::new())
+                });
+            }
+        }
 
-                return Err(Error::AuditFailed {
-                    hostname,
-                    user: item.user,
-                    path: item.path,
-                })?;
+        Ok(accesses)
+    });
+
+    Ok(targets
+        .iter()
+        .zip(results)
+        .map(|(target, (mut report, result))| {
+            if let Err(e) = &result {
+                let item = &target.item;
+                report.eprintln(format!(
+                    "failed to read {} (via {}@{}): {:#}",
+                    item.path, item.user, target.hostname, e
+                ));
+            }
+            (report, result)
+        })
+        .collect())
+}
+
+/// Returns a table of `accesses`, with a column telling whether the keys are found in the files if `live`.
+fn accesses_table(accesses: &[Access], hash_alg: HashAlg, live: bool) -> String {
+    let mut rows =
+        vec![["HOST", "USER", "PATH", "KEY", "AUTHORIZED BY", "FOUND"].map(str::to_owned)];
+    for access in accesses {
+        let grants = access
+            .grants
+            .iter()
+            .map(|grant| match grant {
+                AuthorizedItem::PublicKey(_) => "key".to_owned(),
+                grant => grant.to_string(),
+            })
+            .collect::<Vec<_>>();
+
+        rows.push([
+            access.hostname.clone(),
+            access.user.clone(),
+            access.path.clone(),
+            describe_key(&access.key, hash_alg),
+            if grants.is_empty() {
+                "-".to_owned()
             } else {
-                println!("OK");
+                grants.join(", ")
+            },
+            match access.deployed {
+                Some(true) => "yes",
+                Some(false) => "no",
+                None => "-",
             }
+            .to_owned(),
+        ]);
+    }
+
+    let columns = if live { 6 } else { 5 };
+    let widths: Vec<_> = (0..columns)
+        .map(|column| rows.iter().map(|row| row[column].len()).max().unwrap_or(0))
+        .collect();
+
+    let mut table = String::new();
+    for row in &rows {
+        let cells: Vec<_> = (0..columns)
+            .map(|column| format!("{:<width$}", row[column], width = widths[column]))
+            .collect();
+        table.push_str(cells.join("  ").trim_end());
+        table.push('\n');
+    }
+
+    table
+}
+
+fn plan_config(
+    path: String,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+) -> Result<()> {
+    let config = read_config(path)?;
+    plan(&config, connector, selector, hash_alg, jobs, Date::today())?;
+
+    Ok(())
+}
+
+fn plan(
+    config: &Config,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    jobs: usize,
+    today: Date,
+) -> Result<Vec<Vec<String>>> {
+    let identities = config.identities.clone().unwrap_or_default();
+    let known_keys = config.known_keys();
+    let policy = config.policy.clone().unwrap_or_default();
+    let revocations = Revocations::load(config.revoked.as_ref(), &config.dir)?;
+
+    let targets = select(config, selector)?;
+    let results = map_files(connector, jobs, &targets, |report, i, transport, file| {
+        plan_target(
+            report,
+            &targets[i],
+            transport,
+            file,
+            &identities,
+            &known_keys,
+            &policy,
+            &revocations,
+            hash_alg,
+            today,
+        )
+    });
+
+    report::finish(results)
+}
+
+/// Prints the changes push would make to the file of `target`, and returns them line by line.
+#[allow(clippy::too_many_arguments)]
+fn plan_target(
+    report: &mut Report,
+    target: &Target,
+    transport: &dyn Transport,
+    file: Option<RemoteFile>,
+    identities: &Identities,
+    known_keys: &AuthorizedKeys,
+    policy: &Policy,
+    revocations: &Revocations,
+    hash_alg: HashAlg,
+    today: Date,
+) -> Result<Vec<String>> {
+    let item = &target.item;
+    let mut authorized_keys = item.collect_authorized_keys(identities, known_keys, today)?;
+
+    remove_revoked_keys(report, revocations, &mut authorized_keys, hash_alg);
+    let compliant = check_policy(
+        report,
+        policy,
+        item.file_owner(),
+        &authorized_keys,
+        hash_alg,
+    );
+
+    // apply the same update as push, so that the plan matches the file it would write
+    let contents = file.map(|file| file.text).unwrap_or_default();
+    let mut authorized_keys_file = AuthorizedKeysFile::parse(&contents);
+    let current_keys = authorized_keys_file.authorized_keys(item.scope())?;
+    authorized_keys_file.set_authorized_keys(item.scope(), &authorized_keys)?;
+    let planned_keys = authorized_keys_file.authorized_keys(item.scope())?;
+
+    let mut lines = Vec::new();
+    if !compliant {
+        lines.push("push refused: policy violated".to_owned());
+    } else if authorized_keys_file.to_string() == contents {
+        lines.push("no changes".to_owned());
+    }
+
+    let added_keys = planned_keys.difference(&current_keys);
+    let removed_keys = current_keys.difference(&planned_keys);
+    for (sign, keys) in [('+', added_keys), ('-', removed_keys)] {
+        for key in keys.sorted_keys() {
+            match identities.identity_for_key(key) {
+                Some(identity) => lines.push(format!(
+                    "{} {} {}",
+                    sign,
+                    describe_key(key, hash_alg),
+                    identity
+                )),
+                None => lines.push(format!("{} {}", sign, describe_key(key, hash_alg))),
+            }
+        }
+    }
+    for key in planned_keys.sorted_keys() {
+        let Some(current_key) = current_keys.get(key) else {
+            continue;
+        };
+        if current_key.options() != key.options() {
+            lines.push(format!(
+                "~ {} options `{}` -> `{}`",
+                describe_key(key, hash_alg),
+                describe_options(current_key),
+                describe_options(key)
+            ));
+        }
+    }
+
+    report.println(format!("{} (via {}):", item.path, transport));
+    for line in &lines {
+        report.println(format!("  {}", line));
+    }
+
+    Ok(lines)
+}
+
+fn rollback_config(
+    path: String,
+    connector: &dyn Connector,
+    selector: &Selector,
+    hostname: String,
+) -> Result<()> {
+    let config = read_config(path)?;
+
+    let targets: Vec<_> = selector
+        .select(&config)?
+        .into_iter()
+        .filter(|target| target.hostname == hostname)
+        .collect();
+
+    if targets.is_empty() {
+        return Err(Error::NoMatchingItem(hostname).into());
+    }
+
+    for target in targets {
+        let transport = connect(connector, &target)?;
+        for warning in transport.warnings() {
+            eprintln!("{}", warning);
         }
+        restore_authorized_keys(&*transport, &target.item.path)?;
+    }
+
+    Ok(())
+}
+
+/// Returns the targets of `config` selected by `selector`.
+/// Fails if selectors are given but no target matches, e.g., because of a typo.
+fn select<'a>(config: &'a Config, selector: &Selector) -> Result<Vec<Target<'a>>> {
+    let targets = selector.select(config)?;
+
+    if targets.is_empty() && !selector.is_empty() {
+        return Err(Error::NoSelectedItem.into());
+    }
+
+    Ok(targets)
+}
+
+fn expiring_config(
+    path: String,
+    selector: &Selector,
+    hash_alg: HashAlg,
+    within: Days,
+) -> Result<()> {
+    // identities are not tied to hosts, so selecting targets would silently list all keys anyway
+    if !selector.is_empty() {
+        return Err(Error::SelectorUnsupported("expiring").into());
+    }
+
+    let config = read_config(path)?;
+    let identities = config.identities.unwrap_or_default();
+    let today = Date::today();
+
+    let keys = expiring(&identities, today, within);
+    if keys.is_empty() {
+        println!("no keys expire within {} days", within.0);
+    }
+
+    for (expiry, identity, key) in keys {
+        let expired = if expiry <= today { " (expired)" } else { "" };
+        println!(
+            "{}  {}  {}{}",
+            expiry,
+            identity,
+            describe_key(key, hash_alg),
+            expired
+        );
     }
 
     Ok(())
 }
 
+/// Returns the temporary keys of all identities expiring `within` days after `today`
+/// or earlier, ordered by their expiry dates.
+fn expiring(
+    identities: &Identities,
+    today: Date,
+    within: Days,
+) -> Vec<(Date, Identity, &PublicKey)> {
+    let mut keys: Vec<_> = identities
+        .temporary_keys()
+        .filter_map(|(identity, temporary_key)| {
+            let expiry = temporary_key.expires?;
+            (expiry <= today.add_days(within)).then_some((expiry, identity, &temporary_key.key))
+        })
+        .collect();
+    keys.sort_by(|(a, a_identity, _), (b, b_identity, _)| {
+        a.cmp(b)
+            .then_with(|| a_identity.identity().cmp(b_identity.identity()))
+    });
+
+    keys
+}
+
+/// Connects to the host of `target` as the user of its item.
+fn connect(connector: &dyn Connector, target: &Target) -> Result<Box<dyn Transport>> {
+    connector.connect(
+        target.hostname,
+        &target.settings,
+        &target.item.user,
+        target.item.become_owner().as_ref(),
+    )
+}
+
+/// Calls `f` with the index of each target, a transport to its host and the current contents of its file.
+///
+/// Targets on the same host accessing their files as the same user share a connection,
+/// and all of their files are read in a single round trip.
+/// The results are returned in the order of `targets`.
+fn map_files<R, F>(
+    connector: &dyn Connector,
+    jobs: usize,
+    targets: &[Target],
+    f: F,
+) -> Vec<(Report, Result<R>)>
+where
+    R: Send,
+    F: Fn(&mut Report, usize, &dyn Transport, Option<RemoteFile>) -> Result<R> + Sync,
+{
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_indices = HashMap::new();
+    for (i, target) in targets.iter().enumerate() {
+        let key = (
+            target.hostname,
+            &target.item.user,
+            target.item.become_owner(),
+        );
+        let group = *group_indices.entry(key).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[group].push(i);
+    }
+
+    let results = parallel::map(jobs, &groups, |group| {
+        let files = connect(connector, &targets[group[0]]).and_then(|transport| {
+            let paths: Vec<_> = group
+                .iter()
+                .map(|&i| targets[i].item.path.as_str())
+                .collect();
+            let files = transport
+                .read_files(&paths)
+                .map_err(Error::ReadAuthorizedKeys)?;
+            Ok((transport, files))
+        });
+
+        match files {
+            Ok((transport, files)) => group
+                .iter()
+                .zip(files)
+                .enumerate()
+                .map(|(n, (&i, file))| {
+                    let mut report = Report::default();
+                    // warnings about the shared connection are reported once, with its first target
+                    if n == 0 {
+                        for warning in transport.warnings() {
+                            report.eprintln(warning);
+                        }
+                    }
+                    let result = file
+                        .map_err(|e| Error::ReadAuthorizedKeys(e).into())
+                        .and_then(|file| f(&mut report, i, &*transport, file));
+                    (i, (report, result))
+                })
+                .collect(),
+            // all targets of the group fail alike
+            Err(e) => group
+                .iter()
+                .map(|&i| (i, (Report::default(), Err(anyhow::anyhow!("{:#}", e)))))
+                .collect::<Vec<_>>(),
+        }
+    });
+
+    let mut results: Vec<_> = results.into_iter().flatten().collect();
+    results.sort_by_key(|(i, _)| *i);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
 fn read_config(path: String) -> Result<Config> {
     println!("reading configuration file {}... ", path);
+    let config = load_config(path)?;
+    println!("OK");
+    Ok(config)
+}
 
+fn load_config(path: String) -> Result<Config> {
     let file = File::open(&path)?;
-    let config = serde_yaml::from_reader(BufReader::new(file)).map_err(|e| Error::ReadConfig {
-        path,
-        source: e.into(),
-    })?;
+    let mut config: Config =
+        serde_yaml::from_reader(BufReader::new(file)).map_err(|e| Error::ReadConfig {
+            path: path.clone(),
+            source: e.into(),
+        })?;
+    config.dir = Path::new(&path)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .to_owned();
 
-    println!("OK");
     Ok(config)
 }
 
@@ -188,88 +1295,1185 @@ fn write_config(path: String, config: &Config) -> Result<()> {
     Ok(())
 }
 
-fn read_authorized_keys(connection: &SshConnection, path: String) -> Result<AuthorizedKeys> {
-    println!(
+/// Parses the authorized keys read from `path`, which must exist.
+fn read_authorized_keys(
+    report: &mut Report,
+    transport: &dyn Transport,
+    path: &str,
+    scope: Scope,
+    file: Option<RemoteFile>,
+) -> Result<AuthorizedKeysFile> {
+    report.println(format!(
         "reading authorized keys from {} (via {})...",
-        path, connection
-    );
+        path, transport
+    ));
 
-    let contents = connection
-        .read_file(path.clone())
-        .map_err(|e| Error::ReadAuthorizedKeys(e.into()))?;
-    let cursor = Cursor::new(contents);
-    let authorized_keys = AuthorizedKeys::from_reader(cursor)?;
+    let contents = file.ok_or_else(|| Error::MissingAuthorizedKeys {
+        path: path.to_owned(),
+        transport: transport.to_string(),
+    })?;
+    let file = AuthorizedKeysFile::parse(&contents.text);
 
-    println!(
+    for invalid_line in file.invalid_lines(scope)? {
+        report.eprintln(format!(
+            "skipping invalid line {} of {} (via {}): {}",
+            invalid_line.number, path, transport, invalid_line.error
+        ));
+    }
+
+    report.println(format!(
         "successfully read {} authorized keys from {} (via {})",
-        authorized_keys.len(),
+        file.authorized_keys(scope)?.len(),
         path,
-        connection
-    );
+        transport
+    ));
 
-    Ok(authorized_keys)
+    Ok(file)
 }
 
+/// Writes `authorized_keys` to `path`, whose current contents are `contents`.
 fn write_authorized_keys(
-    connection: &SshConnection,
-    path: String,
-    authorized_keys: AuthorizedKeys,
+    report: &mut Report,
+    transport: &dyn Transport,
+    path: &str,
+    scope: Scope,
+    authorized_keys: &AuthorizedKeys,
+    contents: Option<String>,
 ) -> Result<()> {
-    println!(
+    report.println(format!(
         "writing authorized keys to {} (via {})...",
-        path, connection
-    );
+        path, transport
+    ));
 
-    let mut text = String::new();
-    authorized_keys.to_writer(&mut text)?;
+    // update the existing file, so that comments and the order of keys are preserved
+    let contents = contents.unwrap_or_default();
+    let mut file = AuthorizedKeysFile::parse(&contents);
+    file.set_authorized_keys(scope, authorized_keys)?;
 
-    connection
-        .write_file(path.clone(), text)
-        .map_err(|e| Error::WriteAuthorizedKeys(e.into()))?;
+    let text = file.to_string();
+    if text == contents {
+        report.println(format!(
+            "authorized keys in {} (via {}) are up to date",
+            path, transport
+        ));
+        return Ok(());
+    }
 
-    println!(
+    transport
+        .write_file(path, &text)
+        .map_err(Error::WriteAuthorizedKeys)?;
+    if let Err(e) = backup::prune_backups(transport, path, backup::KEPT_BACKUPS) {
+        report.eprintln(format!(
+            "failed to remove outdated backups of {} (via {}): {:#}",
+            path, transport, e
+        ));
+    }
+
+    report.println(format!(
         "successfully wrote {} authorized keys to {} (via {})",
         authorized_keys.len(),
         path,
-        connection
+        transport
+    ));
+
+    Ok(())
+}
+
+fn restore_authorized_keys(transport: &dyn Transport, path: &str) -> Result<()> {
+    println!(
+        "restoring authorized keys of {} (via {})...",
+        path, transport
+    );
+
+    let backup = backup::find_latest_backup(transport, path)
+        .map_err(Error::RestoreAuthorizedKeys)?
+        .ok_or_else(|| Error::NoBackup {
+            path: path.to_owned(),
+            transport: transport.to_string(),
+        })?;
+
+    transport
+        .restore_backup(path, &backup)
+        .map_err(Error::RestoreAuthorizedKeys)?;
+
+    println!(
+        "successfully restored {} from {} (via {})",
+        path, backup, transport
     );
 
     Ok(())
 }
 
-impl Item {
-    pub fn collect_authorized_keys(&self, identities: &Identities) -> Result<AuthorizedKeys> {
-        let collect = self.authorized_items.collect_authorized_keys(identities);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Item, test_keys, transport::MemoryConnector};
 
-        if let Some(&identity) = collect.undefined_identities.first() {
-            return Err(Error::UndefinedIdentity(identity.clone()).into());
-        } else {
-            return Ok(collect.authorized_keys);
+    const PATH: &str = "/home/deploy/.ssh/authorized_keys";
+
+    fn config() -> Config {
+        serde_yaml::from_str(&format!(
+            "hosts:
+  web.example.com:
+  - user: deploy
+    path: {PATH}
+    authorized_keys:
+    - '@alice'
+    - {bar}
+identities:
+  alice:
+  - {foo}
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+        ))
+        .unwrap()
+    }
+
+    /// The date the tests run on, so that temporary keys do not depend on the clock.
+    fn today() -> Date {
+        "2024-01-15".parse().unwrap()
+    }
+
+    /// Returns the SHA256 fingerprint of `key`.
+    fn fingerprint(key: &str) -> String {
+        key.parse::<PublicKey>()
+            .unwrap()
+            .fingerprint(HashAlg::Sha256)
+            .to_string()
+    }
+
+    /// Returns the keys authorized by `item` of `config`, in order.
+    fn keys(config: &Config, item: &Item) -> Vec<String> {
+        let identities = config.identities.clone().unwrap_or_default();
+        item.collect_authorized_keys(&identities, &config.known_keys(), today())
+            .unwrap()
+            .sorted_keys()
+            .into_iter()
+            .map(|key| key.to_string())
+            .collect()
+    }
+
+    /// Pushes all items of `config` to the hosts of `connector`.
+    fn push_all(config: &Config, connector: &MemoryConnector, expiry_time: bool) {
+        push(
+            config,
+            connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            expiry_time,
+            today(),
+        )
+        .unwrap();
+    }
+
+    /// Audits the first item of `config` on the hosts of `connector`.
+    fn audit_first(config: &Config, connector: &MemoryConnector) -> AuditResult {
+        audit(
+            config,
+            connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            today(),
+        )
+        .unwrap()
+        .remove(0)
+        .1
+    }
+
+    /// Pulls the items of `config` selected by `selector` from hosts with the given files at `PATH`,
+    /// and reads back the configuration file `pull` would write.
+    fn pull_and_reload(mut config: Config, files: &[(&str, &str)], selector: &Selector) -> Config {
+        let connector = MemoryConnector::default();
+        for (hostname, text) in files {
+            connector.host(hostname).set_file(PATH, text);
         }
+
+        pull(
+            &mut config,
+            &connector,
+            selector,
+            HashAlg::Sha256,
+            1,
+            today(),
+        )
+        .unwrap();
+        serde_yaml::from_str(&serde_yaml::to_string(&config).unwrap()).unwrap()
     }
 
-    pub fn set_authorized_items(
-        &mut self,
-        authorized_keys: AuthorizedKeys,
-        identities: &Identities,
-    ) {
-        let mut authorized_items = AuthorizedItems::default();
-
-        for key in authorized_keys.iter().cloned() {
-            if let Some(identity) = identities.identity_for_key(&key) {
-                // only add the full identity if all of its keys are contained in `authorized_keys`
-                // otherwise we only add this specific key
-                let keys_for_identity = identities.keys_for_identity(&identity).unwrap_or_default();
-                if authorized_keys.is_superset(&keys_for_identity) {
-                    authorized_items.insert(AuthorizedItem::Identity(identity));
-                } else {
-                    authorized_items.insert(AuthorizedItem::PublicKey(key));
-                }
-            } else {
-                authorized_items.insert(AuthorizedItem::PublicKey(key));
+    #[test]
+    fn push_then_audit() {
+        let connector = MemoryConnector::default();
+        let host = connector.host("web.example.com");
+        host.set_file(PATH, &format!("# hand-written\n{}\n", test_keys::BAZ));
+
+        let results = audit(
+            &config(),
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            today(),
+        )
+        .unwrap();
+        let result = &results[0].1;
+        assert_eq!(result.statuses, [AuditStatus::Drifted]);
+        assert_eq!(result.unknown_keys.len(), 1);
+        assert_eq!(result.unknown_keys[0].identity, None);
+        let identities: Vec<_> = result
+            .missing_keys
+            .iter()
+            .map(|key| key.identity.as_deref())
+            .collect();
+        assert!(identities.contains(&Some("alice")));
+        assert!(identities.contains(&None));
+
+        push(
+            &config(),
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            false,
+            today(),
+        )
+        .unwrap();
+        assert_eq!(
+            host.file(PATH).unwrap(),
+            format!("# hand-written\n{}\n{}\n", test_keys::BAR, test_keys::FOO)
+        );
+        let results = audit(
+            &config(),
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            today(),
+        )
+        .unwrap();
+        assert_eq!(results[0].1.statuses, [AuditStatus::Ok]);
+
+        restore_authorized_keys(&host, PATH).unwrap();
+        assert!(host.file(PATH).unwrap().contains(test_keys::BAZ));
+    }
+
+    #[test]
+    fn pull_into_config() {
+        let config = pull_and_reload(
+            config(),
+            &[(
+                "web.example.com",
+                &format!("{}\n{}\n", test_keys::FOO, test_keys::BAZ),
+            )],
+            &Selector::default(),
+        );
+
+        let item = &config.hosts["web.example.com"].items[0];
+        assert_eq!(keys(&config, item), [test_keys::BAZ, test_keys::FOO]);
+        assert!(serde_yaml::to_string(&config).unwrap().contains("'@alice'"));
+    }
+
+    #[test]
+    fn pull_selected_items() {
+        let mut config = config();
+        let host = serde_yaml::to_value(&config.hosts["web.example.com"]).unwrap();
+        config.hosts.insert(
+            "db.example.com".to_owned(),
+            serde_yaml::from_value(host).unwrap(),
+        );
+
+        let selector = Selector {
+            hosts: vec!["db.*".to_owned()],
+            ..Selector::default()
+        };
+        let mut config = pull_and_reload(
+            config,
+            &[("db.example.com", &format!("{}\n", test_keys::BAZ))],
+            &selector,
+        );
+
+        let hosts = &config.hosts;
+        assert_eq!(
+            keys(&config, &hosts["db.example.com"].items[0]),
+            [test_keys::BAZ]
+        );
+        assert_eq!(keys(&config, &hosts["web.example.com"].items[0]).len(), 2);
+
+        let selector = Selector {
+            hosts: vec!["mail.*".to_owned()],
+            ..Selector::default()
+        };
+        let connector = MemoryConnector::default();
+        assert!(pull(
+            &mut config,
+            &connector,
+            &selector,
+            HashAlg::Sha256,
+            1,
+            today()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn pull_expands_unresolved_fingerprints() {
+        // one item lists `test_keys::FOO` in full and loses it on the host,
+        // while another one refers to it by fingerprint
+        let item =
+            |key: &str| format!("[{{user: deploy, path: {PATH}, authorized_keys: ['{key}']}}]");
+        let full = item(test_keys::FOO);
+        let by_fingerprint = item(&fingerprint(test_keys::FOO));
+        let hosts =
+            format!("hosts:\n  web.example.com: {full}\n  db.example.com: {by_fingerprint}\n");
+        let bar = format!("{}\n", test_keys::BAR);
+        let foo = format!("{}\n", test_keys::FOO);
+        let web = Selector {
+            hosts: vec!["web.*".to_owned()],
+            ..Selector::default()
+        };
+
+        let expected = [
+            ("db.example.com", test_keys::FOO),
+            ("web.example.com", test_keys::BAR),
+        ];
+
+        for (name, yaml, files, selector, expected) in [
+            (
+                "fingerprint of another host",
+                hosts.clone(),
+                vec![
+                    ("web.example.com", bar.as_str()),
+                    ("db.example.com", foo.as_str()),
+                ],
+                Selector::default(),
+                &expected[..],
+            ),
+            (
+                "fingerprint of an unselected host",
+                hosts,
+                vec![("web.example.com", bar.as_str())],
+                web,
+                &expected[..],
+            ),
+            (
+                "fingerprint of a group",
+                format!(
+                    "groups:\n  web:\n    items: {by_fingerprint}\n\
+                     hosts:\n  web.example.com:\n    groups: [web]\n    items: {full}\n"
+                ),
+                vec![("web.example.com", foo.as_str())],
+                Selector::default(),
+                &[("web.example.com", test_keys::FOO)][..],
+            ),
+        ] {
+            let config = pull_and_reload(serde_yaml::from_str(&yaml).unwrap(), &files, &selector);
+
+            let items = config
+                .hosts
+                .values()
+                .flat_map(|host| &host.items)
+                .chain(config.groups.values().flat_map(|group| &group.items));
+            for item in items {
+                assert_eq!(item.authorized_items.fingerprints().count(), 0, "{}", name);
+            }
+            let targets = config.targets().unwrap();
+            assert_eq!(targets.len(), expected.len(), "{}", name);
+            for (target, (hostname, key)) in targets.iter().zip(expected) {
+                assert_eq!(target.hostname, *hostname, "{}", name);
+                assert_eq!(keys(&config, &target.item), [*key], "{}", name);
             }
         }
+    }
+
+    #[test]
+    fn pull_keeps_inherited_keys_in_groups() {
+        let config: Config = serde_yaml::from_str(&format!(
+            "groups:
+  web:
+    items:
+    - user: deploy
+      path: {PATH}
+      authorized_keys: ['@alice']
+hosts:
+  web.example.com:
+    groups: [web]
+identities:
+  alice:
+  - {foo}
+",
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+
+        let config = pull_and_reload(
+            config,
+            &[(
+                "web.example.com",
+                &format!("{}\n{}\n", test_keys::FOO, test_keys::BAR),
+            )],
+            &Selector::default(),
+        );
+
+        let yaml = serde_yaml::to_string(&config).unwrap();
+        assert_eq!(yaml.matches("'@alice'").count(), 1);
+        let host = &config.hosts["web.example.com"];
+        assert_eq!(host.items.len(), 1);
+        assert_eq!(keys(&config, &host.items[0]), [test_keys::BAR]);
+    }
+
+    /// A configuration excluding `test_keys::BAR`, a key of `@sre`, by its fingerprint.
+    fn excluding_config() -> Config {
+        serde_yaml::from_str(&format!(
+            "hosts:
+  web.example.com:
+  - user: deploy
+    path: {PATH}
+    authorized_keys:
+    - '@sre'
+    - '!{bar_fingerprint}'
+identities:
+  alice:
+  - {foo}
+  sre:
+  - '@alice'
+  - {bar}
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            bar_fingerprint = fingerprint(test_keys::BAR),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn push_skips_excluded_keys() {
+        let connector = MemoryConnector::default();
+        push_all(&excluding_config(), &connector, false);
+
+        assert_eq!(
+            connector.host("web.example.com").file(PATH).unwrap(),
+            format!("{}\n", test_keys::FOO)
+        );
+    }
+
+    #[test]
+    fn audit_reports_excluded_keys() {
+        let connector = MemoryConnector::default();
+        connector.host("web.example.com").set_file(
+            PATH,
+            &format!(
+                "{}\n{}\n{}\n",
+                test_keys::FOO,
+                test_keys::BAR,
+                test_keys::BAZ
+            ),
+        );
+
+        let result = audit_first(&excluding_config(), &connector);
+        assert_eq!(
+            result.statuses,
+            [AuditStatus::ExcludedKey, AuditStatus::Drifted]
+        );
+        assert_eq!(result.excluded_keys.len(), 1);
+        assert_eq!(result.excluded_keys[0].identity.as_deref(), Some("sre"));
+        assert_eq!(result.unknown_keys.len(), 1);
+    }
+
+    #[test]
+    fn pull_skips_excluded_keys() {
+        let config = pull_and_reload(
+            excluding_config(),
+            &[(
+                "web.example.com",
+                &format!(
+                    "{}\n{}\n{}\n",
+                    test_keys::FOO,
+                    test_keys::BAR,
+                    test_keys::BAZ
+                ),
+            )],
+            &Selector::default(),
+        );
+
+        let item = &config.hosts["web.example.com"].items[0];
+        assert_eq!(keys(&config, item), [test_keys::BAZ, test_keys::FOO]);
+    }
+
+    /// `test_keys::BAR` of `@alice` has expired by `today()`, `test_keys::BAZ` expires later.
+    fn temporary_config() -> Config {
+        serde_yaml::from_str(&format!(
+            "hosts:
+  web.example.com:
+  - user: deploy
+    path: {PATH}
+    authorized_keys:
+    - '@alice'
+identities:
+  alice:
+  - {foo}
+  - key: {bar}
+    expires: 2024-01-01
+  - key: {baz}
+    expires: 2024-02-01
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            baz = test_keys::BAZ,
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn push_adds_expiry_times() {
+        let connector = MemoryConnector::default();
+        push_all(&temporary_config(), &connector, true);
+
+        assert_eq!(
+            connector.host("web.example.com").file(PATH).unwrap(),
+            format!(
+                "expiry-time=\"20240201Z\" {}\n{}\n",
+                test_keys::BAZ,
+                test_keys::FOO
+            )
+        );
+    }
+
+    #[test]
+    fn audit_reports_expired_keys() {
+        let connector = MemoryConnector::default();
+        connector.host("web.example.com").set_file(
+            PATH,
+            &format!(
+                "{}\n{}\n{}\n",
+                test_keys::FOO,
+                test_keys::BAR,
+                test_keys::BAZ
+            ),
+        );
+
+        let result = audit_first(&temporary_config(), &connector);
+        assert_eq!(result.statuses, [AuditStatus::Drifted]);
+        assert_eq!(result.expired_keys.len(), 1);
+        assert_eq!(
+            result.expired_keys[0].expires,
+            Some("2024-01-01".parse().unwrap())
+        );
+        assert!(result.unknown_keys.is_empty());
+    }
+
+    #[test]
+    fn expiring_keys_by_expiry() {
+        let identities = temporary_config().identities.unwrap();
+        let expiring = |within| {
+            expiring(&identities, today(), Days(within))
+                .into_iter()
+                .map(|(date, identity, key)| format!("{} {} {}", date, identity, key))
+                .collect::<Vec<_>>()
+        };
+
+        // expired keys are always listed
+        assert_eq!(
+            expiring(14),
+            [format!("2024-01-01 @alice {}", test_keys::BAR)]
+        );
+        assert_eq!(
+            expiring(30),
+            [
+                format!("2024-01-01 @alice {}", test_keys::BAR),
+                format!("2024-02-01 @alice {}", test_keys::BAZ),
+            ]
+        );
+    }
+
+    #[test]
+    fn expiring_rejects_selectors() {
+        let selector = Selector {
+            hosts: vec!["web.example.com".to_owned()],
+            ..Selector::default()
+        };
+
+        assert!(matches!(
+            expiring_config(
+                "unused.yaml".to_owned(),
+                &selector,
+                HashAlg::Sha256,
+                Days(14)
+            )
+            .unwrap_err()
+            .downcast_ref(),
+            Some(Error::SelectorUnsupported("expiring"))
+        ));
+    }
+
+    #[test]
+    fn pull_skips_expired_keys() {
+        let config = pull_and_reload(
+            temporary_config(),
+            &[(
+                "web.example.com",
+                &format!(
+                    "{}\n{}\n{}\n",
+                    test_keys::FOO,
+                    test_keys::BAR,
+                    test_keys::BAZ
+                ),
+            )],
+            &Selector::default(),
+        );
+
+        let item = &config.hosts["web.example.com"].items[0];
+        assert_eq!(keys(&config, item), [test_keys::BAZ, test_keys::FOO]);
+    }
+
+    /// `test_keys::FOO` is revoked, even though `@alice` still lists it.
+    fn revoking_config() -> Config {
+        serde_yaml::from_str(&format!(
+            "hosts:
+  web.example.com:
+  - user: deploy
+    path: {PATH}
+    authorized_keys:
+    - '@alice'
+    - {bar}
+identities:
+  alice:
+  - {foo}
+revoked:
+  keys:
+  - key: {foo_fingerprint}
+    reason: laptop stolen
+    date: 2024-05-01
+",
+            foo = test_keys::FOO,
+            bar = test_keys::BAR,
+            foo_fingerprint = fingerprint(test_keys::FOO),
+        ))
+        .unwrap()
+    }
+
+    /// Plans the first item of `config` on the hosts of `connector`.
+    fn plan_first(config: &Config, connector: &MemoryConnector) -> Vec<String> {
+        plan(
+            config,
+            connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            today(),
+        )
+        .unwrap()
+        .remove(0)
+    }
+
+    #[test]
+    fn audit_reports_revoked_keys() {
+        let connector = MemoryConnector::default();
+        connector
+            .host("web.example.com")
+            .set_file(PATH, &format!("{}\n{}\n", test_keys::FOO, test_keys::BAR));
+
+        let result = audit_first(&revoking_config(), &connector);
+        assert_eq!(result.statuses, [AuditStatus::RevokedKey]);
+        assert_eq!(result.revoked_keys.len(), 1);
+        assert_eq!(
+            result.revoked_keys[0].to_string(),
+            format!(
+                "{} (foo) @alice (revoked on 2024-05-01: laptop stolen)",
+                fingerprint(test_keys::FOO)
+            )
+        );
+        assert!(result.unknown_keys.is_empty());
+    }
+
+    #[test]
+    fn audit_does_not_expect_revoked_keys() {
+        let connector = MemoryConnector::default();
+        connector
+            .host("web.example.com")
+            .set_file(PATH, &format!("{}\n", test_keys::BAR));
+
+        let result = audit_first(&revoking_config(), &connector);
+        assert_eq!(result.statuses, [AuditStatus::Ok]);
+        assert!(result.missing_keys.is_empty());
+    }
+
+    #[test]
+    fn plan_removes_revoked_keys() {
+        let connector = MemoryConnector::default();
+        connector
+            .host("web.example.com")
+            .set_file(PATH, &format!("{}\n{}\n", test_keys::FOO, test_keys::BAR));
+
+        assert_eq!(
+            plan_first(&revoking_config(), &connector),
+            [format!(
+                "- {} @alice",
+                describe_key(&test_keys::FOO.parse().unwrap(), HashAlg::Sha256)
+            )]
+        );
+    }
+
+    #[test]
+    fn plan_does_not_add_revoked_keys() {
+        let connector = MemoryConnector::default();
+        connector
+            .host("web.example.com")
+            .set_file(PATH, &format!("{}\n", test_keys::BAR));
+
+        assert_eq!(plan_first(&revoking_config(), &connector), ["no changes"]);
+    }
+
+    #[test]
+    fn push_removes_revoked_keys() {
+        let connector = MemoryConnector::default();
+        let host = connector.host("web.example.com");
+        host.set_file(PATH, &format!("{}\n{}\n", test_keys::FOO, test_keys::BAR));
+
+        push_all(&revoking_config(), &connector, false);
+        assert_eq!(host.file(PATH).unwrap(), format!("{}\n", test_keys::BAR));
+    }
+
+    #[test]
+    fn pull_skips_revoked_keys() {
+        let config = pull_and_reload(
+            revoking_config(),
+            &[(
+                "web.example.com",
+                &format!("{}\n{}\n", test_keys::FOO, test_keys::BAR),
+            )],
+            &Selector::default(),
+        );
+
+        let item = &config.hosts["web.example.com"].items[0];
+        assert_eq!(keys(&config, item), [test_keys::BAR]);
+    }
+
+    /// `config()` with a second host authorizing `test_keys::FOO` by its fingerprint.
+    fn locating_config() -> Config {
+        let mut config = config();
+        config.hosts.extend(
+            serde_yaml::from_str::<Config>(&format!(
+                "hosts:
+  db.example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys:
+    - {foo_fingerprint}
+",
+                foo_fingerprint = fingerprint(test_keys::FOO),
+            ))
+            .unwrap()
+            .hosts,
+        );
+        config
+    }
+
+    /// Locates the keys matching `query` in `locating_config()` on the hosts of `connector`.
+    fn locate_all(connector: &MemoryConnector, query: &str, live: bool) -> Vec<Access> {
+        locate(
+            &locating_config(),
+            connector,
+            &Selector::default(),
+            1,
+            &parse_query(query).unwrap(),
+            live,
+            today(),
+        )
+        .unwrap()
+        .into_iter()
+        .flat_map(|(_, result)| result.unwrap())
+        .collect()
+    }
+
+    #[test]
+    fn where_lists_configured_accesses() {
+        let accesses = locate_all(&MemoryConnector::default(), "@alice", false);
+
+        assert_eq!(
+            accesses_table(&accesses, HashAlg::Sha256, false),
+            format!(
+                "HOST             USER    PATH                               KEY                                                       AUTHORIZED BY
+db.example.com   root    /root/.ssh/authorized_keys         {foo} (foo)  {foo}
+web.example.com  deploy  {PATH}  {foo} (foo)  @alice
+",
+                foo = fingerprint(test_keys::FOO),
+            )
+        );
+    }
+
+    #[test]
+    fn where_finds_keys() {
+        let connector = MemoryConnector::default();
+
+        assert_eq!(locate_all(&connector, test_keys::BAR, false).len(), 1);
+        assert!(locate_all(&connector, test_keys::BAZ, false).is_empty());
+    }
+
+    #[test]
+    fn where_live_finds_unconfigured_keys() {
+        // `test_keys::BAZ` is not in the configuration file, but authorized on the server
+        let connector = MemoryConnector::default();
+        connector
+            .host("web.example.com")
+            .set_file(PATH, &format!("{}\n{}\n", test_keys::FOO, test_keys::BAZ));
+
+        let accesses = locate_all(&connector, test_keys::BAZ, true);
+        assert_eq!(accesses.len(), 1);
+        assert!(accesses[0].grants.is_empty());
+        assert_eq!(accesses[0].deployed, Some(true));
+    }
+
+    #[test]
+    fn where_live_reports_deployment() {
+        let connector = MemoryConnector::default();
+        connector
+            .host("web.example.com")
+            .set_file(PATH, &format!("{}\n", test_keys::FOO));
+
+        let accesses = locate_all(&connector, "@alice", true);
+        assert_eq!(
+            accesses
+                .iter()
+                .map(|access| (access.hostname.as_str(), access.deployed))
+                .collect::<Vec<_>>(),
+            [
+                ("db.example.com", Some(false)),
+                ("web.example.com", Some(true))
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_query_rejects_exclusions() {
+        assert!(parse_query("!@alice").is_err());
+        assert!(parse_query("@alice").is_ok());
+    }
+
+    #[test]
+    fn option_differences() {
+        let config: Config = serde_yaml::from_str(&format!(
+            "hosts:
+  web.example.com:
+  - user: deploy
+    path: {PATH}
+    authorized_keys:
+    - 'no-pty {foo}'
+identities: {{}}
+",
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+
+        let connector = MemoryConnector::default();
+        let host = connector.host("web.example.com");
+        host.set_file(PATH, &format!("{}\n", test_keys::FOO));
+        let audit = || {
+            audit(
+                &config,
+                &connector,
+                &Selector::default(),
+                HashAlg::Sha256,
+                1,
+                today(),
+            )
+            .unwrap()
+            .remove(0)
+            .1
+        };
+
+        let result = audit();
+        assert_eq!(result.statuses, [AuditStatus::Drifted]);
+        assert_eq!(
+            result.errors,
+            ["found key SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo (foo) with options ``, expected `no-pty`"]
+        );
+
+        push(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            false,
+            today(),
+        )
+        .unwrap();
+        assert_eq!(
+            host.file(PATH).unwrap(),
+            format!("no-pty {}\n", test_keys::FOO)
+        );
+        assert_eq!(audit().statuses, [AuditStatus::Ok]);
+    }
+
+    #[test]
+    fn push_refuses_policy_violations() {
+        let connector = MemoryConnector::default();
+        let mut config = config();
+        config.policy =
+            Some(serde_yaml::from_str("default:\n  denied_key_types: [ssh-ed25519]").unwrap());
+
+        assert!(push(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            false,
+            today()
+        )
+        .is_err());
+        assert_eq!(connector.host("web.example.com").file(PATH), None);
+    }
+
+    #[test]
+    fn policy_violations_in_key_order() {
+        let policy: Policy =
+            serde_yaml::from_str("default:\n  denied_key_types: [ssh-ed25519]").unwrap();
+        let mut authorized_keys = AuthorizedKeys::default();
+        for key in [test_keys::FOO, test_keys::BAR, test_keys::BAZ] {
+            authorized_keys.insert(key.parse().unwrap());
+        }
+
+        assert_eq!(
+            policy_violations(&policy, "deploy", &authorized_keys, HashAlg::Sha256),
+            authorized_keys
+                .sorted_keys()
+                .into_iter()
+                .map(|key| format!(
+                    "found key violating policy {}: key type ssh-ed25519 is denied",
+                    describe_key(key, HashAlg::Sha256)
+                ))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn plan_changes() {
+        let connector = MemoryConnector::default();
+        let host = connector.host("web.example.com");
+        host.set_file(
+            PATH,
+            &format!(
+                "# hand-written\n{}\nno-pty {}\n",
+                test_keys::BAZ,
+                test_keys::FOO
+            ),
+        );
+        let describe = |key: &str| describe_key(&key.parse().unwrap(), HashAlg::Sha256);
+        let plan = |config: &Config| {
+            plan(
+                config,
+                &connector,
+                &Selector::default(),
+                HashAlg::Sha256,
+                1,
+                today(),
+            )
+            .unwrap()
+            .remove(0)
+        };
+
+        assert_eq!(
+            plan(&config()),
+            [
+                format!("+ {}", describe(test_keys::BAR)),
+                format!("- {}", describe(test_keys::BAZ)),
+                format!("~ {} options `no-pty` -> ``", describe(test_keys::FOO)),
+            ]
+        );
+        assert!(host.file(PATH).unwrap().contains(test_keys::BAZ));
+
+        let mut refused = config();
+        refused.policy =
+            Some(serde_yaml::from_str("default:\n  denied_key_types: [ssh-ed25519]").unwrap());
+        assert_eq!(plan(&refused)[0], "push refused: policy violated");
+
+        push(
+            &config(),
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            false,
+            today(),
+        )
+        .unwrap();
+        assert_eq!(plan(&config()), ["no changes"]);
+
+        let mut removed = config();
+        removed.hosts.get_mut("web.example.com").unwrap().items[0].authorized_items =
+            serde_yaml::from_str("['@alice']").unwrap();
+        assert_eq!(plan(&removed), [format!("- {}", describe(test_keys::BAR))]);
+    }
+
+    #[test]
+    fn audit_continues_past_failures() {
+        let mut config = config();
+        for hostname in ["a.example.com", "b.example.com", "c.example.com"] {
+            let host = serde_yaml::to_value(&config.hosts["web.example.com"]).unwrap();
+            config
+                .hosts
+                .insert(hostname.to_owned(), serde_yaml::from_value(host).unwrap());
+        }
+
+        let connector = MemoryConnector::default();
+        push(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            false,
+            today(),
+        )
+        .unwrap();
+        connector.set_unreachable("a.example.com");
+        connector
+            .host("b.example.com")
+            .set_file(PATH, "not a key\n");
+        connector.host("c.example.com").set_file(PATH, "");
+
+        let results: Vec<_> = audit(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            1,
+            today(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|(_, result)| result)
+        .collect();
+        let statuses: Vec<_> = results
+            .iter()
+            .map(|result| (result.hostname.as_str(), result.statuses.as_slice()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ("a.example.com", &[AuditStatus::Unreachable][..]),
+                // an invalid line instead of the expected keys
+                (
+                    "b.example.com",
+                    &[AuditStatus::ParseError, AuditStatus::Drifted]
+                ),
+                ("c.example.com", &[AuditStatus::Drifted]),
+                ("web.example.com", &[AuditStatus::Ok]),
+            ]
+        );
+        assert_eq!(audit::exit_code(&results), 14);
+        assert_eq!(results[0].errors, ["host a.example.com is unreachable"]);
+        assert_eq!(results[1].errors.len(), 1);
+    }
+
+    #[test]
+    fn one_connection_per_host_and_user() {
+        let config: Config = serde_yaml::from_str(&format!(
+            "hosts:
+  web.example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys: [{foo}]
+  - user: root
+    path: {PATH}
+    authorized_keys: [{foo}]
+  - user: deploy
+    path: /home/deploy/.ssh/authorized_keys2
+    authorized_keys: [{foo}]
+",
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+
+        let connector = MemoryConnector::default();
+        push(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            2,
+            false,
+            today(),
+        )
+        .unwrap();
+        assert_eq!(connector.connections(), 2);
+
+        audit(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            2,
+            today(),
+        )
+        .unwrap();
+        assert_eq!(connector.connections(), 4);
+    }
+
+    #[test]
+    fn concurrent_push_and_pull() {
+        let hostnames: Vec<_> = (0..8).map(|i| format!("web{}.example.com", i)).collect();
+        let mut config: Config = serde_yaml::from_str(&format!(
+            "hosts:
+{hosts}identities:
+  alice:
+  - {foo}
+",
+            hosts = hostnames
+                .iter()
+                .map(|hostname| format!(
+                    "  {hostname}:\n  - user: deploy\n    path: {PATH}\n    authorized_keys: ['@alice']\n"
+                ))
+                .collect::<String>(),
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+
+        let connector = MemoryConnector::default();
+        push(
+            &config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            4,
+            false,
+            today(),
+        )
+        .unwrap();
+
+        for hostname in &hostnames {
+            assert_eq!(
+                connector.host(hostname).file(PATH).unwrap(),
+                format!("{}\n", test_keys::FOO)
+            );
+        }
+
+        // only the last host has an additional key, which must be pulled into its own item
+        let last = hostnames.last().unwrap();
+        connector
+            .host(last)
+            .set_file(PATH, &format!("{}\n{}\n", test_keys::FOO, test_keys::BAR));
+        pull(
+            &mut config,
+            &connector,
+            &Selector::default(),
+            HashAlg::Sha256,
+            4,
+            today(),
+        )
+        .unwrap();
 
-        self.authorized_items = authorized_items;
+        for (hostname, host) in &config.hosts {
+            let expected = if hostname == last { 1 } else { 0 };
+            assert_eq!(
+                host.items[0].authorized_items.public_keys().count(),
+                expected,
+                "{}",
+                hostname
+            );
+        }
     }
 }
@@ -0,0 +1,1010 @@
+//! SSH connections using a native client, as an alternative to the `ssh` binary.
+//!
+//! Host keys are verified against `known_hosts`, and files are accessed via SFTP.
+
+use crate::{
+    backup,
+    config::ConnectionSettings,
+    transport::{FileStat, Transport},
+};
+use russh::{
+    client,
+    keys::{self, agent::client::AgentClient, agent::AgentIdentity, PrivateKeyWithHashAlg},
+    Disconnect,
+};
+use russh_sftp::{
+    client::{error::Error as SftpError, RawSftpSession},
+    protocol::{FileAttributes, OpenFlags, Packet, StatusCode},
+};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// The size of the chunks in which files are read and written.
+const CHUNK_SIZE: u32 = 32 * 1024;
+
+const POSIX_RENAME: &str = "posix-rename@openssh.com";
+const FSYNC: &str = "fsync@openssh.com";
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("SSH connection failed")]
+    Ssh(#[from] russh::Error),
+    #[error("failed to load key")]
+    Key(#[from] keys::Error),
+    #[error("host key of {hostname} is not listed in {known_hosts}")]
+    UnknownHostKey {
+        hostname: String,
+        known_hosts: String,
+    },
+    #[error("host key of {hostname} does not match line {line} of {known_hosts}")]
+    HostKeyChanged {
+        hostname: String,
+        line: usize,
+        known_hosts: String,
+    },
+    #[error(
+        "authentication as {user}@{hostname} failed{}",
+        .warnings.iter().map(|warning| format!(" ({})", warning)).collect::<String>()
+    )]
+    Authentication {
+        hostname: String,
+        user: String,
+        warnings: Vec<String>,
+    },
+    #[error("SFTP request failed")]
+    Sftp(#[from] SftpError),
+    #[error("SFTP server of {0} does not support atomic renames")]
+    NoPosixRename(String),
+    #[error("file {0} is not valid UTF-8")]
+    InvalidUtf8(String),
+    #[error("connection to {0} timed out")]
+    Timeout(String),
+    #[error("{0} is not supported by the native SSH client")]
+    UnsupportedSetting(&'static str),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Settings of native SSH connections.
+pub struct NativeSshOptions {
+    pub port: u16,
+    /// Private keys to authenticate with if the SSH agent has no suitable key.
+    pub identity_files: Vec<PathBuf>,
+    pub known_hosts: PathBuf,
+    /// Whether to authenticate with the keys of the SSH agent at `SSH_AUTH_SOCK`.
+    pub use_agent: bool,
+    pub connect_timeout: Option<Duration>,
+}
+
+impl NativeSshOptions {
+    /// Returns the default options, overridden by the connection settings of a host.
+    pub fn from_settings(settings: &ConnectionSettings) -> Result<Self> {
+        if settings.proxy_jump.is_some() {
+            return Err(Error::UnsupportedSetting("proxy_jump"));
+        }
+
+        if !settings.ssh_options.is_empty() {
+            return Err(Error::UnsupportedSetting("ssh_options"));
+        }
+
+        let mut options = NativeSshOptions::default();
+
+        if let Some(port) = settings.port {
+            options.port = port;
+        }
+
+        if let Some(identity_file) = &settings.identity_file {
+            options.identity_files = vec![match identity_file.strip_prefix("~/") {
+                Some(path) => home_dir().join(path),
+                None => PathBuf::from(identity_file),
+            }];
+        }
+
+        options.connect_timeout = settings.connect_timeout.map(Duration::from_secs);
+
+        Ok(options)
+    }
+}
+
+fn home_dir() -> PathBuf {
+    PathBuf::from(std::env::var_os("HOME").unwrap_or_default())
+}
+
+impl Default for NativeSshOptions {
+    fn default() -> Self {
+        let ssh_dir = home_dir().join(".ssh");
+
+        NativeSshOptions {
+            port: 22,
+            identity_files: ["id_ed25519", "id_ecdsa", "id_rsa"]
+                .into_iter()
+                .map(|name| ssh_dir.join(name))
+                .collect(),
+            known_hosts: ssh_dir.join("known_hosts"),
+            use_agent: true,
+            connect_timeout: None,
+        }
+    }
+}
+
+/// Verifies the host key of the server against `known_hosts`.
+struct Client {
+    hostname: String,
+    port: u16,
+    known_hosts: PathBuf,
+}
+
+impl client::Handler for Client {
+    type Error = Error;
+
+    async fn check_server_key(&mut self, key: &keys::PublicKeyOrCertificate) -> Result<bool> {
+        let known_hosts = self.known_hosts.display().to_string();
+
+        let known = match key {
+            keys::PublicKeyOrCertificate::PublicKey { key, .. } => {
+                match keys::check_known_hosts_path(
+                    &self.hostname,
+                    self.port,
+                    key,
+                    &self.known_hosts,
+                ) {
+                    Err(keys::Error::KeyChanged { line }) => {
+                        return Err(Error::HostKeyChanged {
+                            hostname: self.hostname.clone(),
+                            line,
+                            known_hosts,
+                        })
+                    }
+                    result => result?,
+                }
+            }
+            // host certificates are not supported
+            keys::PublicKeyOrCertificate::Certificate(_) => false,
+        };
+
+        if !known {
+            return Err(Error::UnknownHostKey {
+                hostname: self.hostname.clone(),
+                known_hosts,
+            });
+        }
+
+        Ok(true)
+    }
+}
+
+/// A connection to a host, accessing its files via SFTP.
+pub struct NativeSshConnection {
+    runtime: tokio::runtime::Runtime,
+    session: client::Handle<Client>,
+    sftp: RawSftpSession,
+    hostname: String,
+    user: String,
+    posix_rename: bool,
+    fsync: bool,
+    /// Warnings about identity files that could not be loaded
+    warnings: Vec<String>,
+}
+
+impl NativeSshConnection {
+    pub fn connect(hostname: &str, user: &str, options: &NativeSshOptions) -> Result<Self> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .map_err(russh::Error::from)?;
+
+        let connect = async {
+            let client = Client {
+                hostname: hostname.to_owned(),
+                port: options.port,
+                known_hosts: options.known_hosts.clone(),
+            };
+            let config = Arc::new(client::Config::default());
+            let mut session = client::connect(config, (hostname, options.port), client).await?;
+
+            let mut warnings = Vec::new();
+            if !authenticate(&mut session, user, options, &mut warnings).await? {
+                return Err(Error::Authentication {
+                    hostname: hostname.to_owned(),
+                    user: user.to_owned(),
+                    warnings,
+                });
+            }
+
+            let channel = session.channel_open_session().await?;
+            channel.request_subsystem(true, "sftp").await?;
+            let sftp = RawSftpSession::new(channel.into_stream());
+            let version = sftp.init().await?;
+
+            Ok((session, sftp, version.extensions, warnings))
+        };
+
+        let (session, sftp, extensions, warnings) = runtime.block_on(async {
+            match options.connect_timeout {
+                Some(timeout) => tokio::time::timeout(timeout, connect)
+                    .await
+                    .map_err(|_| Error::Timeout(hostname.to_owned()))?,
+                None => connect.await,
+            }
+        })?;
+
+        let has_extension = |name| extensions.get(name).is_some_and(|version| version == "1");
+
+        Ok(NativeSshConnection {
+            posix_rename: has_extension(POSIX_RENAME),
+            fsync: has_extension(FSYNC),
+            runtime,
+            session,
+            sftp,
+            hostname: hostname.to_owned(),
+            user: user.to_owned(),
+            warnings,
+        })
+    }
+
+    async fn read(&self, path: &str) -> Result<Vec<u8>> {
+        let handle = self
+            .sftp
+            .open(path, OpenFlags::READ, FileAttributes::empty())
+            .await?
+            .handle;
+
+        let mut data = Vec::new();
+        let result = loop {
+            match self.sftp.read(&handle, data.len() as u64, CHUNK_SIZE).await {
+                Ok(chunk) if chunk.data.is_empty() => break Ok(()),
+                Ok(chunk) => data.extend(chunk.data),
+                Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => {
+                    break Ok(())
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.sftp.close(handle).await?;
+        result?;
+        Ok(data)
+    }
+
+    async fn attributes(&self, path: &str) -> Result<Option<FileAttributes>> {
+        match self.sftp.stat(path).await {
+            Ok(attrs) => Ok(Some(attrs.attrs)),
+            Err(SftpError::Status(status)) if status.status_code == StatusCode::NoSuchFile => {
+                Ok(None)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes `data` to `path`, taking over the mode and, if permitted, the owner from `attrs`.
+    async fn create(&self, path: &str, data: &[u8], attrs: &FileAttributes) -> Result<()> {
+        let flags = OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE;
+        let handle = self
+            .sftp
+            .open(path, flags, permissions(0o600))
+            .await?
+            .handle;
+
+        let result = async {
+            let mut offset = 0;
+            for chunk in data.chunks(CHUNK_SIZE as usize) {
+                self.sftp.write(&handle, offset, chunk.to_vec()).await?;
+                offset += chunk.len() as u64;
+            }
+
+            let mode = attrs.permissions.unwrap_or(0o600) & 0o7777;
+            self.sftp.fsetstat(&handle, permissions(mode)).await?;
+
+            // like `cp -p`, only privileged users can preserve the owner
+            let mut owner = FileAttributes::empty();
+            owner.uid = attrs.uid;
+            owner.gid = attrs.gid;
+            if owner.uid.is_some() && owner.gid.is_some() {
+                let _ = self.sftp.fsetstat(&handle, owner).await;
+            }
+
+            if self.fsync {
+                self.sftp.fsync(&handle).await?;
+            }
+
+            Ok::<_, SftpError>(())
+        }
+        .await;
+
+        self.sftp.close(handle).await?;
+        Ok(result?)
+    }
+
+    /// Atomically replaces `path` with `data`, see [`create`](Self::create).
+    async fn replace(&self, path: &str, data: &[u8], attrs: &FileAttributes) -> Result<()> {
+        if !self.posix_rename {
+            return Err(Error::NoPosixRename(self.hostname.clone()));
+        }
+
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |duration| duration.subsec_nanos());
+        let tmp = format!("{}.tmp.{}.{}", path, std::process::id(), nanos);
+
+        let result = async {
+            self.create(&tmp, data, attrs).await?;
+            self.rename(&tmp, path).await
+        }
+        .await;
+
+        if result.is_err() {
+            let _ = self.sftp.remove(tmp).await;
+        }
+
+        result
+    }
+
+    /// Renames `from` to `to`, replacing `to` if it exists.
+    async fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut data = Vec::new();
+        for path in [from, to] {
+            data.extend((path.len() as u32).to_be_bytes());
+            data.extend(path.as_bytes());
+        }
+
+        match self.sftp.extended(POSIX_RENAME, data).await? {
+            Packet::Status(status) if status.status_code == StatusCode::Ok => Ok(()),
+            Packet::Status(status) => Err(SftpError::Status(status).into()),
+            _ => Err(SftpError::UnexpectedPacket.into()),
+        }
+    }
+
+    async fn list_dir(&self, dir: &str) -> Result<Vec<String>> {
+        let handle = self.sftp.opendir(dir).await?.handle;
+
+        let mut names = Vec::new();
+        let result = loop {
+            match self.sftp.readdir(&handle).await {
+                Ok(name) => names.extend(
+                    name.files
+                        .into_iter()
+                        .map(|file| file.filename)
+                        .filter(|name| name != "." && name != ".."),
+                ),
+                Err(SftpError::Status(status)) if status.status_code == StatusCode::Eof => {
+                    break Ok(())
+                }
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.sftp.close(handle).await?;
+        result?;
+        Ok(names)
+    }
+}
+
+/// Authenticates as `user`, first with the keys of the SSH agent and then with the identity files.
+/// Returns `false` if no key was accepted.
+///
+/// Identity files that cannot be loaded are skipped, adding a warning to `warnings`.
+async fn authenticate(
+    session: &mut client::Handle<Client>,
+    user: &str,
+    options: &NativeSshOptions,
+    warnings: &mut Vec<String>,
+) -> Result<bool> {
+    let rsa_hash = session.best_supported_rsa_hash().await?.flatten();
+
+    if options.use_agent {
+        if let Ok(mut agent) = AgentClient::connect_env().await {
+            for identity in agent.request_identities().await? {
+                if let AgentIdentity::PublicKey { key, .. } = identity {
+                    let hash_alg = key.algorithm().is_rsa().then_some(rsa_hash).flatten();
+                    let result = session
+                        .authenticate_publickey_with(user, key, hash_alg, &mut agent)
+                        .await
+                        .map_err(|_| russh::Error::SendError)?;
+
+                    if result.success() {
+                        return Ok(true);
+                    }
+                }
+            }
+        }
+    }
+
+    for path in options.identity_files.iter().filter(|path| path.exists()) {
+        let key = match keys::load_secret_key(path, None) {
+            Ok(key) => key,
+            Err(e) => {
+                warnings.push(format!("skipped identity file {}: {}", path.display(), e));
+                continue;
+            }
+        };
+
+        let key = PrivateKeyWithHashAlg::new(Arc::new(key), rsa_hash);
+        if session.authenticate_publickey(user, key).await?.success() {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// Returns attributes only setting the permission bits to `mode`.
+fn permissions(mode: u32) -> FileAttributes {
+    let mut attrs = FileAttributes::empty();
+    attrs.permissions = Some(mode);
+    attrs
+}
+
+impl Transport for NativeSshConnection {
+    fn read_file(&self, path: &str) -> anyhow::Result<String> {
+        let data = self.runtime.block_on(self.read(path))?;
+        Ok(String::from_utf8(data).map_err(|_| Error::InvalidUtf8(path.to_owned()))?)
+    }
+
+    fn write_file(&self, path: &str, text: &str) -> anyhow::Result<()> {
+        self.runtime.block_on(async {
+            let attrs = self.attributes(path).await?;
+
+            if let Some(attrs) = &attrs {
+                let previous = self.read(path).await?;
+                self.create(&backup::new_backup_path(path), &previous, attrs)
+                    .await?;
+            } else if let Some(dir) = Path::new(path).parent().and_then(Path::to_str) {
+                if !dir.is_empty() && self.attributes(dir).await?.is_none() {
+                    self.sftp.mkdir(dir, permissions(0o700)).await?;
+                }
+            }
+
+            let attrs = attrs.unwrap_or_else(|| permissions(0o600));
+            self.replace(path, text.as_bytes(), &attrs).await
+        })?;
+
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Option<FileStat>> {
+        let attrs = self.runtime.block_on(self.attributes(path))?;
+        Ok(attrs.map(|attrs| FileStat {
+            mode: attrs.permissions.unwrap_or_default() & 0o7777,
+        }))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        Ok(self.runtime.block_on(self.list_dir(dir))?)
+    }
+
+    fn restore_backup(&self, path: &str, backup: &str) -> anyhow::Result<()> {
+        self.runtime.block_on(async {
+            let attrs = self.sftp.stat(backup).await?.attrs;
+            let contents = self.read(backup).await?;
+            self.replace(path, &contents, &attrs).await?;
+            self.sftp.remove(backup).await?;
+            Ok::<_, Error>(())
+        })?;
+
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> anyhow::Result<()> {
+        self.runtime.block_on(self.sftp.remove(path))?;
+        Ok(())
+    }
+
+    fn warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+}
+
+impl fmt::Display for NativeSshConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sftp://{}@{}", self.user, self.hostname)
+    }
+}
+
+impl Drop for NativeSshConnection {
+    fn drop(&mut self) {
+        let _ = self
+            .runtime
+            .block_on(self.session.disconnect(Disconnect::ByApplication, "", "en"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+    use russh::{
+        server::{self, Auth, Msg, Session},
+        Channel, ChannelId,
+    };
+    use russh_sftp::protocol::{Attrs, Data, File, Handle, Name, Status, Version};
+    use std::{
+        collections::HashMap,
+        fs,
+        io::ErrorKind,
+        os::unix::fs::{DirBuilderExt, FileExt, OpenOptionsExt, PermissionsExt},
+    };
+
+    type ServerResult<T> = std::result::Result<T, StatusCode>;
+
+    #[test]
+    fn read_and_write_files() {
+        let (root, _client_dir, options) = start_server();
+        let connection = NativeSshConnection::connect("127.0.0.1", "test", &options).unwrap();
+        let path = "/authorized_keys";
+
+        assert_eq!(connection.stat(path).unwrap(), None);
+
+        connection.write_file(path, "first\n").unwrap();
+        assert_eq!(connection.read_file(path).unwrap(), "first\n");
+        assert_eq!(
+            connection.stat(path).unwrap(),
+            Some(FileStat { mode: 0o600 })
+        );
+
+        fs::set_permissions(
+            root.path().join("authorized_keys"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        let long_text = "second\n".repeat(10_000);
+        connection.write_file(path, &long_text).unwrap();
+        assert_eq!(connection.read_file(path).unwrap(), long_text);
+        assert_eq!(
+            connection.stat(path).unwrap(),
+            Some(FileStat { mode: 0o640 })
+        );
+
+        let backup = backup::find_latest_backup(&connection, path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(connection.read_file(&backup).unwrap(), "first\n");
+        assert_eq!(connection.list("/").unwrap().len(), 2);
+
+        connection.restore_backup(path, &backup).unwrap();
+        assert_eq!(connection.read_file(path).unwrap(), "first\n");
+        assert_eq!(connection.list("/").unwrap(), vec!["authorized_keys"]);
+
+        connection
+            .write_file("/.ssh/authorized_keys", "new\n")
+            .unwrap();
+        assert_eq!(
+            connection
+                .stat("/.ssh")
+                .unwrap()
+                .map(|stat| stat.mode & 0o777),
+            Some(0o700)
+        );
+    }
+
+    #[test]
+    fn reject_unknown_host_key() {
+        let (_root, _client_dir, options) = start_server();
+        fs::write(&options.known_hosts, "").unwrap();
+
+        assert!(matches!(
+            NativeSshConnection::connect("127.0.0.1", "test", &options),
+            Err(Error::UnknownHostKey { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_changed_host_key() {
+        let (_root, _client_dir, options) = start_server();
+        let other_key = ed25519_key(3).public_key().to_openssh().unwrap();
+        fs::write(
+            &options.known_hosts,
+            format!("[127.0.0.1]:{} {}\n", options.port, other_key),
+        )
+        .unwrap();
+
+        assert!(matches!(
+            NativeSshConnection::connect("127.0.0.1", "test", &options),
+            Err(Error::HostKeyChanged { line: 1, .. })
+        ));
+    }
+
+    #[test]
+    fn reject_unknown_user() {
+        let (_root, _client_dir, options) = start_server();
+
+        assert!(matches!(
+            NativeSshConnection::connect("127.0.0.1", "root", &options),
+            Err(Error::Authentication { .. })
+        ));
+    }
+
+    #[test]
+    fn report_skipped_identity_files() {
+        let (_root, client_dir, mut options) = start_server();
+        let invalid = client_dir.path().join("id_invalid");
+        fs::write(&invalid, "not a key\n").unwrap();
+        options.identity_files.insert(0, invalid.clone());
+
+        let connection = NativeSshConnection::connect("127.0.0.1", "test", &options).unwrap();
+        let warnings = connection.warnings();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with(&format!("skipped identity file {}: ", invalid.display())));
+
+        let error = match NativeSshConnection::connect("127.0.0.1", "root", &options) {
+            Err(e @ Error::Authentication { .. }) => e.to_string(),
+            _ => panic!("authentication should fail"),
+        };
+        assert!(error.contains("(skipped identity file"));
+    }
+
+    #[test]
+    fn options_from_settings() {
+        let options = NativeSshOptions::from_settings(&ConnectionSettings {
+            port: Some(2222),
+            identity_file: Some("/etc/keys/deploy".to_owned()),
+            connect_timeout: Some(10),
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(options.port, 2222);
+        assert_eq!(
+            options.identity_files,
+            vec![PathBuf::from("/etc/keys/deploy")]
+        );
+        assert_eq!(options.connect_timeout, Some(Duration::from_secs(10)));
+
+        assert!(matches!(
+            NativeSshOptions::from_settings(&ConnectionSettings {
+                proxy_jump: Some("bastion.example.com".to_owned()),
+                ..Default::default()
+            }),
+            Err(Error::UnsupportedSetting("proxy_jump"))
+        ));
+    }
+
+    fn ed25519_key(seed: u8) -> keys::PrivateKey {
+        keys::ssh_key::private::Ed25519Keypair::from_seed(&[seed; 32]).into()
+    }
+
+    /// Starts an SSH server on a random port, serving the files of a temporary directory
+    /// to the user `test`, and returns that directory, the temporary directory holding
+    /// the client's key and known hosts, and options to connect to the server.
+    fn start_server() -> (tempfile::TempDir, tempfile::TempDir, NativeSshOptions) {
+        let root = tempfile::tempdir().unwrap();
+        let client_dir = tempfile::tempdir().unwrap();
+        let host_key = ed25519_key(1);
+        let client_key = ed25519_key(2);
+
+        let identity_file = client_dir.path().join("id_ed25519");
+        fs::write(
+            &identity_file,
+            client_key
+                .to_openssh(keys::ssh_key::LineEnding::LF)
+                .unwrap(),
+        )
+        .unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let known_hosts = client_dir.path().join("known_hosts");
+        fs::write(
+            &known_hosts,
+            format!(
+                "[127.0.0.1]:{} {}\n",
+                port,
+                host_key.public_key().to_openssh().unwrap()
+            ),
+        )
+        .unwrap();
+
+        let config = Arc::new(server::Config {
+            keys: vec![host_key],
+            ..Default::default()
+        });
+        let server_root = root.path().to_owned();
+        let client_key = client_key.public_key().clone();
+
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .unwrap();
+
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let server = TestServer {
+                        root: server_root.clone(),
+                        client_key: client_key.clone(),
+                        channels: HashMap::new(),
+                    };
+                    let session = server::run_stream(config.clone(), stream, server);
+                    tokio::spawn(async move {
+                        let _ = session.await.unwrap().await;
+                    });
+                }
+            });
+        });
+
+        let options = NativeSshOptions {
+            port,
+            identity_files: vec![identity_file],
+            known_hosts,
+            use_agent: false,
+            connect_timeout: None,
+        };
+
+        (root, client_dir, options)
+    }
+
+    struct TestServer {
+        root: PathBuf,
+        client_key: keys::PublicKey,
+        channels: HashMap<ChannelId, Channel<Msg>>,
+    }
+
+    impl server::Handler for TestServer {
+        type Error = russh::Error;
+
+        async fn auth_publickey(
+            &mut self,
+            user: &str,
+            key: &keys::PublicKey,
+        ) -> std::result::Result<Auth, Self::Error> {
+            if user == "test" && *key == self.client_key {
+                Ok(Auth::Accept)
+            } else {
+                Ok(Auth::reject())
+            }
+        }
+
+        async fn channel_open_session(
+            &mut self,
+            channel: Channel<Msg>,
+            reply: server::ChannelOpenHandle,
+            _session: &mut Session,
+        ) -> std::result::Result<(), Self::Error> {
+            self.channels.insert(channel.id(), channel);
+            reply.accept().await;
+            Ok(())
+        }
+
+        async fn subsystem_request(
+            &mut self,
+            id: ChannelId,
+            name: &str,
+            session: &mut Session,
+        ) -> std::result::Result<(), Self::Error> {
+            match self.channels.remove(&id) {
+                Some(channel) if name == "sftp" => {
+                    session.channel_success(id)?;
+                    let sftp = DirectorySftp {
+                        root: self.root.clone(),
+                        files: HashMap::new(),
+                        dirs: HashMap::new(),
+                        next_handle: 0,
+                    };
+                    russh_sftp::server::run(channel.into_stream(), sftp).await;
+                }
+                _ => session.channel_failure(id)?,
+            }
+            Ok(())
+        }
+    }
+
+    /// An SFTP server for the files of a directory.
+    struct DirectorySftp {
+        root: PathBuf,
+        files: HashMap<String, fs::File>,
+        dirs: HashMap<String, Vec<String>>,
+        next_handle: u32,
+    }
+
+    impl DirectorySftp {
+        fn resolve(&self, path: &str) -> PathBuf {
+            self.root.join(path.trim_start_matches('/'))
+        }
+
+        fn new_handle(&mut self) -> String {
+            self.next_handle += 1;
+            self.next_handle.to_string()
+        }
+    }
+
+    fn ok(id: u32) -> Status {
+        Status {
+            id,
+            status_code: StatusCode::Ok,
+            error_message: "Ok".to_owned(),
+            language_tag: "en-US".to_owned(),
+        }
+    }
+
+    fn status_code(e: std::io::Error) -> StatusCode {
+        match e.kind() {
+            ErrorKind::NotFound => StatusCode::NoSuchFile,
+            ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+            _ => StatusCode::Failure,
+        }
+    }
+
+    impl russh_sftp::server::Handler for DirectorySftp {
+        type Error = StatusCode;
+
+        fn unimplemented(&self) -> Self::Error {
+            StatusCode::OpUnsupported
+        }
+
+        async fn init(
+            &mut self,
+            _version: u32,
+            _extensions: HashMap<String, String>,
+        ) -> ServerResult<Version> {
+            let mut version = Version::new();
+            for extension in [POSIX_RENAME, FSYNC] {
+                version
+                    .extensions
+                    .insert(extension.to_owned(), "1".to_owned());
+            }
+            Ok(version)
+        }
+
+        async fn open(
+            &mut self,
+            id: u32,
+            filename: String,
+            pflags: OpenFlags,
+            attrs: FileAttributes,
+        ) -> ServerResult<Handle> {
+            let file = fs::File::options()
+                .read(pflags.contains(OpenFlags::READ))
+                .write(pflags.contains(OpenFlags::WRITE))
+                .create(pflags.contains(OpenFlags::CREATE))
+                .truncate(pflags.contains(OpenFlags::TRUNCATE))
+                .mode(attrs.permissions.unwrap_or(0o666))
+                .open(self.resolve(&filename))
+                .map_err(status_code)?;
+
+            let handle = self.new_handle();
+            self.files.insert(handle.clone(), file);
+            Ok(Handle { id, handle })
+        }
+
+        async fn close(&mut self, id: u32, handle: String) -> ServerResult<Status> {
+            self.files.remove(&handle);
+            self.dirs.remove(&handle);
+            Ok(ok(id))
+        }
+
+        async fn read(
+            &mut self,
+            id: u32,
+            handle: String,
+            offset: u64,
+            len: u32,
+        ) -> ServerResult<Data> {
+            let file = self.files.get(&handle).ok_or(StatusCode::Failure)?;
+            let mut data = vec![0; len as usize];
+            let n = file.read_at(&mut data, offset).map_err(status_code)?;
+
+            if n == 0 {
+                return Err(StatusCode::Eof);
+            }
+
+            data.truncate(n);
+            Ok(Data { id, data })
+        }
+
+        async fn write(
+            &mut self,
+            id: u32,
+            handle: String,
+            offset: u64,
+            data: Vec<u8>,
+        ) -> ServerResult<Status> {
+            let file = self.files.get(&handle).ok_or(StatusCode::Failure)?;
+            file.write_all_at(&data, offset).map_err(status_code)?;
+            Ok(ok(id))
+        }
+
+        async fn stat(&mut self, id: u32, path: String) -> ServerResult<Attrs> {
+            let metadata = fs::metadata(self.resolve(&path)).map_err(status_code)?;
+            Ok(Attrs {
+                id,
+                attrs: FileAttributes::from(&metadata),
+            })
+        }
+
+        async fn fsetstat(
+            &mut self,
+            id: u32,
+            handle: String,
+            attrs: FileAttributes,
+        ) -> ServerResult<Status> {
+            let file = self.files.get(&handle).ok_or(StatusCode::Failure)?;
+
+            if attrs.uid.is_some() {
+                return Err(StatusCode::PermissionDenied);
+            }
+            if let Some(mode) = attrs.permissions {
+                file.set_permissions(fs::Permissions::from_mode(mode))
+                    .map_err(status_code)?;
+            }
+
+            Ok(ok(id))
+        }
+
+        async fn opendir(&mut self, id: u32, path: String) -> ServerResult<Handle> {
+            let mut names = Vec::new();
+            for entry in fs::read_dir(self.resolve(&path)).map_err(status_code)? {
+                let entry = entry.map_err(status_code)?;
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+
+            let handle = self.new_handle();
+            self.dirs.insert(handle.clone(), names);
+            Ok(Handle { id, handle })
+        }
+
+        async fn readdir(&mut self, id: u32, handle: String) -> ServerResult<Name> {
+            let names = self.dirs.get_mut(&handle).ok_or(StatusCode::Failure)?;
+
+            if names.is_empty() {
+                return Err(StatusCode::Eof);
+            }
+
+            let files = names.drain(..).map(File::dummy).collect();
+            Ok(Name { id, files })
+        }
+
+        async fn mkdir(
+            &mut self,
+            id: u32,
+            path: String,
+            attrs: FileAttributes,
+        ) -> ServerResult<Status> {
+            fs::DirBuilder::new()
+                .mode(attrs.permissions.unwrap_or(0o777))
+                .create(self.resolve(&path))
+                .map_err(status_code)?;
+            Ok(ok(id))
+        }
+
+        async fn remove(&mut self, id: u32, filename: String) -> ServerResult<Status> {
+            fs::remove_file(self.resolve(&filename)).map_err(status_code)?;
+            Ok(ok(id))
+        }
+
+        async fn extended(
+            &mut self,
+            id: u32,
+            request: String,
+            data: Vec<u8>,
+        ) -> ServerResult<Packet> {
+            let mut reader = wire::Reader::new(&data);
+            let mut read_path = || {
+                let path = reader.read_string().map_err(|_| StatusCode::BadMessage)?;
+                String::from_utf8(path.to_vec()).map_err(|_| StatusCode::BadMessage)
+            };
+
+            match request.as_str() {
+                POSIX_RENAME => {
+                    let from = self.resolve(&read_path()?);
+                    let to = self.resolve(&read_path()?);
+                    fs::rename(from, to).map_err(status_code)?;
+                }
+                FSYNC => {
+                    let handle = read_path()?;
+                    let file = self.files.get(&handle).ok_or(StatusCode::Failure)?;
+                    file.sync_all().map_err(status_code)?;
+                }
+                _ => return Err(StatusCode::OpUnsupported),
+            }
+
+            Ok(Packet::Status(ok(id)))
+        }
+    }
+}
@@ -0,0 +1,195 @@
+use crate::{key_type::KeyType, public_key::PublicKey};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Rules that keys have to satisfy before they are pushed or when they are audited.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    /// Rules for keys authorized for any user.
+    #[serde(default)]
+    default: Rules,
+    /// Additional rules for keys authorized for specific users.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    users: HashMap<String, Rules>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Rules {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    allowed_key_types: Option<Vec<KeyType>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    denied_key_types: Vec<KeyType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min_rsa_bits: Option<usize>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    require_security_key: bool,
+}
+
+#[derive(thiserror::Error, Debug, Eq, PartialEq)]
+pub enum Violation {
+    #[error("key type {0} is not allowed")]
+    KeyTypeNotAllowed(KeyType),
+    #[error("key type {0} is denied")]
+    KeyTypeDenied(KeyType),
+    #[error("RSA key has {bits} bits, at least {min_bits} bits are required")]
+    RsaKeyTooSmall { bits: usize, min_bits: usize },
+    #[error("RSA key size cannot be determined, at least {min_bits} bits are required")]
+    UnreadableRsaModulus { min_bits: usize },
+    #[error("key is not backed by a security key")]
+    SecurityKeyRequired,
+}
+
+impl Policy {
+    /// Returns the violations of `key` when it is authorized for `user`.
+    pub fn check(&self, user: &str, key: &PublicKey) -> Vec<Violation> {
+        let mut violations = self.default.check(key);
+
+        if let Some(rules) = self.users.get(user) {
+            for violation in rules.check(key) {
+                if !violations.contains(&violation) {
+                    violations.push(violation);
+                }
+            }
+        }
+
+        violations
+    }
+}
+
+impl Rules {
+    fn check(&self, key: &PublicKey) -> Vec<Violation> {
+        let mut violations = Vec::new();
+        let key_type = key.key_type();
+
+        if let Some(allowed_key_types) = &self.allowed_key_types {
+            if !allowed_key_types.contains(&key_type) {
+                violations.push(Violation::KeyTypeNotAllowed(key_type));
+            }
+        }
+
+        if self.denied_key_types.contains(&key_type) {
+            violations.push(Violation::KeyTypeDenied(key_type));
+        }
+
+        if let Some(min_bits) = self.min_rsa_bits {
+            match key.rsa_bits() {
+                Some(bits) if bits < min_bits => {
+                    violations.push(Violation::RsaKeyTooSmall { bits, min_bits })
+                }
+                // the size of an RSA key whose modulus cannot be read is unknown, so it fails the rule
+                None if key_type.is_rsa() => {
+                    violations.push(Violation::UnreadableRsaModulus { min_bits })
+                }
+                _ => {}
+            }
+        }
+
+        if self.require_security_key && !key_type.is_security_key() {
+            violations.push(Violation::SecurityKeyRequired);
+        }
+
+        violations
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_keys;
+
+    #[test]
+    fn check_policy() {
+        let policy = test_policy();
+
+        assert_eq!(policy.check("test", &key(test_keys::FOO)), vec![]);
+        assert_eq!(policy.check("test", &key(test_keys::RSA_3072)), vec![]);
+        assert_eq!(
+            policy.check("test", &key(test_keys::RSA_2048)),
+            vec![Violation::RsaKeyTooSmall {
+                bits: 2048,
+                min_bits: 3072
+            }]
+        );
+        assert_eq!(
+            policy.check("test", &key(test_keys::DSA)),
+            vec![Violation::KeyTypeDenied(KeyType::Dsa)]
+        );
+    }
+
+    #[test]
+    fn check_truncated_rsa_key() {
+        let policy = test_policy();
+
+        assert_eq!(
+            policy.check("test", &key("ssh-rsa AAAAB3NzaC1yc2E= truncated")),
+            vec![Violation::UnreadableRsaModulus { min_bits: 3072 }]
+        );
+        assert_eq!(
+            Policy::default().check("test", &key("ssh-rsa AAAAB3NzaC1yc2E= truncated")),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn check_policy_for_user() {
+        let policy = test_policy();
+
+        assert_eq!(policy.check("root", &key(test_keys::SK_ED25519)), vec![]);
+        assert_eq!(
+            policy.check("root", &key(test_keys::FOO)),
+            vec![Violation::SecurityKeyRequired]
+        );
+        assert_eq!(
+            policy.check("root", &key(test_keys::DSA)),
+            vec![
+                Violation::KeyTypeDenied(KeyType::Dsa),
+                Violation::SecurityKeyRequired
+            ]
+        );
+    }
+
+    #[test]
+    fn check_allowed_key_types() {
+        let policy: Policy = serde_yaml::from_str(
+            "default:\n  allowed_key_types: [ssh-ed25519, ecdsa-sha2-nistp256]",
+        )
+        .unwrap();
+
+        assert_eq!(policy.check("test", &key(test_keys::FOO)), vec![]);
+        assert_eq!(policy.check("test", &key(test_keys::ECDSA)), vec![]);
+        assert_eq!(
+            policy.check("test", &key(test_keys::RSA_3072)),
+            vec![Violation::KeyTypeNotAllowed(KeyType::Rsa)]
+        );
+    }
+
+    #[test]
+    fn unknown_rules() {
+        assert!(serde_yaml::from_str::<Policy>("default:\n  min_rsa_bit: 4096\n").is_err());
+        assert!(serde_yaml::from_str::<Policy>(
+            "users:\n  root:\n    require_security_keys: true\n"
+        )
+        .is_err());
+        assert!(serde_yaml::from_str::<Policy>("min_rsa_bits: 4096\n").is_err());
+    }
+
+    fn test_policy() -> Policy {
+        serde_yaml::from_str(
+            "
+default:
+  denied_key_types: [ssh-dss]
+  min_rsa_bits: 3072
+users:
+  root:
+    require_security_key: true
+",
+        )
+        .unwrap()
+    }
+
+    fn key(key: &str) -> PublicKey {
+        key.parse().unwrap()
+    }
+}
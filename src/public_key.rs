@@ -1,114 +1,602 @@
-use serde::{Deserialize, Serialize};
+use crate::{
+    fingerprint::{Fingerprint, HashAlg},
+    key_type::KeyType,
+    wire,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+use std::cmp::Ordering;
 use std::fmt;
 use std::hash::Hash;
 use std::str::FromStr;
 
-#[derive(Serialize, Deserialize, Clone, Eq, Debug, Ord, PartialOrd)]
-#[serde(transparent)]
-pub struct PublicKey(String);
+/// A public key as it appears on a line of an `authorized_keys` file,
+/// i.e., `[options] keytype base64-key [comment]`.
+#[derive(Clone, Debug)]
+pub struct PublicKey {
+    options: Vec<KeyOption>,
+    key_type: KeyType,
+    data: Vec<u8>,
+    comment: Option<String>,
+}
+
+/// A single option preceding a key, e.g., `no-pty` or `from="10.0.0.0/8"`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct KeyOption {
+    name: String,
+    value: Option<String>,
+}
 
 impl PublicKey {
-    /// Returns the public key with the comment removed.
-    fn strip_comment(&self) -> &str {
-        self.0
-            .match_indices(' ')
-            .nth(1)
-            .map_or(&self.0, |pos| &self.0[..pos.0])
+    /// Returns this public key's options.
+    pub fn options(&self) -> &[KeyOption] {
+        &self.options
+    }
+
+    /// Returns the option `name` of this public key, if it is set.
+    pub fn option(&self, name: &str) -> Option<&KeyOption> {
+        self.options
+            .iter()
+            .find(|option| option.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Returns this public key with the option `name` set to `value`,
+    /// replacing an existing option of that name.
+    pub fn with_option(mut self, name: &str, value: Option<&str>) -> Self {
+        let option = KeyOption::new(name, value);
+
+        match self
+            .options
+            .iter_mut()
+            .find(|option| option.name.eq_ignore_ascii_case(name))
+        {
+            Some(existing) => *existing = option,
+            None => self.options.push(option),
+        }
+
+        self
+    }
+
+    /// Returns this public key's type, e.g., `ssh-ed25519`.
+    pub fn key_type(&self) -> KeyType {
+        self.key_type
     }
 
     /// Returns this public key's comment, if any.
     pub fn comment(&self) -> Option<&str> {
-        self.0
-            .match_indices(' ')
-            .nth(1)
-            .map(|pos| &self.0[pos.0 + 1..])
+        self.comment.as_deref()
+    }
+
+    /// Returns the size of the modulus in bits, if this is an RSA key.
+    pub fn rsa_bits(&self) -> Option<usize> {
+        let mut reader = wire::Reader::new(&self.data);
+        reader.read_string().ok()?;
+
+        match self.key_type {
+            KeyType::Rsa => {}
+            KeyType::RsaCert => {
+                // certificates have a nonce in front of the public key
+                reader.read_string().ok()?;
+            }
+            _ => return None,
+        }
+
+        let _e = reader.read_mpint().ok()?;
+        let n = reader.read_mpint().ok()?;
+
+        n.first()
+            .map(|&msb| (n.len() - 1) * 8 + (8 - msb.leading_zeros() as usize))
+    }
+
+    /// Returns the encoded public key, i.e., the decoded base64 field.
+    pub fn blob(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the fingerprint of this public key using the hash algorithm `alg`.
+    pub fn fingerprint(&self, alg: HashAlg) -> Fingerprint {
+        Fingerprint::compute(alg, &self.data)
+    }
+}
+
+impl KeyOption {
+    pub fn new(name: &str, value: Option<&str>) -> Self {
+        KeyOption {
+            name: name.to_owned(),
+            value: value.map(str::to_owned),
+        }
     }
 }
 
 #[derive(thiserror::Error, Debug)]
-#[error("failed to parse public key")]
-pub struct ParsePublicKeyError;
+pub enum ParsePublicKeyError {
+    #[error("invalid key options")]
+    InvalidOptions,
+    #[error("missing key type")]
+    MissingKeyType,
+    #[error("missing key data")]
+    MissingKeyData,
+    #[error("unknown key type {0}")]
+    UnknownKeyType(String),
+    #[error("key data is not valid base64")]
+    InvalidBase64(#[from] base64::DecodeError),
+    #[error("malformed key data")]
+    MalformedKeyData(#[from] wire::UnexpectedEof),
+    #[error("key type {declared} does not match key data of type {encoded}")]
+    KeyTypeMismatch { declared: KeyType, encoded: String },
+}
+
+/// Returns `true` if `s` is a known key type rather than an option list.
+fn is_key_type(s: &str) -> bool {
+    s.parse::<KeyType>().is_ok()
+}
+
+/// Returns `true` if `s` can only be an option list.
+fn is_option_list(s: &str) -> bool {
+    s.contains(['=', ','])
+}
+
+/// Splits off the first whitespace-delimited field of `s`,
+/// treating whitespace inside double quotes as part of the field.
+fn split_field(s: &str) -> std::result::Result<(&str, &str), ParsePublicKeyError> {
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (pos, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            ' ' | '\t' if !quoted => return Ok((&s[..pos], s[pos..].trim_start())),
+            _ => {}
+        }
+    }
+
+    if quoted {
+        return Err(ParsePublicKeyError::InvalidOptions);
+    }
+
+    Ok((s, ""))
+}
+
+/// Parses a comma separated option list, e.g., `from="10.0.0.1,10.0.0.2",no-pty`.
+fn parse_options(s: &str) -> std::result::Result<Vec<KeyOption>, ParsePublicKeyError> {
+    let mut options = Vec::new();
+    let mut chars = s.chars().peekable();
+
+    loop {
+        let mut name = String::new();
+        let mut value = None;
+
+        while let Some(&c) = chars.peek() {
+            if c == ',' || c == '=' {
+                break;
+            }
+            name.push(c);
+            chars.next();
+        }
+
+        if name.is_empty() {
+            return Err(ParsePublicKeyError::InvalidOptions);
+        }
+
+        if chars.peek() == Some(&'=') {
+            chars.next();
+            if chars.next() != Some('"') {
+                return Err(ParsePublicKeyError::InvalidOptions);
+            }
+
+            let mut v = String::new();
+            loop {
+                match chars.next() {
+                    Some('\\') if chars.peek() == Some(&'"') => v.push(chars.next().unwrap()),
+                    Some('"') => break,
+                    Some(c) => v.push(c),
+                    None => return Err(ParsePublicKeyError::InvalidOptions),
+                }
+            }
+            value = Some(v);
+        }
+
+        options.push(KeyOption { name, value });
+
+        match chars.next() {
+            Some(',') => continue,
+            None => return Ok(options),
+            Some(_) => return Err(ParsePublicKeyError::InvalidOptions),
+        }
+    }
+}
 
 impl FromStr for PublicKey {
     type Err = ParsePublicKeyError;
 
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        // public keys consists of at least 2 parts separated by spaces
-        if s.splitn(3, ' ').count() < 2 {
-            return Err(ParsePublicKeyError);
+        let s = s.trim();
+        let (first, rest) = split_field(s)?;
+        let (second, _) = split_field(rest)?;
+
+        // the option list is optional, the key type is not
+        let (options, rest) =
+            if !is_key_type(first) && (is_key_type(second) || is_option_list(first)) {
+                (parse_options(first)?, rest)
+            } else {
+                (Vec::new(), s)
+            };
+
+        let (key_type, rest) = split_field(rest)?;
+        let (data, comment) = split_field(rest)?;
+
+        if key_type.is_empty() {
+            return Err(ParsePublicKeyError::MissingKeyType);
         }
 
-        Ok(PublicKey(s.to_owned()))
+        if data.is_empty() {
+            return Err(ParsePublicKeyError::MissingKeyData);
+        }
+
+        let key_type: KeyType = key_type
+            .parse()
+            .map_err(|_| ParsePublicKeyError::UnknownKeyType(key_type.to_owned()))?;
+        let data = STANDARD.decode(data)?;
+
+        // the key data starts with its own key type, which has to match the declared one
+        let encoded = wire::Reader::new(&data).read_string()?;
+        if encoded != key_type.name().as_bytes() {
+            return Err(ParsePublicKeyError::KeyTypeMismatch {
+                declared: key_type,
+                encoded: String::from_utf8_lossy(encoded).into_owned(),
+            });
+        }
+
+        Ok(PublicKey {
+            options,
+            key_type,
+            data,
+            comment: Some(comment.trim_end())
+                .filter(|comment| !comment.is_empty())
+                .map(str::to_owned),
+        })
     }
 }
 
 impl PartialEq for PublicKey {
     fn eq(&self, other: &Self) -> bool {
-        self.strip_comment() == other.strip_comment()
+        self.key_type == other.key_type && self.data == other.data
     }
 }
 
+impl Eq for PublicKey {}
+
 impl Hash for PublicKey {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
-        self.strip_comment().hash(state)
+        self.key_type.hash(state);
+        self.data.hash(state);
+    }
+}
+
+impl Ord for PublicKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.key_type, &self.data).cmp(&(&other.key_type, &other.data))
+    }
+}
+
+impl PartialOrd for PublicKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
     }
 }
 
 impl fmt::Display for PublicKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        for (i, option) in self.options.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            option.fmt(f)?;
+        }
+
+        if !self.options.is_empty() {
+            f.write_str(" ")?;
+        }
+
+        write!(f, "{} {}", self.key_type, STANDARD.encode(&self.data))?;
+
+        if let Some(comment) = &self.comment {
+            write!(f, " {}", comment)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for KeyOption {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)?;
+
+        if let Some(value) = &self.value {
+            write!(f, "=\"{}\"", value.replace('"', "\\\""))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Serialize for PublicKey {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PublicKey {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(PublicKeyVisitor)
+    }
+}
+
+struct PublicKeyVisitor;
+
+impl<'de> Visitor<'de> for PublicKeyVisitor {
+    type Value = PublicKey;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a valid public key")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        FromStr::from_str(v).map_err(de::Error::custom)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_keys;
+    use std::collections::HashSet;
 
     #[test]
     fn public_key_comment() {
         assert_eq!(
-            PublicKey(
-                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3eWCGNEO+FIx user@local".to_owned()
-            )
-            .comment(),
-            Some("user@local")
+            test_keys::FOO.parse::<PublicKey>().unwrap().comment(),
+            Some("foo")
         );
         assert_eq!(
-            PublicKey(
-                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3eWCGNEO+FIx random comment"
-                    .to_owned()
-            )
-            .comment(),
+            format!("{} random comment", key_without_comment(test_keys::FOO))
+                .parse::<PublicKey>()
+                .unwrap()
+                .comment(),
             Some("random comment")
         );
         assert_eq!(
-            PublicKey("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph3Mgju0wqHzXqX".to_owned())
+            key_without_comment(test_keys::FOO)
+                .parse::<PublicKey>()
+                .unwrap()
                 .comment(),
             None
         );
     }
 
     #[test]
-    fn public_key_strip_comment() {
+    fn public_key_eq_ignores_comment() {
+        assert_eq!(
+            test_keys::FOO.parse::<PublicKey>().unwrap(),
+            key_without_comment(test_keys::FOO)
+                .parse::<PublicKey>()
+                .unwrap()
+        );
+        assert_eq!(
+            format!("{} random comment", key_without_comment(test_keys::BAR))
+                .parse::<PublicKey>()
+                .unwrap(),
+            test_keys::BAR.parse::<PublicKey>().unwrap()
+        );
+        assert_ne!(
+            test_keys::FOO.parse::<PublicKey>().unwrap(),
+            test_keys::BAR.parse::<PublicKey>().unwrap()
+        );
+    }
+
+    #[test]
+    fn public_key_options() {
+        let key: PublicKey = format!(
+            r#"from="10.0.0.0/8",no-pty {} bob"#,
+            key_without_comment(test_keys::FOO)
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            key.options(),
+            &[
+                KeyOption::new("from", Some("10.0.0.0/8")),
+                KeyOption::new("no-pty", None)
+            ]
+        );
+        assert_eq!(key.key_type(), KeyType::Ed25519);
+        assert_eq!(key.comment(), Some("bob"));
+        assert_eq!(key, test_keys::FOO.parse().unwrap());
+    }
+
+    #[test]
+    fn public_key_quoted_options() {
+        let key: PublicKey = format!(
+            r#"command="echo \"hello, world\"",from="10.0.0.1,10.0.0.2" {}"#,
+            key_without_comment(test_keys::RSA_2048)
+        )
+        .parse()
+        .unwrap();
+
+        assert_eq!(
+            key.options(),
+            &[
+                KeyOption::new("command", Some(r#"echo "hello, world""#)),
+                KeyOption::new("from", Some("10.0.0.1,10.0.0.2"))
+            ]
+        );
+        assert_eq!(key.key_type(), KeyType::Rsa);
+        assert_eq!(key.comment(), None);
+    }
+
+    #[test]
+    fn public_key_invalid_options() {
+        let key = key_without_comment(test_keys::FOO);
+
+        assert!(matches!(
+            format!(r#"from="10.0.0.0/8 {}"#, key).parse::<PublicKey>(),
+            Err(ParsePublicKeyError::InvalidOptions)
+        ));
+        assert!(matches!(
+            format!("from=10.0.0.0/8 {}", key).parse::<PublicKey>(),
+            Err(ParsePublicKeyError::InvalidOptions)
+        ));
+        assert!(matches!(
+            "no-pty ssh-ed25519".parse::<PublicKey>(),
+            Err(ParsePublicKeyError::MissingKeyData)
+        ));
+    }
+
+    #[test]
+    fn public_key_display() {
+        for line in [
+            key_without_comment(test_keys::FOO),
+            test_keys::FOO,
+            test_keys::DSA,
+            &format!(
+                r#"command="echo \"hi\"",no-pty {} bob"#,
+                key_without_comment(test_keys::ECDSA)
+            ),
+        ] {
+            assert_eq!(line.parse::<PublicKey>().unwrap().to_string(), line);
+        }
+    }
+
+    #[test]
+    fn public_key_with_option() {
+        let key: PublicKey = format!("no-pty,Expiry-Time=\"20200101\" {}", test_keys::FOO)
+            .parse()
+            .unwrap();
+        let key = key.with_option("expiry-time", Some("20301231"));
+
         assert_eq!(
-            PublicKey(
-                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3eWCGNEO+FOx user@local".to_owned()
-            )
-            .strip_comment(),
-            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3eWCGNEO+FOx"
+            key.to_string(),
+            format!("no-pty,expiry-time=\"20301231\" {}", test_keys::FOO)
         );
+    }
+
+    #[test]
+    fn public_key_hash_ignores_options() {
+        let keys: HashSet<PublicKey> = [
+            test_keys::FOO.to_owned(),
+            format!(
+                r#"from="10.0.0.0/8" {} bob"#,
+                key_without_comment(test_keys::FOO)
+            ),
+        ]
+        .into_iter()
+        .map(|key| key.parse().unwrap())
+        .collect();
+
+        assert_eq!(keys.len(), 1);
+    }
+
+    #[test]
+    fn parse_key_types() {
+        for (key, key_type) in [
+            (test_keys::FOO, KeyType::Ed25519),
+            (test_keys::RSA_3072, KeyType::Rsa),
+            (test_keys::ECDSA, KeyType::EcdsaNistp256),
+            (test_keys::DSA, KeyType::Dsa),
+            (test_keys::SK_ED25519, KeyType::SkEd25519),
+        ] {
+            assert_eq!(key.parse::<PublicKey>().unwrap().key_type(), key_type);
+        }
+    }
+
+    #[test]
+    fn public_key_validation() {
+        assert!(matches!(
+            "".parse::<PublicKey>(),
+            Err(ParsePublicKeyError::MissingKeyType)
+        ));
+        assert!(matches!(
+            "ssh-rsa".parse::<PublicKey>(),
+            Err(ParsePublicKeyError::MissingKeyData)
+        ));
+        assert!(matches!(
+            "ssh-rsaa AAAAB3NzaC1yc2EAAAADAQABAAABAQ== comment".parse::<PublicKey>(),
+            Err(ParsePublicKeyError::UnknownKeyType(key_type)) if key_type == "ssh-rsaa"
+        ));
+        assert!(matches!(
+            "ssh-rsa foo".parse::<PublicKey>(),
+            Err(ParsePublicKeyError::InvalidBase64(_))
+        ));
+        assert!(matches!(
+            "ssh-rsa AAAAB3NzaC1y".parse::<PublicKey>(),
+            Err(ParsePublicKeyError::MalformedKeyData(_))
+        ));
+        assert!(matches!(
+            test_keys::FOO.replace("ssh-ed25519", "ssh-rsa").parse::<PublicKey>(),
+            Err(ParsePublicKeyError::KeyTypeMismatch {
+                declared: KeyType::Rsa,
+                encoded
+            }) if encoded == "ssh-ed25519"
+        ));
+    }
+
+    #[test]
+    fn public_key_fingerprint() {
+        for (key, sha256, md5) in [
+            (
+                test_keys::FOO,
+                "SHA256:lbaPt/4kw5/34odx19C2oQ6vy42CpNonmXBAr17OIuo",
+                "MD5:3f:fe:d5:aa:5c:ea:6c:65:85:82:80:88:34:3b:56:90",
+            ),
+            (
+                test_keys::RSA_2048,
+                "SHA256:Zre1K9ViCcK9y7Ms4/ja4KVURDhjaoTdxBSVP1auWjQ",
+                "MD5:33:84:ee:b2:46:4b:f9:49:a4:2a:38:7c:25:fc:2a:54",
+            ),
+            (
+                test_keys::SK_ED25519,
+                "SHA256:awSUpUGsH6mWR4tHxqskJh1wAmALZ66tncH1nZqWZr8",
+                "MD5:82:e7:fb:30:0f:40:f2:3e:6b:66:80:f1:bd:32:aa:26",
+            ),
+        ] {
+            let key: PublicKey = key.parse().unwrap();
+            assert_eq!(key.fingerprint(HashAlg::Sha256).to_string(), sha256);
+            assert_eq!(key.fingerprint(HashAlg::Md5).to_string(), md5);
+        }
+    }
+
+    #[test]
+    fn public_key_rsa_bits() {
         assert_eq!(
-            PublicKey(
-                "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3eWCGNEO+FIx random comment"
-                    .to_owned()
-            )
-            .strip_comment(),
-            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQCdWXdw3eWCGNEO+FIx"
+            test_keys::RSA_2048.parse::<PublicKey>().unwrap().rsa_bits(),
+            Some(2048)
         );
         assert_eq!(
-            PublicKey("ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mgju0wqHzXqX".to_owned())
-                .strip_comment(),
-            "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQC+Ph5Mgju0wqHzXqX"
+            test_keys::RSA_3072.parse::<PublicKey>().unwrap().rsa_bits(),
+            Some(3072)
         );
+        assert_eq!(
+            test_keys::FOO.parse::<PublicKey>().unwrap().rsa_bits(),
+            None
+        );
+    }
+
+    fn key_without_comment(key: &str) -> &str {
+        key.rsplit_once(' ').unwrap().0
     }
 }
@@ -0,0 +1,53 @@
+//! Output of the operations on single targets, buffered so that the output
+//! of concurrent operations does not interleave.
+
+/// The buffered output of an operation.
+#[derive(Default)]
+pub struct Report {
+    lines: Vec<Line>,
+}
+
+enum Line {
+    Out(String),
+    Err(String),
+}
+
+impl Report {
+    /// Adds a line of regular output, printed to stdout.
+    pub fn println(&mut self, line: impl Into<String>) {
+        self.lines.push(Line::Out(line.into()));
+    }
+
+    /// Adds a line of warnings or errors, printed to stderr.
+    pub fn eprintln(&mut self, line: impl Into<String>) {
+        self.lines.push(Line::Err(line.into()));
+    }
+
+    pub fn print(&self) {
+        for line in &self.lines {
+            match line {
+                Line::Out(line) => println!("{}", line),
+                Line::Err(line) => eprintln!("{}", line),
+            }
+        }
+    }
+
+    /// Prints all lines to stderr, e.g., to keep stdout free for machine-readable output.
+    pub fn print_to_stderr(&self) {
+        for line in &self.lines {
+            match line {
+                Line::Out(line) | Line::Err(line) => eprintln!("{}", line),
+            }
+        }
+    }
+}
+
+/// Prints the reports of all operations in order,
+/// returning their results or the first error.
+pub fn finish<T>(results: Vec<(Report, anyhow::Result<T>)>) -> anyhow::Result<Vec<T>> {
+    for (report, _) in &results {
+        report.print();
+    }
+
+    results.into_iter().map(|(_, result)| result).collect()
+}
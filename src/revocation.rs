@@ -0,0 +1,404 @@
+//! Keys revoked for all hosts, e.g., after a laptop was stolen.
+
+use crate::{
+    date::Date,
+    fingerprint::Fingerprint,
+    public_key::{ParsePublicKeyError, PublicKey},
+    wire,
+};
+use serde::{
+    de::{self, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashSet,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The `revoked` section of the configuration file.
+#[derive(Deserialize, Serialize, Clone, Default, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Revoked {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keys: Vec<RevokedKey>,
+    /// OpenSSH key revocation lists (KRLs) or plain files with a public key per line
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub files: Vec<PathBuf>,
+}
+
+/// A key revoked in the configuration file.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct RevokedKey {
+    pub key: KeyOrFingerprint,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<Date>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum KeyOrFingerprint {
+    Fingerprint(Fingerprint),
+    PublicKey(PublicKey),
+}
+
+impl<'de> Deserialize<'de> for KeyOrFingerprint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(KeyOrFingerprintVisitor)
+    }
+}
+
+struct KeyOrFingerprintVisitor;
+
+impl<'de> Visitor<'de> for KeyOrFingerprintVisitor {
+    type Value = KeyOrFingerprint;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a fingerprint or a public key")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: de::Error,
+    {
+        if v.starts_with("SHA256:") || v.starts_with("MD5:") {
+            v.parse()
+                .map(KeyOrFingerprint::Fingerprint)
+                .map_err(de::Error::custom)
+        } else {
+            v.parse()
+                .map(KeyOrFingerprint::PublicKey)
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
+/// Why and when a key was revoked.
+#[derive(Serialize, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Revocation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub date: Option<Date>,
+    /// The revoked keys file listing the key, if it is not revoked in the configuration file
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+}
+
+/// The revoked keys of the configuration file, together with those read from revoked keys files.
+#[derive(Default)]
+pub struct Revocations {
+    keys: Vec<RevokedKey>,
+    files: Vec<RevokedKeysFile>,
+}
+
+/// The keys of a revoked keys file, as encoded keys and the SHA1 and SHA256 digests of encoded keys.
+#[derive(Default)]
+struct RevokedKeysFile {
+    path: PathBuf,
+    blobs: HashSet<Vec<u8>>,
+    sha1_digests: HashSet<Vec<u8>>,
+    sha256_digests: HashSet<Vec<u8>>,
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[error("failed to read revoked keys file {0}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("invalid key revocation list {0}")]
+    InvalidKrl(PathBuf, #[source] ParseKrlError),
+    #[error("invalid line {1} in revoked keys file {0}")]
+    InvalidLine(PathBuf, usize, #[source] ParsePublicKeyError),
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum ParseKrlError {
+    #[error("unsupported format version {0}")]
+    UnsupportedVersion(u32),
+    #[error("unexpected end of data")]
+    UnexpectedEof(#[from] wire::UnexpectedEof),
+}
+
+// see PROTOCOL.krl of OpenSSH
+const KRL_MAGIC: &[u8] = b"SSHKRL\n\0";
+const KRL_FORMAT_VERSION: u32 = 1;
+const KRL_SECTION_EXPLICIT_KEY: u8 = 2;
+const KRL_SECTION_FINGERPRINT_SHA1: u8 = 3;
+const KRL_SECTION_SIGNATURE: u8 = 4;
+const KRL_SECTION_FINGERPRINT_SHA256: u8 = 5;
+
+impl Revocations {
+    /// Reads the revoked keys files of `revoked`, with relative paths resolved against `dir`,
+    /// i.e., the directory of the configuration file.
+    pub fn load(revoked: Option<&Revoked>, dir: &Path) -> Result<Self, Error> {
+        let Some(revoked) = revoked else {
+            return Ok(Revocations::default());
+        };
+
+        let files = revoked
+            .files
+            .iter()
+            .map(|path| {
+                let path = dir.join(path);
+                let data = fs::read(&path).map_err(|e| Error::Read(path.clone(), e))?;
+                RevokedKeysFile::parse(path, &data)
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Revocations {
+            keys: revoked.keys.clone(),
+            files,
+        })
+    }
+
+    /// Returns the revocation of `key`, if it is revoked.
+    pub fn find(&self, key: &PublicKey) -> Option<Revocation> {
+        let revoked_key = self.keys.iter().find(|revoked_key| match &revoked_key.key {
+            KeyOrFingerprint::Fingerprint(fingerprint) => {
+                key.fingerprint(fingerprint.alg()) == *fingerprint
+            }
+            KeyOrFingerprint::PublicKey(public_key) => public_key == key,
+        });
+        if let Some(revoked_key) = revoked_key {
+            return Some(Revocation {
+                reason: revoked_key.reason.clone(),
+                date: revoked_key.date,
+                file: None,
+            });
+        }
+
+        self.files
+            .iter()
+            .find(|file| file.contains(key))
+            .map(|file| Revocation {
+                file: Some(file.path.clone()),
+                ..Revocation::default()
+            })
+    }
+}
+
+impl RevokedKeysFile {
+    fn parse(path: PathBuf, data: &[u8]) -> Result<Self, Error> {
+        let mut file = RevokedKeysFile {
+            path,
+            ..RevokedKeysFile::default()
+        };
+
+        if let Some(krl) = data.strip_prefix(KRL_MAGIC) {
+            if let Err(e) = file.parse_krl(krl) {
+                return Err(Error::InvalidKrl(file.path, e));
+            }
+            return Ok(file);
+        }
+
+        for (i, line) in String::from_utf8_lossy(data).lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.parse::<PublicKey>() {
+                Ok(key) => file.blobs.insert(key.blob().to_vec()),
+                Err(e) => return Err(Error::InvalidLine(file.path, i + 1, e)),
+            };
+        }
+
+        Ok(file)
+    }
+
+    /// Reads the revoked keys of a KRL following its magic number.
+    /// Revoked certificates are ignored, and the signatures are not verified.
+    fn parse_krl(&mut self, data: &[u8]) -> Result<(), ParseKrlError> {
+        let mut reader = wire::Reader::new(data);
+
+        let version = reader.read_u32()?;
+        if version != KRL_FORMAT_VERSION {
+            return Err(ParseKrlError::UnsupportedVersion(version));
+        }
+        let _krl_version = reader.read_u64()?;
+        let _generated_date = reader.read_u64()?;
+        let _flags = reader.read_u64()?;
+        let _reserved = reader.read_string()?;
+        let _comment = reader.read_string()?;
+
+        while !reader.is_empty() {
+            let section_type = reader.read_u8()?;
+            if section_type == KRL_SECTION_SIGNATURE {
+                break;
+            }
+
+            let mut section = wire::Reader::new(reader.read_string()?);
+            let entries = match section_type {
+                KRL_SECTION_EXPLICIT_KEY => &mut self.blobs,
+                KRL_SECTION_FINGERPRINT_SHA1 => &mut self.sha1_digests,
+                KRL_SECTION_FINGERPRINT_SHA256 => &mut self.sha256_digests,
+                _ => continue,
+            };
+            while !section.is_empty() {
+                entries.insert(section.read_string()?.to_vec());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn contains(&self, key: &PublicKey) -> bool {
+        let blob = key.blob();
+
+        self.blobs.contains(blob)
+            || self.sha1_digests.contains(Sha1::digest(blob).as_slice())
+            || self
+                .sha256_digests
+                .contains(Sha256::digest(blob).as_slice())
+    }
+}
+
+impl fmt::Display for Revocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("revoked")?;
+
+        if let Some(date) = &self.date {
+            write!(f, " on {}", date)?;
+        }
+        if let Some(file) = &self.file {
+            write!(f, " in {}", file.display())?;
+        }
+        if let Some(reason) = &self.reason {
+            write!(f, ": {}", reason)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_keys;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+
+    /// A KRL generated by `ssh-keygen -k` revoking `test_keys::FOO` explicitly,
+    /// `test_keys::BAZ` by its SHA1 digest and `test_keys::BAR` by its SHA256 fingerprint.
+    const KRL: &str = "U1NIS1JMCgAAAAABAAAAAAAAAAAAAAAAatKOOwAAAAAAAAAAAAAAAAAAAAACAAAANwAAADMAAAALc3NoLWVkMjU1MTkAAAAgvKygp5uECX6QwrRcOVEKGv0rJzNpUhevQ7EPzu8sNiQDAAAAGAAAABShNycpPZ4WUUfVRT//Nnjd2npDgAUAAAAkAAAAIMk7aVxfh+FlKSfhSqSHfkPM0OWcJxyNCzOskU0iYsYe";
+
+    #[test]
+    fn revoked_keys() {
+        let revoked: Revoked = serde_yaml::from_str(&format!(
+            "keys:
+- key: {foo}
+  reason: laptop stolen
+  date: 2024-05-01
+- key: SHA256:yTtpXF+H4WUpJ+FKpId+Q8zQ5ZwnHI0LM6yRTSJixh4
+",
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+        let revocations = Revocations::load(Some(&revoked), Path::new("")).unwrap();
+
+        let revocation = revocations.find(&test_keys::FOO.parse().unwrap()).unwrap();
+        assert_eq!(
+            revocation.to_string(),
+            "revoked on 2024-05-01: laptop stolen"
+        );
+        assert_eq!(
+            revocations.find(&test_keys::BAR.parse().unwrap()),
+            Some(Revocation::default())
+        );
+        assert_eq!(revocations.find(&test_keys::BAZ.parse().unwrap()), None);
+    }
+
+    #[test]
+    fn revoked_keys_ignore_options_and_comments() {
+        let revoked: Revoked = serde_yaml::from_str(&format!(
+            "keys:\n- key: {foo}\n- key: SHA256:yTtpXF+H4WUpJ+FKpId+Q8zQ5ZwnHI0LM6yRTSJixh4\n",
+            foo = test_keys::FOO,
+        ))
+        .unwrap();
+        let revocations = Revocations::load(Some(&revoked), Path::new("")).unwrap();
+
+        // keys are revoked however they are written in an authorized keys file
+        for key in [test_keys::FOO, test_keys::BAR] {
+            let (key_type, rest) = key.split_once(' ').unwrap();
+            let data = rest.split(' ').next().unwrap();
+            let deployed = format!("no-pty {} {} someone else", key_type, data);
+            assert!(revocations.find(&deployed.parse().unwrap()).is_some());
+        }
+    }
+
+    #[test]
+    fn invalid_revoked_key() {
+        let error = serde_yaml::from_str::<Revoked>("keys:\n- key: ssh-ed25519 AAAA!! foo\n")
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("key data is not valid base64"));
+
+        assert!(serde_yaml::from_str::<Revoked>("keys:\n- key: SHA256:invalid\n").is_err());
+    }
+
+    #[test]
+    fn revoked_keys_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let krl = dir.path().join("revoked.krl");
+        fs::write(&krl, STANDARD.decode(KRL).unwrap()).unwrap();
+        let plain = dir.path().join("revoked_keys");
+        fs::write(&plain, format!("# stolen\n\n{}\n", test_keys::ECDSA)).unwrap();
+
+        // relative paths are resolved against the directory of the configuration file
+        let revocations = Revocations::load(
+            Some(&Revoked {
+                keys: Vec::new(),
+                files: vec!["revoked.krl".into(), plain.clone()],
+            }),
+            dir.path(),
+        )
+        .unwrap();
+        for key in [test_keys::FOO, test_keys::BAR, test_keys::BAZ] {
+            assert_eq!(
+                revocations.find(&key.parse().unwrap()).unwrap().file,
+                Some(krl.clone())
+            );
+        }
+        assert_eq!(
+            revocations
+                .find(&test_keys::ECDSA.parse().unwrap())
+                .unwrap()
+                .file,
+            Some(plain.clone())
+        );
+        assert_eq!(revocations.find(&test_keys::DSA.parse().unwrap()), None);
+
+        fs::write(&plain, "ssh-ed25519 AAAA\n").unwrap();
+        let error = Revocations::load(
+            Some(&Revoked {
+                keys: Vec::new(),
+                files: vec![plain],
+            }),
+            Path::new(""),
+        )
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("invalid line 1 in revoked keys file"));
+
+        fs::write(&krl, &STANDARD.decode(KRL).unwrap()[..40]).unwrap();
+        assert!(matches!(
+            Revocations::load(
+                Some(&Revoked {
+                    keys: Vec::new(),
+                    files: vec![krl],
+                }),
+                Path::new("")
+            ),
+            Err(Error::InvalidKrl(..))
+        ));
+    }
+}
@@ -0,0 +1,173 @@
+//! Selecting a subset of the targets of the configuration file.
+
+use crate::config::{self, Config, Target};
+
+/// Selectors restricting the targets a command applies to.
+///
+/// All selectors are glob patterns, where `*` matches any sequence of characters
+/// and `?` matches any single character.
+/// A target is selected if it matches any of the patterns given for each selector.
+#[derive(clap::Args, Clone, Debug, Default)]
+#[command(about = None, long_about = None, next_help_heading = "Selection")]
+pub struct Selector {
+    /// Only process hosts matching this pattern (may be given several times)
+    #[arg(long = "host", value_name = "GLOB", global = true)]
+    pub hosts: Vec<String>,
+    /// Skip hosts matching this pattern (may be given several times)
+    #[arg(long = "exclude-host", value_name = "GLOB", global = true)]
+    pub exclude_hosts: Vec<String>,
+    /// Only process items with a user matching this pattern (may be given several times)
+    #[arg(long = "user", value_name = "GLOB", global = true)]
+    pub users: Vec<String>,
+    /// Only process items with a path matching this pattern (may be given several times)
+    #[arg(long = "path", value_name = "GLOB", global = true)]
+    pub paths: Vec<String>,
+}
+
+impl Selector {
+    /// Returns `true` if no selectors are given, i.e., all targets are selected.
+    pub fn is_empty(&self) -> bool {
+        self.hosts.is_empty()
+            && self.exclude_hosts.is_empty()
+            && self.users.is_empty()
+            && self.paths.is_empty()
+    }
+
+    pub fn matches(&self, target: &Target) -> bool {
+        let any =
+            |patterns: &[String], s: &str| patterns.iter().any(|pattern| glob_match(pattern, s));
+
+        (self.hosts.is_empty() || any(&self.hosts, target.hostname))
+            && !any(&self.exclude_hosts, target.hostname)
+            && (self.users.is_empty() || any(&self.users, &target.item.user))
+            && (self.paths.is_empty() || any(&self.paths, &target.item.path))
+    }
+
+    /// Returns the selected targets of `config`, ordered by host.
+    pub fn select<'a>(&self, config: &'a Config) -> config::Result<Vec<Target<'a>>> {
+        let mut targets = config.targets()?;
+        targets.retain(|target| self.matches(target));
+
+        Ok(targets)
+    }
+}
+
+/// Returns `true` if `s` matches the glob `pattern`.
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let s: Vec<char> = s.chars().collect();
+
+    // position of the last `*` in the pattern and the position in `s` it was tried at
+    let mut backtrack = None;
+    let (mut p, mut i) = (0, 0);
+
+    while i < s.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            // let the last `*` match one more character
+            _ => match backtrack {
+                Some((star, start)) => {
+                    backtrack = Some((star, start + 1));
+                    p = star + 1;
+                    i = start + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("web.example.com", "web.example.com"));
+        assert!(!glob_match("web.example.com", "web.example.co"));
+        assert!(glob_match("web*.example.com", "web.example.com"));
+        assert!(glob_match("web*.example.com", "web12.example.com"));
+        assert!(!glob_match("web*.example.com", "db1.example.com"));
+        assert!(glob_match("web?", "web1"));
+        assert!(!glob_match("web?", "web"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match(
+            "/home/*/.ssh/*",
+            "/home/alice/.ssh/authorized_keys"
+        ));
+        assert!(glob_match("*a*b*", "xxaxxbxxb"));
+        assert!(!glob_match("*a*b", "xxaxxbxxbc"));
+    }
+
+    #[test]
+    fn select_targets() {
+        let config: Config = serde_yaml::from_str(
+            "hosts:
+  web1.example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys: []
+  - user: deploy
+    path: /home/deploy/.ssh/authorized_keys
+    authorized_keys: []
+  web2.example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys: []
+  db.example.com:
+  - user: root
+    path: /root/.ssh/authorized_keys
+    authorized_keys: []
+",
+        )
+        .unwrap();
+        let select = |selector: Selector| {
+            selector
+                .select(&config)
+                .unwrap()
+                .iter()
+                .map(|target| format!("{}:{}", target.hostname, target.item.user))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(select(Selector::default()).len(), 4);
+        assert_eq!(
+            select(Selector {
+                hosts: vec!["web*".to_owned()],
+                exclude_hosts: vec!["web2.*".to_owned()],
+                ..Selector::default()
+            }),
+            ["web1.example.com:root", "web1.example.com:deploy"]
+        );
+        assert_eq!(
+            select(Selector {
+                hosts: vec!["db.example.com".to_owned(), "web2.example.com".to_owned()],
+                ..Selector::default()
+            }),
+            ["db.example.com:root", "web2.example.com:root"]
+        );
+        assert_eq!(
+            select(Selector {
+                paths: vec!["/home/*".to_owned()],
+                ..Selector::default()
+            }),
+            ["web1.example.com:deploy"]
+        );
+        assert_eq!(
+            select(Selector {
+                users: vec!["admin".to_owned()],
+                ..Selector::default()
+            }),
+            Vec::<String>::new()
+        );
+    }
+}
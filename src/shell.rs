@@ -0,0 +1,479 @@
+//! POSIX shell scripts used to manage files on remote hosts.
+//!
+//! All paths are quoted, and file contents are passed via stdin rather than
+//! being embedded in the script, so that neither is ever interpreted by the shell.
+
+use crate::config::BecomeMethod;
+use std::path::Path;
+
+/// Quotes `s` as a single word for a POSIX shell.
+pub fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+/// Returns a script printing the contents of `path`.
+pub fn read_file(path: &str) -> String {
+    format!("cat -- {}", quote(path))
+}
+
+/// Returns a script atomically replacing the contents of `path` with stdin,
+/// keeping a copy of the previous contents at `backup`.
+/// A missing parent directory is created with mode 0700, like `~/.ssh`.
+///
+/// The new contents are written to a temporary file in the same directory first,
+/// which takes over the mode and owner of the existing file and is then renamed into place,
+/// so that the file is never left truncated if the connection drops.
+pub fn write_file(path: &str, backup: &str) -> String {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_string_lossy(),
+        _ => ".".into(),
+    };
+
+    format!(
+        "set -e
+[ -d {dir} ] || mkdir -p -m 700 -- {dir}
+tmp=$(mktemp -- {tmp})
+trap 'rm -f \"$tmp\"' EXIT
+if [ -e {path} ]; then
+  cp -p -- {path} \"$tmp\"
+  cp -p -- {path} {backup}
+else
+  chmod 600 \"$tmp\"
+fi
+cat > \"$tmp\"
+sync \"$tmp\" 2>/dev/null || sync
+mv -f -- \"$tmp\" {path}
+trap - EXIT",
+        path = quote(path),
+        dir = quote(&dir),
+        tmp = quote(&format!("{}.tmp.XXXXXX", path)),
+        backup = quote(backup),
+    )
+}
+
+/// Returns a script running `script` with `sh` as `user`, via `sudo` or `doas`.
+/// Both are run non-interactively, so they fail instead of prompting for a password.
+pub fn become_user(method: BecomeMethod, user: &str, script: &str) -> String {
+    let command = match method {
+        BecomeMethod::Sudo => "sudo",
+        BecomeMethod::Doas => "doas",
+    };
+
+    format!(
+        "{} -n -u {} -- sh -c {}",
+        command,
+        quote(user),
+        quote(script)
+    )
+}
+
+/// Returns a script printing the long listing and contents of all `paths`,
+/// each preceded by `marker`, as parsed by [`parse_files`].
+/// A file that cannot be read is reported as such, without affecting the other files.
+pub fn read_files(paths: &[&str], marker: &str) -> String {
+    let mut script = String::new();
+
+    for path in paths {
+        script.push_str(&format!(
+            "if [ ! -e {path} ]; then
+  printf '%s none\\n' {marker}
+elif listing=$(ls -ldnL -- {path}) && text=$(cat -- {path} && printf x); then
+  printf '%s file\\n%s\\n%s\\n' {marker} \"$listing\" \"${{text%x}}\"
+else
+  printf '%s error\\n' {marker}
+fi
+",
+            path = quote(path),
+            marker = quote(marker),
+        ));
+    }
+
+    script
+}
+
+/// A file as printed by [`read_files`].
+#[derive(Debug, Eq, PartialEq)]
+pub enum ListedFile {
+    /// The file does not exist.
+    Missing,
+    /// The file exists, but could not be read.
+    Unreadable,
+    /// The file was read, but is not valid UTF-8.
+    InvalidUtf8,
+    /// The mode and contents of the file.
+    File(u32, String),
+}
+
+/// Parses the output of [`read_files`], returning each file.
+///
+/// The contents of each file are decoded separately,
+/// so that a file that is not valid UTF-8 does not affect the other files.
+pub fn parse_files(output: &[u8], marker: &str) -> Option<Vec<ListedFile>> {
+    let separator = format!("{} ", marker);
+    let mut parts = split(output, separator.as_bytes());
+
+    if !parts.next()?.is_empty() {
+        return None;
+    }
+
+    parts
+        .map(|part| {
+            match part {
+                b"none\n" => return Some(ListedFile::Missing),
+                b"error\n" => return Some(ListedFile::Unreadable),
+                _ => {}
+            }
+
+            let part = part.strip_prefix(b"file\n")?;
+            let newline = part.iter().position(|&byte| byte == b'\n')?;
+            let listing = std::str::from_utf8(&part[..newline]).ok()?;
+            let text = part[newline + 1..].strip_suffix(b"\n")?;
+            let mode = parse_mode(listing)?;
+
+            Some(match String::from_utf8(text.to_vec()) {
+                Ok(text) => ListedFile::File(mode, text),
+                Err(_) => ListedFile::InvalidUtf8,
+            })
+        })
+        .collect()
+}
+
+/// Splits `bytes` at each occurrence of `separator`, like [`str::split`].
+fn split<'a>(mut bytes: &'a [u8], separator: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+    let mut finished = false;
+
+    std::iter::from_fn(move || {
+        if finished {
+            return None;
+        }
+
+        match bytes
+            .windows(separator.len())
+            .position(|window| window == separator)
+        {
+            Some(i) => {
+                let part = &bytes[..i];
+                bytes = &bytes[i + separator.len()..];
+                Some(part)
+            }
+            None => {
+                finished = true;
+                Some(bytes)
+            }
+        }
+    })
+}
+
+/// Returns a script printing the long listing of `path`, printing nothing if it does not exist.
+pub fn stat(path: &str) -> String {
+    format!(
+        "if [ -e {path} ]; then ls -ldnL -- {path}; fi",
+        path = quote(path)
+    )
+}
+
+/// Parses the permission bits from the long listing of a file, e.g., `-rw-r-----`.
+pub fn parse_mode(listing: &str) -> Option<u32> {
+    let permissions = listing.get(1..10)?.as_bytes();
+    let mut mode = 0;
+
+    for (i, &c) in permissions.iter().enumerate() {
+        let (set, special) = match (i % 3, c) {
+            (_, b'-') => (false, false),
+            (0, b'r') | (1, b'w') | (2, b'x') => (true, false),
+            (2, b's') | (2, b't') => (true, true),
+            (2, b'S') | (2, b'T') => (false, true),
+            _ => return None,
+        };
+
+        if set {
+            mode |= 1 << (8 - i);
+        }
+        if special {
+            mode |= 0o4000 >> (i / 3);
+        }
+    }
+
+    Some(mode)
+}
+
+/// Returns a script printing the names of all files in `dir`, one per line.
+pub fn list(dir: &str) -> String {
+    format!("ls -1A -- {}", quote(dir))
+}
+
+/// Returns a script removing `path`.
+pub fn remove_file(path: &str) -> String {
+    format!("rm -f -- {}", quote(path))
+}
+
+/// Returns a script atomically replacing `path` with `backup`, removing the backup.
+pub fn restore_backup(path: &str, backup: &str) -> String {
+    format!(
+        "set -e
+tmp=$(mktemp -- {tmp})
+trap 'rm -f \"$tmp\"' EXIT
+cp -p -- {backup} \"$tmp\"
+sync \"$tmp\" 2>/dev/null || sync
+mv -f -- \"$tmp\" {path}
+trap - EXIT
+rm -f -- {backup}",
+        path = quote(path),
+        tmp = quote(&format!("{}.tmp.XXXXXX", path)),
+        backup = quote(backup),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup;
+    use std::{
+        fs,
+        io::Write,
+        path::Path,
+        process::{Command, Stdio},
+    };
+
+    const HOSTILE_NAMES: [&str; 6] = [
+        "with space",
+        "$(touch pwned)",
+        "`touch pwned`",
+        "it's",
+        "\"quoted\"; touch pwned",
+        "-rf",
+    ];
+
+    #[test]
+    fn quote_words() {
+        assert_eq!(quote("foo"), "'foo'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        assert_eq!(
+            run(&format!("printf %s {}", quote("$HOME `id` \"x\" 'y'")), ""),
+            "$HOME `id` \"x\" 'y'"
+        );
+    }
+
+    #[test]
+    fn hostile_paths() {
+        let dir = tempfile::tempdir().unwrap();
+
+        for name in HOSTILE_NAMES {
+            let path = dir.path().join(name);
+            let path = path.to_str().unwrap();
+
+            assert_eq!(
+                parse_files(run(&read_files(&[path], "marker"), "").as_bytes(), "marker"),
+                Some(vec![ListedFile::Missing])
+            );
+
+            run(&write_file(path, &backup::backup_path(path, 1)), "first\n");
+            run(&write_file(path, &backup::backup_path(path, 2)), "second\n");
+
+            assert_eq!(run(&read_file(path), ""), "second\n");
+            assert_eq!(
+                parse_files(run(&read_files(&[path], "marker"), "").as_bytes(), "marker"),
+                Some(vec![ListedFile::File(0o600, "second\n".to_owned())])
+            );
+            assert!(run(&stat(path), "").starts_with("-rw-------"));
+
+            run(&restore_backup(path, &backup::backup_path(path, 2)), "");
+            assert_eq!(run(&read_file(path), ""), "first\n");
+
+            run(&write_file(path, &backup::backup_path(path, 3)), "third\n");
+            run(&remove_file(&backup::backup_path(path, 3)), "");
+        }
+
+        let mut names: Vec<_> = run(&list(dir.path().to_str().unwrap()), "")
+            .lines()
+            .map(str::to_owned)
+            .collect();
+        names.sort();
+        let mut expected = HOSTILE_NAMES.map(str::to_owned);
+        expected.sort();
+        assert_eq!(names, expected);
+
+        assert_no_injection(dir.path());
+    }
+
+    #[test]
+    fn hostile_contents() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+        let path = path.to_str().unwrap();
+
+        let text =
+            "ssh-ed25519 AAAA $(touch pwned)\nEOT\nssh-ed25519 AAAA `touch pwned` $HOME\nEOT";
+        run(&write_file(path, &backup::backup_path(path, 1)), text);
+
+        assert_eq!(run(&read_file(path), ""), text);
+        assert_no_injection(dir.path());
+    }
+
+    #[test]
+    fn write_file_preserves_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("authorized_keys");
+
+        run(&write_file(path.to_str().unwrap(), "unused"), "new\n");
+        assert_eq!(mode(&path), 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        let backup = backup::backup_path(path.to_str().unwrap(), 1);
+        run(&write_file(path.to_str().unwrap(), &backup), "newer\n");
+        assert_eq!(mode(&path), 0o640);
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "new\n");
+    }
+
+    #[test]
+    fn write_file_creates_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("home/test/.ssh/authorized_keys");
+
+        run(&write_file(path.to_str().unwrap(), "unused"), "new\n");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert_eq!(mode(path.parent().unwrap()), 0o700);
+    }
+
+    #[test]
+    fn become_other_user() {
+        let script = become_user(BecomeMethod::Sudo, "alice", "printf %s \"it's $0\"");
+        let command = script.strip_prefix("sudo -n -u 'alice' -- ").unwrap();
+        assert_eq!(run(command, ""), "it's sh");
+
+        let script = become_user(BecomeMethod::Doas, "-rf", "true");
+        assert_eq!(script, "doas -n -u '-rf' -- sh -c 'true'");
+    }
+
+    #[test]
+    fn read_several_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let marker = "authorized-keys-marker";
+
+        let mut paths = Vec::new();
+        for (i, name) in HOSTILE_NAMES.iter().enumerate() {
+            let path = dir.path().join(name).to_str().unwrap().to_owned();
+            // every other file is missing, and file contents look like the output of the script
+            if i % 2 == 0 {
+                let text = format!("{} none\nkey {}\n\n", marker.to_uppercase(), i);
+                fs::write(&path, text).unwrap();
+                fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+            }
+            paths.push(path);
+        }
+        // a directory cannot be read as a file, which must not affect the other files
+        let unreadable = dir.path().join("unreadable");
+        fs::create_dir(&unreadable).unwrap();
+        paths.insert(1, unreadable.to_str().unwrap().to_owned());
+        let paths: Vec<_> = paths.iter().map(String::as_str).collect();
+
+        let output = run(&read_files(&paths, marker), "");
+        let mut files = parse_files(output.as_bytes(), marker).unwrap();
+
+        assert_eq!(files.len(), paths.len());
+        assert_eq!(files.remove(1), ListedFile::Unreadable);
+        for (i, file) in files.into_iter().enumerate() {
+            if i % 2 == 0 {
+                let text = format!("{} none\nkey {}\n\n", marker.to_uppercase(), i);
+                assert_eq!(file, ListedFile::File(0o640, text));
+            } else {
+                assert_eq!(file, ListedFile::Missing);
+            }
+        }
+
+        assert_eq!(parse_files(b"", marker), Some(vec![]));
+        assert_eq!(parse_files(b"garbage", marker), None);
+        assert_no_injection(dir.path());
+    }
+
+    #[test]
+    fn read_files_with_invalid_utf8() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let marker = "authorized-keys-marker";
+
+        let good = dir.path().join("good");
+        fs::write(&good, "ssh-ed25519 AAAA good\n").unwrap();
+        let binary = dir.path().join("binary");
+        fs::write(&binary, b"\xff\xfe\x00binary\n").unwrap();
+        let other = dir.path().join("other");
+        fs::write(&other, "ssh-ed25519 AAAA other\n").unwrap();
+        let paths: Vec<_> = [&good, &binary, &other]
+            .iter()
+            .map(|path| {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o600)).unwrap();
+                path.to_str().unwrap()
+            })
+            .collect();
+
+        let output = run_bytes(&read_files(&paths, marker), "");
+        let files = parse_files(&output, marker).unwrap();
+
+        assert_eq!(
+            files,
+            [
+                ListedFile::File(0o600, "ssh-ed25519 AAAA good\n".to_owned()),
+                ListedFile::InvalidUtf8,
+                ListedFile::File(0o600, "ssh-ed25519 AAAA other\n".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_modes() {
+        for (listing, mode) in [
+            ("-rw------- 1 1000 1000 94 Oct 16 12:00 keys", Some(0o600)),
+            ("-rw-rw-r--. 1 0 0 94 Oct 16 12:00 keys", Some(0o664)),
+            ("drwx--x--x+", Some(0o711)),
+            ("-rwsr-xr-T", Some(0o5754)),
+            ("-rw", None),
+            ("-rw-?-----", None),
+        ] {
+            assert_eq!(parse_mode(listing), mode, "{}", listing);
+        }
+        assert_eq!(run(&stat("/nonexistent/file"), ""), "");
+    }
+
+    fn mode(path: &Path) -> u32 {
+        use std::os::unix::fs::PermissionsExt;
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    fn assert_no_injection(dir: &Path) {
+        assert!(!dir.join("pwned").exists());
+        assert!(!Path::new("pwned").exists());
+    }
+
+    /// Runs `script` locally, as `SshConnection` would on a remote host.
+    fn run(script: &str, stdin: &str) -> String {
+        String::from_utf8(run_bytes(script, stdin)).unwrap()
+    }
+
+    /// Runs `script` locally, returning its output as bytes.
+    fn run_bytes(script: &str, stdin: &str) -> Vec<u8> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(stdin.as_bytes())
+            .unwrap();
+
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "script failed: {}", script);
+        output.stdout
+    }
+}
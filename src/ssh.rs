@@ -1,6 +1,16 @@
+use crate::{
+    backup,
+    config::{Become, ConnectionSettings},
+    shell::{self, ListedFile},
+    transport::{FileStat, RemoteFile, Transport},
+};
 use std::{
     fmt,
-    process::{Command, Output},
+    io::Write,
+    path::PathBuf,
+    process::{Command, Output, Stdio},
+    sync::{Arc, Once},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 #[derive(thiserror::Error, Debug)]
@@ -9,8 +19,36 @@ pub enum Error {
     CommandFailed { command: String, error: String },
     #[error("SSH failed to read file {path}")]
     ReadFile { path: String },
+    #[error("file {path} is not valid UTF-8")]
+    InvalidUtf8 { path: String },
+    #[error("SSH failed to read files {paths:?}")]
+    ReadFiles { paths: Vec<String> },
     #[error("SSH failed to write file {path}")]
-    WriteFile { path: String },
+    WriteFile {
+        path: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("SSH failed to stat file {path}")]
+    Stat { path: String },
+    #[error("SSH failed to list directory {dir}")]
+    List {
+        dir: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("SSH failed to restore backup {backup}")]
+    RestoreBackup {
+        backup: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("SSH failed to remove file {path}")]
+    RemoveFile {
+        path: String,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -18,50 +56,386 @@ type Result<T> = std::result::Result<T, Error>;
 pub struct SshConnection {
     hostname: String,
     user: String,
+    args: Vec<String>,
+    become_as: Option<Become>,
+    control_master: Option<Arc<ControlMaster>>,
 }
 
 impl SshConnection {
-    pub fn new(hostname: String, user: String) -> Self {
-        SshConnection { hostname, user }
+    pub fn new(
+        hostname: String,
+        settings: &ConnectionSettings,
+        user: String,
+        become_as: Option<Become>,
+        control_master: Option<Arc<ControlMaster>>,
+    ) -> Self {
+        SshConnection {
+            hostname,
+            user,
+            args: ssh_args(settings),
+            become_as,
+            control_master,
+        }
     }
 
-    fn execute(&self, command: String) -> Result<Output> {
-        let output = Command::new("ssh")
+    /// Executes `script` with `sh` on the remote host, passing `stdin` to it.
+    /// If files are accessed as another user, `script` is run via `sudo` or `doas`.
+    fn execute(&self, script: String, stdin: &[u8]) -> Result<Output> {
+        let command = match &self.become_as {
+            Some(become_as) => shell::become_user(become_as.method, &become_as.user, &script),
+            None => script.clone(),
+        };
+
+        let mut ssh = Command::new("ssh");
+        ssh.args(&self.args);
+
+        if let Some(control_master) = &self.control_master {
+            control_master.start();
+            ssh.args(control_master.client_args());
+        }
+
+        let mut child = ssh
+            .arg("--")
             .arg(format!("{}@{}", self.user, self.hostname))
-            .arg(&command)
-            .output()
+            // the remote login shell may not be POSIX compatible, so always use `sh`
+            .arg(format!("sh -c {}", shell::quote(&command)))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
             .map_err(|e| Error::CommandFailed {
-                command: command.clone(),
+                command: script.clone(),
                 error: e.to_string(),
             })?;
 
+        // a failure to write stdin shows up in the exit status of `ssh`
+        if let Some(mut child_stdin) = child.stdin.take() {
+            let _ = child_stdin.write_all(stdin);
+        }
+
+        let output = child.wait_with_output().map_err(|e| Error::CommandFailed {
+            command: script.clone(),
+            error: e.to_string(),
+        })?;
+
         if !output.status.success() {
             return Err(Error::CommandFailed {
-                command,
+                command: script,
                 error: String::from_utf8(output.stderr).unwrap_or_default(),
             });
         }
 
         Ok(output)
     }
+}
 
-    pub fn read_file(&self, path: String) -> Result<String> {
-        let command = format!("cat \"{}\"", path);
-        let output = self.execute(command)?;
-        let text = String::from_utf8(output.stdout).map_err(|_| Error::ReadFile { path })?;
+impl Transport for SshConnection {
+    fn read_file(&self, path: &str) -> anyhow::Result<String> {
+        let output = self.execute(shell::read_file(path), &[])?;
+        let text = String::from_utf8(output.stdout).map_err(|_| Error::ReadFile {
+            path: path.to_owned(),
+        })?;
         Ok(text)
     }
 
-    pub fn write_file(&self, path: String, text: String) -> Result<()> {
-        let command = format!("cat > \"{}\" <<EOT\n{}\nEOT", path, text);
-        self.execute(command)
-            .map(|_| ())
-            .map_err(|_| Error::WriteFile { path })
+    fn write_file(&self, path: &str, text: &str) -> anyhow::Result<()> {
+        let script = shell::write_file(path, &backup::new_backup_path(path));
+        self.execute(script, text.as_bytes())
+            .map_err(|e| Error::WriteFile {
+                path: path.to_owned(),
+                source: e.into(),
+            })?;
+        Ok(())
+    }
+
+    fn stat(&self, path: &str) -> anyhow::Result<Option<FileStat>> {
+        let output = self.execute(shell::stat(path), &[])?;
+        let listing = String::from_utf8_lossy(&output.stdout);
+
+        if listing.is_empty() {
+            return Ok(None);
+        }
+
+        let mode = shell::parse_mode(&listing).ok_or_else(|| Error::Stat {
+            path: path.to_owned(),
+        })?;
+        Ok(Some(FileStat { mode }))
+    }
+
+    fn list(&self, dir: &str) -> anyhow::Result<Vec<String>> {
+        let output = self
+            .execute(shell::list(dir), &[])
+            .map_err(|e| Error::List {
+                dir: dir.to_owned(),
+                source: e.into(),
+            })?;
+        let text = String::from_utf8(output.stdout).map_err(|e| Error::List {
+            dir: dir.to_owned(),
+            source: e.into(),
+        })?;
+        Ok(text.lines().map(str::to_owned).collect())
+    }
+
+    fn restore_backup(&self, path: &str, backup: &str) -> anyhow::Result<()> {
+        self.execute(shell::restore_backup(path, backup), &[])
+            .map_err(|e| Error::RestoreBackup {
+                backup: backup.to_owned(),
+                source: e.into(),
+            })?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> anyhow::Result<()> {
+        self.execute(shell::remove_file(path), &[])
+            .map_err(|e| Error::RemoveFile {
+                path: path.to_owned(),
+                source: e.into(),
+            })?;
+        Ok(())
+    }
+
+    fn read_files(
+        &self,
+        paths: &[&str],
+    ) -> anyhow::Result<Vec<anyhow::Result<Option<RemoteFile>>>> {
+        let error = || Error::ReadFiles {
+            paths: paths.iter().map(|path| path.to_string()).collect(),
+        };
+
+        let marker = new_marker();
+        let output = self.execute(shell::read_files(paths, &marker), &[])?;
+        let files = shell::parse_files(&output.stdout, &marker)
+            .filter(|files| files.len() == paths.len())
+            .ok_or_else(error)?;
+
+        Ok(files
+            .into_iter()
+            .zip(paths)
+            .map(|(file, path)| match file {
+                ListedFile::Missing => Ok(None),
+                ListedFile::Unreadable => Err(Error::ReadFile {
+                    path: path.to_string(),
+                }
+                .into()),
+                ListedFile::InvalidUtf8 => Err(Error::InvalidUtf8 {
+                    path: path.to_string(),
+                }
+                .into()),
+                ListedFile::File(mode, text) => Ok(Some(RemoteFile {
+                    text,
+                    stat: FileStat { mode },
+                })),
+            })
+            .collect())
+    }
+}
+
+/// Returns a marker separating files in the output of a script,
+/// which is unlikely to appear in any file.
+fn new_marker() -> String {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos());
+    format!("authorized-keys-{:x}-{:x}", std::process::id(), nanos)
+}
+
+/// A master connection shared by all `ssh` processes for a host and user,
+/// see `ControlMaster` in ssh_config(5).
+///
+/// The master connection is started in the background before the first command and closed when dropped.
+/// If it cannot be started, each command falls back to a separate connection.
+pub struct ControlMaster {
+    path: PathBuf,
+    destination: String,
+    args: Vec<String>,
+    started: Once,
+    /// The `ssh` binary, replaced in tests
+    program: PathBuf,
+}
+
+/// How long an idle master connection is kept open after the last command,
+/// in case it is not closed, e.g., if the process is killed.
+const CONTROL_PERSIST_SECONDS: u32 = 60;
+
+impl ControlMaster {
+    /// Creates a master connection with the control socket at `path`, which is not started yet.
+    pub fn new(path: PathBuf, hostname: &str, settings: &ConnectionSettings, user: &str) -> Self {
+        ControlMaster {
+            path,
+            destination: format!("{}@{}", user, hostname),
+            args: ssh_args(settings),
+            started: Once::new(),
+            program: PathBuf::from("ssh"),
+        }
+    }
+
+    fn control_path(&self) -> String {
+        format!("ControlPath={}", self.path.display())
+    }
+
+    /// Starts the master connection, unless it has been started before.
+    fn start(&self) {
+        self.started.call_once(|| {
+            // `-f` waits for the authentication before running in the background,
+            // without output, so that it does not keep the output of a command open
+            let control_persist = format!("ControlPersist={}", CONTROL_PERSIST_SECONDS);
+            let _ = Command::new(&self.program)
+                .args(&self.args)
+                .args(["-o", "ControlMaster=yes", "-o", &control_persist])
+                .args(["-o", &self.control_path(), "-f", "-N", "--"])
+                .arg(&self.destination)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        });
+    }
+
+    /// Returns the arguments for commands to use the master connection.
+    fn client_args(&self) -> [String; 4] {
+        [
+            "-o".to_owned(),
+            "ControlMaster=no".to_owned(),
+            "-o".to_owned(),
+            self.control_path(),
+        ]
     }
 }
 
+impl Drop for ControlMaster {
+    fn drop(&mut self) {
+        if self.path.exists() {
+            let _ = Command::new(&self.program)
+                .args(["-o", &self.control_path(), "-O", "exit", "--"])
+                .arg(&self.destination)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status();
+        }
+    }
+}
+
+/// Returns the `ssh` arguments for `settings`.
+fn ssh_args(settings: &ConnectionSettings) -> Vec<String> {
+    let mut args = Vec::new();
+
+    if let Some(port) = settings.port {
+        args.extend(["-p".to_owned(), port.to_string()]);
+    }
+
+    if let Some(identity_file) = &settings.identity_file {
+        args.extend(["-i".to_owned(), identity_file.clone()]);
+    }
+
+    if let Some(proxy_jump) = &settings.proxy_jump {
+        args.extend(["-J".to_owned(), proxy_jump.clone()]);
+    }
+
+    if let Some(connect_timeout) = settings.connect_timeout {
+        args.extend([
+            "-o".to_owned(),
+            format!("ConnectTimeout={}", connect_timeout),
+        ]);
+    }
+
+    for (name, value) in &settings.ssh_options {
+        args.extend(["-o".to_owned(), format!("{}={}", name, value)]);
+    }
+
+    args
+}
+
 impl fmt::Display for SshConnection {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}@{}", self.user, self.hostname)
+        write!(f, "{}@{}", self.user, self.hostname)?;
+
+        if let Some(become_as) = &self.become_as {
+            write!(f, " as {}", become_as.user)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ssh_args_for_settings() {
+        assert!(ssh_args(&ConnectionSettings::default()).is_empty());
+
+        let settings = ConnectionSettings {
+            port: Some(2222),
+            identity_file: Some("~/.ssh/deploy".to_owned()),
+            proxy_jump: Some("admin@bastion.example.com".to_owned()),
+            connect_timeout: Some(10),
+            ssh_options: [("ServerAliveInterval".to_owned(), "30".to_owned())].into(),
+        };
+
+        assert_eq!(
+            ssh_args(&settings),
+            [
+                "-p",
+                "2222",
+                "-i",
+                "~/.ssh/deploy",
+                "-J",
+                "admin@bastion.example.com",
+                "-o",
+                "ConnectTimeout=10",
+                "-o",
+                "ServerAliveInterval=30",
+            ]
+        );
+    }
+
+    #[test]
+    fn control_master() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = dir.path().join("log");
+        let socket = dir.path().join("socket");
+
+        // logs its arguments, and creates the control socket like `ssh` starting a master connection
+        let program = dir.path().join("ssh");
+        fs::write(
+            &program,
+            format!(
+                "#!/bin/sh\necho \"$*\" >> {log}\ncase \"$*\" in *ControlMaster=yes*) touch {socket};; esac\n",
+                log = shell::quote(log.to_str().unwrap()),
+                socket = shell::quote(socket.to_str().unwrap()),
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&program, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let settings = ConnectionSettings {
+            port: Some(2222),
+            ..Default::default()
+        };
+        let mut master = ControlMaster::new(socket.clone(), "example.com", &settings, "deploy");
+        master.program = program;
+        let control_path = format!("ControlPath={}", socket.display());
+
+        assert_eq!(
+            master.client_args(),
+            ["-o", "ControlMaster=no", "-o", control_path.as_str()]
+        );
+
+        master.start();
+        master.start();
+        drop(master);
+
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!(
+                "-p 2222 -o ControlMaster=yes -o ControlPersist=60 -o {0} -f -N -- deploy@example.com\n\
+                 -o {0} -O exit -- deploy@example.com\n",
+                control_path
+            )
+        );
     }
 }
@@ -0,0 +1,25 @@
+//! Well-formed public keys for use in tests.
+
+pub const FOO: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAILysoKebhAl+kMK0XDlRChr9KyczaVIXr0OxD87vLDYk foo";
+
+pub const BAR: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAICftCMCejTXqOOaO0A+fjuwja4wYzuczPrsfYTD8vuDM bar";
+
+pub const BAZ: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIHkaIupLW1GnPKT9G/oTqJHDBmryyAqlm4hdSQOFSlTZ baz";
+
+pub const RSA_2048: &str =
+    "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABAQDZGBNYRjWn8AFEuod2Y6wbHsJAji5OLNonogk1H6TdfOgKN96RL5AAcejusdXUpAZuRYKtftkK+dnV/7/9ET0CMF+eN+Q/qd8Z3dsnl7+jocLRlvvUuUcI0bVIIKgTaXtieK/nGMlm2f9OCoK/DQjCp/80KWr1t1PPTqelsTqkHO0+cGMi7hNjm0Y4V/4gbJ+ZNnV+lkNsBcwZR0Qeivb+N/QKJ31XbZipOhgcC4niwXQ+993aMBxU5Hq6BciOdPKCZg6vXPpCpLPIqtSzeu7PjC77OxNyq7rC3QiuaYpyjvRgQDljzyXA8rXROXFYR5wMZaKiqD095qXORAyKBVIF rsa2048";
+
+pub const RSA_3072: &str =
+    "ssh-rsa AAAAB3NzaC1yc2EAAAADAQABAAABgQD0Xh/Gz3LHgwGHe9iwjeavyjC51PX2Y16wHO7a/Mqam+ORD1IxswL6dfgoGQuPeeAd0c15QQotCUQOA7aNOwP8gniSmXvpym+oLdgwWg6MMwyNc2xYy65v2Tpj/W4BINbCKmYPwbarD2MmkvOrKIY+u4hqWhNmQHbc7469E+1iw9hmA5YwTL1QKYhHo6gRIfyHTabT/Kp6TdZjBQxOgQvSbF4f0jylXA+Es0uvD2wENNxAydVZKRpA94KP74qgVYpHn5EArmqt0Lr+FyNaSiXBisJMfk01tNmt5sfwy+mu+izDOrWMHi5BX1yQ1qLIenhT1XEEMWi7jBf1U04vyDpVgBJteKbcP2Pfcc4jl4A1LDdxj+xQAKNmp8TmK5ySACIe9hH9gqlqfM0wMayjEB7ziC1aNCbxV1kZ1pBtjAWsf3t61C3p0As9cUnSvTMFNUnEpyw0Olz3TxboW0uSiD7dQDEWYMYk4A25espB+VFYbOyo5xOLR75pujUN519Mj28= rsa3072";
+
+pub const ECDSA: &str =
+    "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBA0MTGUL3Ai5QK77N+7OY0ZVb7N70H81Qmxe3VJfjoCRG+yt5lwNDSXdWc3KqOebJUjlkSBl2N68yq2kbHYzDLs= ecdsa";
+
+pub const DSA: &str =
+    "ssh-dss AAAAB3NzaC1kc3MAAACBAMhBy2wDcPxbsS8RaJnKlP0qZUsp0TiPfcsidGuyIlZGtaT2b+D7uZRvg0P5VPRqr+Bym11zVqV6g9CftzclPBCmR1zFwRbx4llP2ldwIRMy0NxXO1lZmJCKQEbmcq/sveu/41brsgpIR9bYfe1zdntNzdMFkzxI+lijrFWc8QZ5AAAAFQDj0j2qdtxBt6/2T2LujwcKTic/eQAAAIAgiOdSPsNhJ2OL5F4rK48InTkpj2LZ908iDXHSOjjBy0FC4DHiWlKaCe1dS9bwleklZ+lhPiiWoCE5hRZeVsnR6JS1D5qgLW3zyZM9G9rKOuTEM4iLfGAqhFTSyBdNu0VK3/BJm0ztyAstl2aASPI9IFJRkmDRkh8xVk7R/T2SVAAAAIEAkV57r1Y7vo0RmUo7DzcRtJIXpHqO5v00uYhaEhpZ19IdePafdYZNQdDqkbg2jr3+q0382H70bjl1hinvy+TbnovdjGeypAyaNlhG+lqP0rOLfs1paAgkpLGxvJzJDhjKcfL6XCrNLEfmqw5RVpUIgReQcA/n1w0gIpv0SwQbxGk= dsa";
+
+pub const SK_ED25519: &str =
+    "sk-ssh-ed25519@openssh.com AAAAGnNrLXNzaC1lZDI1NTE5QG9wZW5zc2guY29tAAAAIDK1btUzSKhYfzDJDlxkBt+V07irETCx2mK89JKo7YpjAAAABHNzaDo= sk";
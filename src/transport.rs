@@ -0,0 +1,711 @@
+use crate::{
+    backup,
+    config::{Become, ConnectionSettings},
+    ssh::{ControlMaster, SshConnection},
+};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::Write,
+    os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+};
+
+type Result<T> = anyhow::Result<T>;
+
+/// Metadata of a file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FileStat {
+    /// The permission bits of the file, e.g., `0o600`.
+    pub mode: u32,
+}
+
+/// The contents and metadata of a file.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RemoteFile {
+    pub text: String,
+    pub stat: FileStat,
+}
+
+/// Access to the files of a host.
+pub trait Transport: fmt::Display {
+    /// Reads a file.
+    fn read_file(&self, path: &str) -> Result<String>;
+
+    /// Atomically replaces the contents of a file, keeping a backup of the previous contents.
+    ///
+    /// A new file is created with mode `0600` (and a missing directory with mode `0700`),
+    /// an existing file keeps its mode and owner.
+    fn write_file(&self, path: &str, text: &str) -> Result<()>;
+
+    /// Returns the metadata of a file, or `None` if it does not exist.
+    fn stat(&self, path: &str) -> Result<Option<FileStat>>;
+
+    /// Returns the names of all files in a directory.
+    fn list(&self, dir: &str) -> Result<Vec<String>>;
+
+    /// Atomically replaces a file with one of its backups, removing the backup.
+    fn restore_backup(&self, path: &str, backup: &str) -> Result<()>;
+
+    /// Removes a file, e.g., an outdated backup.
+    fn remove_file(&self, path: &str) -> Result<()>;
+
+    /// Returns warnings that came up while connecting, e.g., about identity files that could not be loaded.
+    fn warnings(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Reads several files at once, returning `None` for files that do not exist,
+    /// and an error for each file that cannot be read.
+    ///
+    /// Transports for remote hosts read all files in a single round trip,
+    /// which fails as a whole only if the host cannot be accessed.
+    fn read_files(&self, paths: &[&str]) -> Result<Vec<Result<Option<RemoteFile>>>> {
+        Ok(paths
+            .iter()
+            .map(|path| {
+                Ok(match self.stat(path)? {
+                    Some(stat) => Some(RemoteFile {
+                        text: self.read_file(path)?,
+                        stat,
+                    }),
+                    None => None,
+                })
+            })
+            .collect())
+    }
+}
+
+/// Creates the transport for a host defined in the configuration file.
+pub trait Connector: Sync {
+    /// Connects to `hostname` as `user`, accessing files as another user if `become_as` is given.
+    fn connect(
+        &self,
+        hostname: &str,
+        settings: &ConnectionSettings,
+        user: &str,
+        become_as: Option<&Become>,
+    ) -> Result<Box<dyn Transport>>;
+}
+
+#[derive(thiserror::Error, Debug)]
+pub enum Error {
+    #[cfg(not(feature = "native-ssh"))]
+    #[error("host {0} requires the native-ssh feature")]
+    NativeSshUnavailable(String),
+    #[error("host {0} does not support accessing files as another user")]
+    BecomeUnsupported(String),
+    #[error("path {0} must not contain `..`")]
+    ParentDirectory(String),
+    #[error("path {0} leads outside of the root directory")]
+    OutsideRoot(String),
+}
+
+/// Selects the transport by the host name:
+/// `local:` or `local:<root>` for the local file system (optionally below `root`),
+/// `sftp://<hostname>` for the native SSH client,
+/// and `ssh://<hostname>` or just `<hostname>` for the `ssh` binary.
+/// Connection settings are ignored for the local file system.
+/// Only the `ssh` binary supports accessing files as another user.
+///
+/// All `ssh` processes for the same host and user share a master connection,
+/// which is closed when the connector is dropped.
+#[derive(Default)]
+pub struct DefaultConnector {
+    /// The directory of the control sockets, or `None` if it could not be created
+    control_dir: OnceLock<Option<PathBuf>>,
+    control_masters: Mutex<HashMap<(String, String), Arc<ControlMaster>>>,
+}
+
+impl DefaultConnector {
+    /// Returns the master connection for `hostname` and `user`,
+    /// or `None` if connections cannot be shared.
+    fn control_master(
+        &self,
+        hostname: &str,
+        settings: &ConnectionSettings,
+        user: &str,
+    ) -> Option<Arc<ControlMaster>> {
+        let control_dir = self.control_dir.get_or_init(create_control_dir).as_ref()?;
+
+        let mut control_masters = self.control_masters.lock().unwrap();
+        let index = control_masters.len();
+        let control_master = control_masters
+            .entry((hostname.to_owned(), user.to_owned()))
+            .or_insert_with(|| {
+                let path = control_dir.join(index.to_string());
+                Arc::new(ControlMaster::new(path, hostname, settings, user))
+            });
+
+        Some(control_master.clone())
+    }
+}
+
+/// Creates a private directory for the control sockets of `ssh`.
+fn create_control_dir() -> Option<PathBuf> {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    let dir = std::env::temp_dir().join(format!(
+        "authorized-keys-{}-{}",
+        std::process::id(),
+        COUNT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    // the path is passed to `ssh` in an option, which would have to be quoted and escaped otherwise
+    let plain = dir
+        .to_str()?
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "/._-".contains(c));
+    if !plain {
+        return None;
+    }
+
+    // fails if the directory exists, so that it cannot be prepared by someone else
+    fs::DirBuilder::new().mode(0o700).create(&dir).ok()?;
+    Some(dir)
+}
+
+impl Drop for DefaultConnector {
+    fn drop(&mut self) {
+        // close all master connections before removing their directory
+        self.control_masters.get_mut().unwrap().clear();
+
+        if let Some(Some(control_dir)) = self.control_dir.get() {
+            let _ = fs::remove_dir_all(control_dir);
+        }
+    }
+}
+
+impl Connector for DefaultConnector {
+    fn connect(
+        &self,
+        hostname: &str,
+        settings: &ConnectionSettings,
+        user: &str,
+        become_as: Option<&Become>,
+    ) -> Result<Box<dyn Transport>> {
+        if become_as.is_some()
+            && (hostname.starts_with("local:") || hostname.starts_with("sftp://"))
+        {
+            return Err(Error::BecomeUnsupported(hostname.to_owned()).into());
+        }
+
+        if let Some(root) = hostname.strip_prefix("local:") {
+            let root = if root.is_empty() { "/" } else { root };
+            Ok(Box::new(LocalTransport::new(PathBuf::from(root))))
+        } else if let Some(hostname) = hostname.strip_prefix("sftp://") {
+            connect_native_ssh(hostname, settings, user)
+        } else {
+            let hostname = hostname.strip_prefix("ssh://").unwrap_or(hostname);
+            Ok(Box::new(SshConnection::new(
+                hostname.to_owned(),
+                settings,
+                user.to_owned(),
+                become_as.cloned(),
+                self.control_master(hostname, settings, user),
+            )))
+        }
+    }
+}
+
+#[cfg(feature = "native-ssh")]
+fn connect_native_ssh(
+    hostname: &str,
+    settings: &ConnectionSettings,
+    user: &str,
+) -> Result<Box<dyn Transport>> {
+    use crate::native_ssh::{NativeSshConnection, NativeSshOptions};
+
+    let options = NativeSshOptions::from_settings(settings)?;
+    Ok(Box::new(NativeSshConnection::connect(
+        hostname, user, &options,
+    )?))
+}
+
+#[cfg(not(feature = "native-ssh"))]
+fn connect_native_ssh(
+    hostname: &str,
+    _settings: &ConnectionSettings,
+    _user: &str,
+) -> Result<Box<dyn Transport>> {
+    Err(Error::NativeSshUnavailable(format!("sftp://{}", hostname)).into())
+}
+
+/// Access to the local file system, optionally below a root directory,
+/// e.g., the mount point of a disk image.
+pub struct LocalTransport {
+    root: PathBuf,
+}
+
+impl LocalTransport {
+    pub fn new(root: PathBuf) -> Self {
+        LocalTransport { root }
+    }
+
+    /// Returns the location of `path` below the root directory.
+    /// Paths containing `..` or symbolic links leading outside the root directory are rejected,
+    /// so that they cannot escape the root directory.
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let mut resolved = self.root.clone();
+
+        for component in Path::new(path).components() {
+            match component {
+                Component::Normal(name) => resolved.push(name),
+                Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+                Component::ParentDir => return Err(Error::ParentDirectory(path.to_owned()).into()),
+            }
+        }
+
+        // only the existing part of the path can contain symbolic links, the rest is created below it
+        let root = match self.root.canonicalize() {
+            Ok(root) => root,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(resolved),
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(existing) = resolved
+            .ancestors()
+            .find(|ancestor| ancestor.symlink_metadata().is_ok())
+        {
+            // dangling symbolic links cannot be told to stay below the root directory
+            match existing.canonicalize() {
+                Ok(existing) if existing.starts_with(&root) => {}
+                _ => return Err(Error::OutsideRoot(path.to_owned()).into()),
+            }
+        }
+
+        Ok(resolved)
+    }
+
+    /// Atomically replaces `path` with a file written by `write`,
+    /// which is passed a newly created, empty temporary file with mode `0600` next to `path`.
+    fn replace<F>(&self, path: &Path, write: F) -> Result<()>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        // removed when dropped, unless it was renamed into place
+        let tmp = tempfile::NamedTempFile::new_in(dir)?;
+
+        write(tmp.path())?;
+        fs::File::open(tmp.path())?.sync_all()?;
+        tmp.persist(path)?;
+
+        Ok(())
+    }
+}
+
+impl Transport for LocalTransport {
+    fn read_file(&self, path: &str) -> Result<String> {
+        Ok(fs::read_to_string(self.resolve(path)?)?)
+    }
+
+    fn write_file(&self, path: &str, text: &str) -> Result<()> {
+        let path = self.resolve(path)?;
+        let metadata = match fs::metadata(&path) {
+            Ok(metadata) => Some(metadata),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        if metadata.is_some() {
+            let backup = backup::new_backup_path(&path.to_string_lossy());
+            fs::copy(&path, backup)?;
+        } else if let Some(dir) = path.parent() {
+            fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o700)
+                .create(dir)?;
+        }
+
+        self.replace(&path, |tmp| {
+            let mut file = fs::File::options().write(true).open(tmp)?;
+            file.write_all(text.as_bytes())?;
+
+            if let Some(metadata) = &metadata {
+                fs::set_permissions(tmp, metadata.permissions())?;
+                // like `cp -p`, only privileged users can preserve the owner
+                let _ = std::os::unix::fs::chown(tmp, Some(metadata.uid()), Some(metadata.gid()));
+            }
+
+            Ok(())
+        })
+    }
+
+    fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+        match fs::metadata(self.resolve(path)?) {
+            Ok(metadata) => Ok(Some(FileStat {
+                mode: metadata.permissions().mode() & 0o7777,
+            })),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn list(&self, dir: &str) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+
+        for entry in fs::read_dir(self.resolve(dir)?)? {
+            names.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+
+        Ok(names)
+    }
+
+    fn restore_backup(&self, path: &str, backup: &str) -> Result<()> {
+        let backup = self.resolve(backup)?;
+
+        self.replace(&self.resolve(path)?, |tmp| {
+            fs::copy(&backup, tmp)?;
+            Ok(())
+        })?;
+
+        fs::remove_file(backup)?;
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &str) -> Result<()> {
+        Ok(fs::remove_file(self.resolve(path)?)?)
+    }
+}
+
+impl fmt::Display for LocalTransport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "local:{}", self.root.display())
+    }
+}
+
+#[cfg(test)]
+pub use memory::{MemoryConnector, MemoryTransport};
+
+#[cfg(test)]
+mod memory {
+    use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// Files held in memory, for tests.
+    #[derive(Clone, Default)]
+    pub struct MemoryTransport {
+        files: Arc<Mutex<BTreeMap<String, String>>>,
+    }
+
+    impl MemoryTransport {
+        pub fn file(&self, path: &str) -> Option<String> {
+            self.files.lock().unwrap().get(path).cloned()
+        }
+
+        pub fn set_file(&self, path: &str, text: &str) {
+            self.files
+                .lock()
+                .unwrap()
+                .insert(path.to_owned(), text.to_owned());
+        }
+    }
+
+    impl Transport for MemoryTransport {
+        fn read_file(&self, path: &str) -> Result<String> {
+            self.file(path)
+                .ok_or_else(|| anyhow::anyhow!("no such file {}", path))
+        }
+
+        fn write_file(&self, path: &str, text: &str) -> Result<()> {
+            if let Some(previous) = self.file(path) {
+                self.set_file(&backup::new_backup_path(path), &previous);
+            }
+
+            self.set_file(path, text);
+            Ok(())
+        }
+
+        fn stat(&self, path: &str) -> Result<Option<FileStat>> {
+            Ok(self.file(path).map(|_| FileStat { mode: 0o600 }))
+        }
+
+        fn list(&self, dir: &str) -> Result<Vec<String>> {
+            let prefix = format!("{}/", dir.trim_end_matches('/'));
+
+            Ok(self
+                .files
+                .lock()
+                .unwrap()
+                .keys()
+                .filter_map(|path| path.strip_prefix(&prefix))
+                .filter(|name| !name.contains('/'))
+                .map(str::to_owned)
+                .collect())
+        }
+
+        fn restore_backup(&self, path: &str, backup: &str) -> Result<()> {
+            let text = self.read_file(backup)?;
+            self.files.lock().unwrap().remove(backup);
+            self.set_file(path, &text);
+            Ok(())
+        }
+
+        fn remove_file(&self, path: &str) -> Result<()> {
+            self.files
+                .lock()
+                .unwrap()
+                .remove(path)
+                .map(|_| ())
+                .ok_or_else(|| anyhow::anyhow!("no such file {}", path))
+        }
+    }
+
+    impl fmt::Display for MemoryTransport {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("memory")
+        }
+    }
+
+    /// Connects to in-memory hosts, for tests.
+    #[derive(Default)]
+    pub struct MemoryConnector {
+        hosts: Mutex<BTreeMap<String, MemoryTransport>>,
+        unreachable_hosts: Mutex<BTreeSet<String>>,
+        connections: AtomicUsize,
+    }
+
+    impl MemoryConnector {
+        /// Returns the files of a host.
+        pub fn host(&self, hostname: &str) -> MemoryTransport {
+            self.hosts
+                .lock()
+                .unwrap()
+                .entry(hostname.to_owned())
+                .or_default()
+                .clone()
+        }
+
+        /// Makes connections to a host fail.
+        pub fn set_unreachable(&self, hostname: &str) {
+            self.unreachable_hosts
+                .lock()
+                .unwrap()
+                .insert(hostname.to_owned());
+        }
+
+        /// Returns the number of connections made so far.
+        pub fn connections(&self) -> usize {
+            self.connections.load(Ordering::Relaxed)
+        }
+    }
+
+    impl Connector for MemoryConnector {
+        fn connect(
+            &self,
+            hostname: &str,
+            _settings: &ConnectionSettings,
+            _user: &str,
+            _become_as: Option<&Become>,
+        ) -> Result<Box<dyn Transport>> {
+            if self.unreachable_hosts.lock().unwrap().contains(hostname) {
+                anyhow::bail!("host {} is unreachable", hostname);
+            }
+
+            self.connections.fetch_add(1, Ordering::Relaxed);
+            Ok(Box::new(self.host(hostname)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::BecomeMethod;
+
+    #[test]
+    fn local_transport() {
+        let dir = tempfile::tempdir().unwrap();
+        let transport = LocalTransport::new(dir.path().to_owned());
+
+        assert_eq!(transport.stat("/authorized_keys").unwrap(), None);
+        assert_eq!(
+            transport
+                .read_files(&["/authorized_keys"])
+                .unwrap()
+                .into_iter()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+            vec![None]
+        );
+
+        transport.write_file("/authorized_keys", "first\n").unwrap();
+        assert_eq!(
+            transport.stat("/authorized_keys").unwrap(),
+            Some(FileStat { mode: 0o600 })
+        );
+        assert_eq!(
+            fs::read_to_string(dir.path().join("authorized_keys")).unwrap(),
+            "first\n"
+        );
+
+        fs::set_permissions(
+            dir.path().join("authorized_keys"),
+            fs::Permissions::from_mode(0o640),
+        )
+        .unwrap();
+        transport
+            .write_file("/authorized_keys", "second\n")
+            .unwrap();
+        assert_eq!(transport.read_file("/authorized_keys").unwrap(), "second\n");
+        assert_eq!(
+            transport.stat("/authorized_keys").unwrap(),
+            Some(FileStat { mode: 0o640 })
+        );
+
+        let names = transport.list("/").unwrap();
+        assert_eq!(names.len(), 2);
+        let backup = names
+            .iter()
+            .find(|name| name.starts_with("authorized_keys.bak."))
+            .map(|name| format!("/{}", name))
+            .unwrap();
+        assert_eq!(transport.read_file(&backup).unwrap(), "first\n");
+
+        transport
+            .restore_backup("/authorized_keys", &backup)
+            .unwrap();
+        assert_eq!(transport.read_file("/authorized_keys").unwrap(), "first\n");
+        assert_eq!(transport.list("/").unwrap(), vec!["authorized_keys"]);
+
+        transport
+            .write_file("/.ssh/authorized_keys", "new\n")
+            .unwrap();
+        assert_eq!(
+            transport
+                .stat("/.ssh")
+                .unwrap()
+                .map(|stat| stat.mode & 0o777),
+            Some(0o700)
+        );
+    }
+
+    #[test]
+    fn local_transport_stays_below_root() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let transport = LocalTransport::new(root.clone());
+
+        transport.write_file("./a/../b", "escaped\n").unwrap_err();
+        transport
+            .write_file("/../outside", "escaped\n")
+            .unwrap_err();
+        assert!(transport.read_file("../root/keys").is_err());
+        assert!(!dir.path().join("outside").exists());
+
+        transport.write_file("/./.ssh/keys", "inside\n").unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join(".ssh/keys")).unwrap(),
+            "inside\n"
+        );
+        assert_eq!(transport.list("/.ssh").unwrap(), ["keys"]);
+    }
+
+    #[test]
+    fn local_transport_does_not_follow_symlinks_outside_root() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("root");
+        let outside = dir.path().join("outside");
+        fs::create_dir_all(root.join("home/alice/.ssh")).unwrap();
+        fs::create_dir(&outside).unwrap();
+        fs::write(outside.join("authorized_keys"), "outside\n").unwrap();
+
+        symlink(
+            outside.join("authorized_keys"),
+            root.join("home/alice/.ssh/authorized_keys"),
+        )
+        .unwrap();
+        symlink(&outside, root.join("home/bob")).unwrap();
+        symlink(dir.path().join("missing"), root.join("home/carol")).unwrap();
+        symlink("alice", root.join("home/dave")).unwrap();
+        let transport = LocalTransport::new(root.clone());
+
+        for path in [
+            "/home/alice/.ssh/authorized_keys",
+            "/home/bob/authorized_keys",
+            "/home/bob/.ssh/authorized_keys",
+            "/home/carol/.ssh/authorized_keys",
+        ] {
+            assert!(transport.read_file(path).is_err());
+            transport.write_file(path, "escaped\n").unwrap_err();
+        }
+        assert_eq!(
+            fs::read_to_string(outside.join("authorized_keys")).unwrap(),
+            "outside\n"
+        );
+        assert!(!outside.join(".ssh").exists());
+        assert!(!dir.path().join("missing").exists());
+
+        // symbolic links staying below the root directory are followed
+        transport
+            .write_file("/home/dave/.ssh/keys", "inside\n")
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(root.join("home/alice/.ssh/keys")).unwrap(),
+            "inside\n"
+        );
+    }
+
+    #[test]
+    fn memory_transport() {
+        let transport = MemoryTransport::default();
+
+        transport.write_file("/home/test/keys", "first\n").unwrap();
+        transport.write_file("/home/test/keys", "second\n").unwrap();
+        transport.set_file("/home/test/.ssh/keys", "other\n");
+
+        assert_eq!(transport.read_file("/home/test/keys").unwrap(), "second\n");
+        assert_eq!(transport.list("/home/test").unwrap().len(), 2);
+        assert_eq!(transport.stat("/home/test/missing").unwrap(), None);
+    }
+
+    #[test]
+    fn default_connector() {
+        let connector = DefaultConnector::default();
+        let settings = ConnectionSettings::default();
+
+        for (hostname, display) in [
+            ("local:", "local:/"),
+            ("local:/mnt/image", "local:/mnt/image"),
+            ("example.com", "test@example.com"),
+            ("ssh://example.com", "test@example.com"),
+        ] {
+            assert_eq!(
+                connector
+                    .connect(hostname, &settings, "test", None)
+                    .unwrap()
+                    .to_string(),
+                display
+            );
+        }
+
+        #[cfg(not(feature = "native-ssh"))]
+        assert!(connector
+            .connect("sftp://example.com", &settings, "test", None)
+            .is_err());
+
+        let become_as = Become {
+            method: BecomeMethod::Sudo,
+            user: "alice".to_owned(),
+        };
+        assert_eq!(
+            connector
+                .connect("example.com", &settings, "test", Some(&become_as))
+                .unwrap()
+                .to_string(),
+            "test@example.com as alice"
+        );
+        assert!(connector
+            .connect("local:", &settings, "test", Some(&become_as))
+            .is_err());
+    }
+}
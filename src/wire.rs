@@ -0,0 +1,84 @@
+/// Reads values encoded in the SSH wire format (RFC 4251, section 5).
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+#[derive(thiserror::Error, Debug)]
+#[error("unexpected end of data")]
+pub struct UnexpectedEof;
+
+type Result<T> = std::result::Result<T, UnexpectedEof>;
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Reader { data }
+    }
+
+    /// Reads `len` raw bytes.
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(UnexpectedEof);
+        }
+
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    /// Returns `true` if all data has been read.
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Reads a `byte`.
+    pub fn read_u8(&mut self) -> Result<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    /// Reads a big-endian `uint32`.
+    pub fn read_u32(&mut self) -> Result<u32> {
+        let bytes = self.read_bytes(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads a big-endian `uint64`.
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let bytes = self.read_bytes(8)?;
+        Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads a length-prefixed `string`.
+    pub fn read_string(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.read_bytes(len)
+    }
+
+    /// Reads an `mpint`, returning its magnitude without leading zero bytes.
+    pub fn read_mpint(&mut self) -> Result<&'a [u8]> {
+        let bytes = self.read_string()?;
+        let start = bytes.iter().position(|&b| b != 0).unwrap_or(bytes.len());
+        Ok(&bytes[start..])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_string() {
+        let mut reader = Reader::new(b"\x00\x00\x00\x03foo\x00\x00\x00\x00\x00\x00\x00\x05ba");
+
+        assert_eq!(reader.read_string().unwrap(), b"foo");
+        assert_eq!(reader.read_string().unwrap(), b"");
+        assert!(reader.read_string().is_err());
+    }
+
+    #[test]
+    fn read_mpint() {
+        let mut reader = Reader::new(b"\x00\x00\x00\x03\x00\x80\x01\x00\x00\x00\x00");
+
+        assert_eq!(reader.read_mpint().unwrap(), b"\x80\x01");
+        assert_eq!(reader.read_mpint().unwrap(), b"");
+    }
+}